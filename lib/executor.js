@@ -1,5 +1,5 @@
 import Promise from 'bluebird'
-import { get, set } from 'lodash'
+import { get, isEqual, set } from 'lodash'
 
 // Fetch from the Roll API
 function callApi (command = '1d20') {
@@ -25,9 +25,14 @@ const TEMPLATE_OUTPUT = {
 
 const executor = {}
 
+// Most times a loop can run, the same as the interpreter's default limit
+const MAX_ITERATIONS = 100
+
 const Executor = (options) => {
   // The selected target
   executor._target = undefined
+  // Tokens standing in for others while looping (e.g. @player in !for @player in [@alice, @bob])
+  executor._aliases = {}
   // Inline macros should be run as the parent token
   executor._runAs = undefined
   // Test Mode (hide output from everyone)
@@ -79,6 +84,10 @@ export function getArgValue (value = {}) {
       getArgValue(part)
     ))
     return values.join('')
+  } else if (Array.isArray(value.Index)) {
+    return index(getArgValue(value.Index[0]), getArgValue(value.Index[1]))
+  } else if (typeof value.Length === 'object') {
+    return length(getArgValue(value.Length))
   } else if (typeof value.Token === 'object') {
     // We can only read data from an attribute right now
     const attribute = value.Token.attribute
@@ -91,6 +100,7 @@ export function getArgValue (value = {}) {
         throw new Error('No target selected')
       }
     }
+    name = executor._aliases[name] || name
     const token = executor.tokens[name]
     if (token) {
       // Check if we're a deep nested attribute
@@ -157,6 +167,82 @@ export function getArgValue (value = {}) {
   return value
 }
 
+// Get a value, running any step or arithmetic expression in it
+export async function evaluate (value = {}, output) {
+  if (typeof value.Step === 'object') {
+    const result = await executeStep(value.Step, output)
+    return result && result.type === 'roll' ? result.data.value : result
+  } else if (typeof value.Expr === 'object') {
+    return calculate(value.Expr, output)
+  } else if (Array.isArray(value.Index)) {
+    return index(await evaluate(value.Index[0], output), await evaluate(value.Index[1], output))
+  } else if (typeof value.Length === 'object') {
+    return length(await evaluate(value.Length, output))
+  }
+  return getArgValue(value)
+}
+
+// Take an item out of an array by its position or an object by its key (e.g. $list[0])
+export function index (value, key) {
+  let item
+  if (Array.isArray(value)) {
+    const position = typeof key === 'string' && /^\d+$/.test(key) ? Number(key) : key
+    if (Number.isInteger(position) && position >= 0) {
+      item = value[position]
+    }
+  } else if (value && typeof value === 'object') {
+    item = value[String(key)]
+  }
+  if (item === undefined) {
+    throw new Error(`Cannot find ${key} in ${JSON.stringify(value)}`)
+  }
+  return item
+}
+
+// Count the items in an array or object, or the characters in text (e.g. len($list))
+export function length (value) {
+  if (Array.isArray(value) || typeof value === 'string') {
+    return [...value].length
+  } else if (value && typeof value === 'object') {
+    return Object.keys(value).length
+  }
+  throw new Error(`Cannot find the length of ${JSON.stringify(value)}`)
+}
+
+// Work out an arithmetic expression (e.g. { Binary: [left, 'Add', right] })
+export async function calculate (expr = {}, output) {
+  if (typeof expr.Value === 'object') {
+    return Number(await evaluate(expr.Value, output))
+  } else if (typeof expr.Negate === 'object') {
+    return -(await calculate(expr.Negate, output))
+  }
+  const [left, op, right] = expr.Binary
+  return arithmetic(await calculate(left, output), op, await calculate(right, output))
+}
+
+// Apply an arithmetic operation, dividing rounds down like the interpreter
+export function arithmetic (left, op, right) {
+  if (right === 0 && ['Divide', 'IntegerDivide', 'Modulo'].includes(op)) {
+    throw new Error('Cannot divide by zero')
+  }
+  switch (op) {
+    case 'Add':
+      return left + right
+    case 'Subtract':
+      return left - right
+    case 'Multiply':
+      return left * right
+    case 'Divide':
+      return left / right
+    case 'IntegerDivide':
+      return Math.floor(left / right)
+    case 'Modulo':
+      return left - right * Math.floor(left / right)
+    default:
+      throw new Error(`Unknown operation ${op}`)
+  }
+}
+
 // Execute all the steps
 export function execute (steps = [], cleanup = true) {
   return new Promise(async (resolve, reject) => {
@@ -212,6 +298,8 @@ export function executeStep (step = {}, output) {
       return executeStepInput(step, output)
     case 'Lambda':
       return executeStepLambda(step, output)
+    case 'Loop':
+      return executeStepLoop(step, output)
     case 'Roll':
     case 'RollHidden':
     case 'RollWhisper':
@@ -228,6 +316,154 @@ export function executeStep (step = {}, output) {
   }
 }
 
+// Execute the steps of a branch in order, resolving with the result of the last
+export async function executeSteps (steps = [], output) {
+  let result
+  for (const step of steps) {
+    result = await executeStep(step, output)
+  }
+  return result
+}
+
+// Execute a loop step, running its steps for each item in turn (e.g. !for $i in 1..3 { ... })
+export async function executeStepLoop (step = {}, output) {
+  let result
+  for (const arg of step.args) {
+    if (typeof arg.Loop !== 'object') {
+      continue
+    }
+    const { variable, over, steps } = arg.Loop
+    const items = await loopItems(over, output)
+    for (const item of items) {
+      if (typeof variable.Token === 'object') {
+        // the token stands in for the token of each item until the loop is done
+        const name = variable.Token.name
+        const previous = executor._aliases[name]
+        executor._aliases[name] = String(item).replace(/^@/, '')
+        try {
+          result = await executeSteps(steps, output)
+        } finally {
+          if (previous === undefined) {
+            delete executor._aliases[name]
+          } else {
+            executor._aliases[name] = previous
+          }
+        }
+      } else {
+        executor.results[variable.Variable] = item
+        result = await executeSteps(steps, output)
+      }
+    }
+  }
+  return result
+}
+
+// Find the items a loop runs over, checking there are not too many
+export async function loopItems (over = {}, output) {
+  let items
+  if (Array.isArray(over.Range)) {
+    const first = await evaluate(over.Range[0], output)
+    const last = await evaluate(over.Range[1], output)
+    if (!Number.isInteger(first) || !Number.isInteger(last)) {
+      throw new Error(`Cannot loop from ${first} to ${last}`)
+    }
+    items = []
+    for (let i = first; i <= last && items.length <= MAX_ITERATIONS; i++) {
+      items.push(i)
+    }
+  } else if (over.Items && Array.isArray(over.Items.Array)) {
+    // tokens on their own are looped over by name (e.g. [@alice, @bob])
+    items = []
+    for (const item of over.Items.Array) {
+      const isToken = typeof item.Token === 'object' && !item.Token.attribute
+      items.push(isToken ? `@${item.Token.name}` : await evaluate(item, output))
+    }
+  } else {
+    items = await evaluate(over.Items, output)
+    if (!Array.isArray(items)) {
+      throw new Error(`Cannot loop over ${items}`)
+    }
+  }
+
+  if (items.length > MAX_ITERATIONS) {
+    throw new Error(`Too many loop iterations (the limit is ${MAX_ITERATIONS})`)
+  }
+  return items
+}
+
+// The token a name refers to, following @target and tokens standing in for others in loops
+function tokenName (name) {
+  return name === 'target' ? executor._target : (executor._aliases[name] || name)
+}
+
+// Find the steps of the first branch whose condition holds, checking else-ifs in order
+export async function branchSteps (conditional = {}, output) {
+  if (await checkCondition(conditional.condition, output)) {
+    return conditional.success
+  }
+  for (const elseIf of conditional.else_if || []) {
+    if (await checkCondition(elseIf.condition, output)) {
+      return elseIf.success
+    }
+  }
+  return conditional.failure
+}
+
+// Check a condition (e.g. { And: [{ Compare: { left, comparison, right } }, { Not: ... }] })
+export async function checkCondition (condition = {}, output) {
+  if (typeof condition.Compare === 'object') {
+    const left = await evaluate(condition.Compare.left, output)
+    const right = await evaluate(condition.Compare.right, output)
+    const ordering = order(left, right)
+    switch (condition.Compare.comparison) {
+      case 'EqualTo':
+        return ordering === 0
+      case 'NotEqualTo':
+        return ordering !== 0
+      case 'GreaterThanOrEqual':
+        return ordering >= 0
+      case 'LessThanOrEqual':
+        return ordering <= 0
+      case 'GreaterThan':
+        return ordering > 0
+      case 'LessThan':
+        return ordering < 0
+      default:
+        return false
+    }
+  } else if (Array.isArray(condition.And)) {
+    return await checkCondition(condition.And[0], output) && checkCondition(condition.And[1], output)
+  } else if (Array.isArray(condition.Or)) {
+    return await checkCondition(condition.Or[0], output) || checkCondition(condition.Or[1], output)
+  } else if (typeof condition.Not === 'object') {
+    return !(await checkCondition(condition.Not, output))
+  }
+  throw new Error('Unknown condition')
+}
+
+// Order two values like the interpreter: -1, 0 or 1, or undefined when they are neither
+// ordered nor equal. Numbers compare by value, including text that reads as a number,
+// other text compares alphabetically and anything else is only equal when identical
+// (so true and 1 are not equal).
+export function order (left, right) {
+  const number = (value) => {
+    if (typeof value === 'number') {
+      return value
+    } else if (typeof value === 'string' && value.trim() !== '' && !isNaN(value)) {
+      return Number(value)
+    }
+    return undefined
+  }
+  const l = number(left)
+  const r = number(right)
+  if (l !== undefined && r !== undefined) {
+    return Math.abs(l - r) < Number.EPSILON ? 0 : (l < r ? -1 : 1)
+  } else if (typeof left === 'string' && typeof right === 'string') {
+    return left === right ? 0 : (left < right ? -1 : 1)
+  }
+  return isEqual(left, right) ? 0 : undefined
+}
+
 export async function executeStepFunction (step = {}, output) {
   return new Promise(async (resolve, reject) => {
     try {
@@ -254,98 +490,30 @@ export async function executeStepLambda (step = {}, output) {
   return Promise.each(step.args, (arg) => new Promise(async (resolve, reject) => {
     try {
       if (typeof arg.Conditional !== 'undefined') {
-        const conditional = arg.Conditional
-        const comparison = conditional.comparison
-        const left = Number(getArgValue(conditional.left))
-        const right = Number(getArgValue(conditional.right))
-        const success = conditional.success
-        const failure = conditional.failure
-
-        let eq = false
-        switch (comparison) {
-          case 'EqualTo':
-            eq = left === right
-            break
-          case 'GreaterThanOrEqual':
-            eq = left >= right
-            break
-          case 'LessThanOrEqual':
-            eq = left <= right
-            break
-          case 'GreaterThan':
-            eq = left > right
-            break
-          case 'LessThan':
-            eq = left < right
-            break
-        }
-
-        const result = await executeStep(eq ? success : failure, output)
+        const steps = await branchSteps(arg.Conditional, output)
+        const result = await executeSteps(steps, output)
         return resolve(result)
       } else if (arg.Assign || arg.Concat || arg.Deduct) {
         const assign = arg.Assign || arg.Concat || arg.Deduct
         const left = assign.left
 
         // if we have a step, run the step and get the immediate output
-        const right = await Promise.all(assign.right.map(val => new Promise(async (res, rej) => {
-          try {
-            if (val.Step === undefined) {
-              const argVal = getArgValue(val)
-              res(argVal)
-            } else {
-                const result = await executeStep(val.Step, output)
-                switch (result && result.type) {
-                  case 'roll':
-                    return res(result.data.value)
-                  default:
-                    return res(result)
-                }
-            }
-          } catch (e) {
-            rej(e)
-          }
-        })))
+        const right = await Promise.all(assign.right.map(val => evaluate(val, output)))
 
         const isToken = typeof left.Token === 'object'
         const isVariable = typeof left.Variable === 'string'
 
         if (isVariable || isToken) {
-          let nextOp // next operation to perform
           const result = right.reduce((a, v) => {
             if (typeof v === 'string') {
-              nextOp = undefined
               return a === 0 ? v : `${a} ${v}`
-            } else if (typeof v === 'boolean') {
-              nextOp = undefined
+            } else if (typeof v === 'boolean' || typeof v === 'number') {
               return v
-            } else if (typeof v === 'number') {
-              switch (nextOp) {
-                case 'Add':
-                  nextOp = undefined
-                  return a + v
-                case 'Divide':
-                  nextOp = undefined
-                  return a / v
-                case 'Multiply':
-                  nextOp = undefined
-                  return a * v
-                case 'Subtract':
-                  nextOp = undefined
-                  return a - v
-                default:
-                  nextOp = undefined
-                  return v
-              }
             } else if (v && typeof v.Boolean !== 'undefined') {
               return v.Boolean
-            } else if (v && typeof v.Primitive === 'string') {
-              nextOp = v.Primitive
-              return a
             } else if (v && typeof v.Array !== 'undefined') {
-              nextOp = undefined
               return v.Array.map(e => getArgValue(e))
             } else if (v && typeof v.Object !== 'undefined') {
-              nextOp = undefined
               return v.Object
             } else {
               return v
@@ -357,7 +525,7 @@ export async function executeStepLambda (step = {}, output) {
           } else if (isToken) {
             const name = left.Token.name
             const attribute = left.Token.attribute
-            let token = executor.tokens[name === 'target' ? executor._target : name]
+            let token = executor.tokens[tokenName(name)]
             if (!token) {
               token = {
                 attributes: {},
@@ -424,12 +592,12 @@ export async function executeStepLambda (step = {}, output) {
             }
 
             // set the real token attribute
-            executor.tokens[name === 'target' ? executor._target : name] = token
+            executor.tokens[tokenName(name)] = token
           }
         }
       } else if (typeof arg.Token === 'object') {
         // run an inline macro
-        const name = arg.Token.name === 'target' ? executor._target : arg.Token.name
+        const name = tokenName(arg.Token.name)
         const macroName = arg.Token.macro_name
         if (executor.tokens[name]) {
           const token = executor.tokens[name]
@@ -461,116 +629,108 @@ export async function executeStepLambda (step = {}, output) {
   }))
 }
 
+// Write a roll flag that compares each die (e.g. rr<2 or cs>=8)
+function comparitive (flag, { op, value }) {
+  const symbols = {
+    EqualTo: '==',
+    NotEqualTo: '!=',
+    GreaterThan: '>',
+    GreaterThanOrEqual: '>=',
+    LessThan: '<',
+    LessThanOrEqual: '<=',
+  }
+  return `${flag}${symbols[op] || '<'}${getArgValue(value)}`
+}
+
+// Put the arguments of a roll back together into an equation for the Roll API (e.g. 1d20+1d4)
+export async function rollCommand (rollArgs = [], output) {
+  let command = ''
+  for (const rollArg of rollArgs) {
+    if (rollArg === 'Advantage') {
+      command += 'adv'
+    } else if (rollArg === 'Disadvantage') {
+      command += 'dis'
+    } else if (typeof rollArg.Comment !== 'undefined') {
+      command += `['${getArgValue(rollArg.Comment)}']`
+    } else if (typeof rollArg.D !== 'undefined') {
+      command += `d${getArgValue(rollArg.D)}`
+    } else if (typeof rollArg.E !== 'undefined') {
+      command += `e${getArgValue(rollArg.E)}`
+    } else if (typeof rollArg.GT !== 'undefined') {
+      command += `gt${getArgValue(rollArg.GT)}`
+    } else if (typeof rollArg.GTE !== 'undefined') {
+      command += `gte${getArgValue(rollArg.GTE)}`
+    } else if (typeof rollArg.H !== 'undefined') {
+      command += `kh${getArgValue(rollArg.H)}`
+    } else if (typeof rollArg.L !== 'undefined') {
+      command += `kl${getArgValue(rollArg.L)}`
+    } else if (typeof rollArg.LT !== 'undefined') {
+      command += `lt${getArgValue(rollArg.LT)}`
+    } else if (typeof rollArg.LTE !== 'undefined') {
+      command += `lte${getArgValue(rollArg.LTE)}`
+    } else if (typeof rollArg.Max !== 'undefined') {
+      command += `max${getArgValue(rollArg.Max)}`
+    } else if (typeof rollArg.Min !== 'undefined') {
+      command += `min${getArgValue(rollArg.Min)}`
+    } else if (typeof rollArg.ModifierNeg !== 'undefined') {
+      // work out expressions (e.g. - (@me.level * 2)) before sending them
+      const value = await evaluate(rollArg.ModifierNeg, output)
+      if (value >= 0) {
+        command += `-${value}`
+      } else {
+        command += `+${-value}`
+      }
+    } else if (typeof rollArg.ModifierPos !== 'undefined') {
+      // make sure the value is actually positive
+      const value = await evaluate(rollArg.ModifierPos, output)
+      if (value >= 0) {
+        command += `+${value}`
+      } else {
+        command += `${value}`
+      }
+    } else if (typeof rollArg.N !== 'undefined') {
+      command += `${getArgValue(rollArg.N)}`
+    } else if (typeof rollArg.RO !== 'undefined') {
+      command += comparitive('ro', rollArg.RO)
+    } else if (typeof rollArg.RR !== 'undefined') {
+      command += comparitive('rr', rollArg.RR)
+    } else if (typeof rollArg.CS !== 'undefined') {
+      command += comparitive('cs', rollArg.CS)
+    } else if (typeof rollArg.CF !== 'undefined') {
+      command += comparitive('cf', rollArg.CF)
+    } else if (typeof rollArg.GL !== 'undefined') {
+      command += comparitive('gl', rollArg.GL)
+    } else if (typeof rollArg.Sides !== 'undefined') {
+      command += `d[${rollArg.Sides.map(side => getArgValue(side)).join(',')}]`
+    } else if (Array.isArray(rollArg.GroupPos)) {
+      command += `+${await rollCommand(rollArg.GroupPos, output)}`
+    } else if (Array.isArray(rollArg.GroupNeg)) {
+      command += `-${await rollCommand(rollArg.GroupNeg, output)}`
+    } else if (typeof rollArg.Primitive !== 'undefined') {
+      switch (rollArg.Primitive) {
+        case 'Add': {
+          command += ' + '
+          break
+        }
+        case 'Subtract': {
+          command += ' - '
+          break
+        }
+      }
+    }
+  }
+  return command
+}
+
 export function executeStepRoll (step = {}, output) {
   return new Promise(async (resolve, reject) => {
     try {
-      // equation for the roll - this is a bit backwards right now because we parse the step out and
-      // really should just be passing the raw input instead of reassembling the pieces
-      // to feed into the Roll API
-      let command = ''
       let to = null
       let as = null
+      const rollArgs = []
       step.args.forEach((arg) => {
         if (typeof arg.Roll !== 'undefined') {
-          const rollArg = arg.Roll
-          if (rollArg === 'Advantage') {
-            command += 'adv'
-          } else if (rollArg === 'Disadvantage') {
-            command += 'dis'
-          } else if (typeof rollArg.Comment !== 'undefined') {
-            command += `['${getArgValue(rollArg.Comment)}']`
-          } else if (typeof rollArg.D !== 'undefined') {
-            command += `d${getArgValue(rollArg.D)}`
-          } else if (typeof rollArg.E !== 'undefined') {
-            command += `e${getArgValue(rollArg.E)}`
-          } else if (typeof rollArg.GT !== 'undefined') {
-            command += `gt${getArgValue(rollArg.GT)}`
-          } else if (typeof rollArg.GTE !== 'undefined') {
-            command += `gte${getArgValue(rollArg.GTE)}`
-          } else if (typeof rollArg.H !== 'undefined') {
-            command += `kh${getArgValue(rollArg.H)}`
-          } else if (typeof rollArg.L !== 'undefined') {
-            command += `kl${getArgValue(rollArg.L)}`
-          } else if (typeof rollArg.LT !== 'undefined') {
-            command += `lt${getArgValue(rollArg.LT)}`
-          } else if (typeof rollArg.LTE !== 'undefined') {
-            command += `lte${getArgValue(rollArg.LTE)}`
-          } else if (typeof rollArg.Max !== 'undefined') {
-            command += `max${getArgValue(rollArg.Max)}`
-          } else if (typeof rollArg.Min !== 'undefined') {
-            command += `min${getArgValue(rollArg.Min)}`
-          } else if (typeof rollArg.ModifierNeg !== 'undefined') {
-            command += `-${getArgValue(rollArg.ModifierNeg)}`
-          } else if (typeof rollArg.ModifierPos !== 'undefined') {
-            // make sure the value is actually positive
-            const value = getArgValue(rollArg.ModifierPos)
-            if (value >= 0) {
-              command += `+${value}`
-            } else {
-              command += `${value}`
-            }
-          } else if (typeof rollArg.N !== 'undefined') {
-            command += `${getArgValue(rollArg.N)}`
-          } else if (typeof rollArg.RO !== 'undefined') {
-            const comparitive = rollArg.RO.op
-            const value = getArgValue(rollArg.RO.value)
-
-            let op = '<'
-            switch (comparitive) {
-              case 'EqualTo':
-                op = '=='
-                break
-              case 'GreaterThanOrEqual':
-                op = '>='
-                break
-              case 'LessThanOrEqual':
-                op = '<='
-                break
-              case 'GreaterThan':
-                op = '>'
-                break
-              case 'LessThan':
-                op = '<'
-                break
-            }
-            command += `ro${op}${value}`
-          } else if (typeof rollArg.RR !== 'undefined') {
-            const comparitive = rollArg.RR.op
-            const value = getArgValue(rollArg.RR.value)
-
-            let op = '<'
-            switch (comparitive) {
-              case 'EqualTo':
-                op = '=='
-                break
-              case 'GreaterThanOrEqual':
-                op = '>='
-                break
-              case 'LessThanOrEqual':
-                op = '<='
-                break
-              case 'GreaterThan':
-                op = '>'
-                break
-              case 'LessThan':
-                op = '<'
-                break
-            }
-            command += `rr${op}${value}`
-          } else if (typeof rollArg.Sides !== 'undefined') {
-            command += `d[${rollArg.Sides.map(side => getArgValue(side)).join(',')}]`
-          } else if (typeof rollArg.Primitive !== 'undefined') {
-            switch (rollArg.Primitive) {
-              case 'Add': {
-                command += ' + '
-                break
-              }
-              case 'Subtract': {
-                command += ' - '
-                break
-              }
-            }
-          }
+          rollArgs.push(arg.Roll)
         } else if (typeof arg.Token === 'object') {
           if (step.op === 'Roll') {
             as = arg
@@ -580,6 +740,11 @@ export function executeStepRoll (step = {}, output) {
         }
       })
 
+      // equation for the roll - this is a bit backwards right now because we parse the step out and
+      // really should just be passing the raw input instead of reassembling the pieces
+      // to feed into the Roll API
+      let command = await rollCommand(rollArgs, output)
+
       // if we set the token equal to a token attribute, but that was it - it was probably a roll command
       if (command === '' && (as || to)) {
         command = getArgValue(as || to)
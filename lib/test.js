@@ -120,9 +120,11 @@ test.beforeEach((t) => {
         Assign: {
           left: { Token: { attribute: 'hp', macro_name: null, name: 'target' } },
           right: [
-            { Token: { attribute: 'hp', macro_name: null, name: 'target' } },
-            { Primitive: 'Subtract' },
-            { VariableReserved: 2 },
+            { Expr: { Binary: [
+              { Value: { Token: { attribute: 'hp', macro_name: null, name: 'target' } } },
+              'Subtract',
+              { Value: { VariableReserved: 2 } },
+            ] } },
           ],
         },
       },
@@ -134,15 +136,20 @@ test.beforeEach((t) => {
   conditionalStep = {
     args: [{
       Conditional: {
-        comparison: 'EqualTo',
-        failure: {
+        condition: {
+          Compare: {
+            comparison: 'EqualTo',
+            left: { Number: 10 },
+            right: { Number: 10 },
+          },
+        },
+        else_if: [],
+        failure: [{
           args: [],
           op: 'Exit',
           result: 'Ignore',
-        },
-        left: { Number: 10 },
-        right: { Number: 10 },
-        success: stepRoll,
+        }],
+        success: [stepRoll],
       },
     }],
     op: 'Lambda',
@@ -410,8 +417,8 @@ test('it executes a == Conditional step', async (t) => {
   let output = await execute([ conditionalStep ], false)
   t.is(output.rolls.length, 1, 'does not compare EqualTo')
 
-  conditionalStep.args[0].Conditional.left = { Number: 9 }
-  conditionalStep.args[0].Conditional.right = { Number: 10 }
+  conditionalStep.args[0].Conditional.condition.Compare.left = { Number: 9 }
+  conditionalStep.args[0].Conditional.condition.Compare.right = { Number: 10 }
   output = await execute([ conditionalStep ], false)
   t.is(output.rolls.length, 0, 'does not compare EqualTo')
 })
@@ -422,15 +429,20 @@ test('it executes a == Conditional step with variable', async (t) => {
   const step = {
     args: [{
       Conditional: {
-        comparison: 'EqualTo',
-        failure: {
+        condition: {
+          Compare: {
+            comparison: 'EqualTo',
+            left: { VariableReserved: 0 },
+            right: { Number: 0 },
+          },
+        },
+        else_if: [],
+        failure: [{
           args: [],
           op: 'Exit',
           result: 'Ignore',
-        },
-        left: { VariableReserved: 0 },
-        right: { Number: 0 },
-        success: stepRoll,
+        }],
+        success: [stepRoll],
       },
     }],
     op: 'Lambda',
@@ -443,89 +455,201 @@ test('it executes a == Conditional step with variable', async (t) => {
 })
 
 test('it executes a >= Conditional step', async (t) => {
-  conditionalStep.args[0].Conditional.comparison = 'GreaterThanOrEqual' 
-  conditionalStep.args[0].Conditional.left = { Float: 15.5 }
-  conditionalStep.args[0].Conditional.right = { Number: 10 }
+  conditionalStep.args[0].Conditional.condition.Compare.comparison = 'GreaterThanOrEqual' 
+  conditionalStep.args[0].Conditional.condition.Compare.left = { Float: 15.5 }
+  conditionalStep.args[0].Conditional.condition.Compare.right = { Number: 10 }
   let output = await execute([ conditionalStep ], false)
   t.is(output.rolls.length, 1, 'does not compare GreaterThanOrEqual')
 
   output.rolls = []
-  conditionalStep.args[0].Conditional.comparison = 'GreaterThanOrEqual' 
-  conditionalStep.args[0].Conditional.left = { Number: 5 }
-  conditionalStep.args[0].Conditional.right = { Number: 15 }
+  conditionalStep.args[0].Conditional.condition.Compare.comparison = 'GreaterThanOrEqual' 
+  conditionalStep.args[0].Conditional.condition.Compare.left = { Number: 5 }
+  conditionalStep.args[0].Conditional.condition.Compare.right = { Number: 15 }
   output = await execute([ conditionalStep ], false)
   t.is(output.rolls.length, 0, 'does not compare GreaterThanOrEqual')
 
   output.rolls = []
-  conditionalStep.args[0].Conditional.comparison = 'GreaterThanOrEqual' 
-  conditionalStep.args[0].Conditional.left = { Number: 15 }
-  conditionalStep.args[0].Conditional.right = { Number: 15 }
+  conditionalStep.args[0].Conditional.condition.Compare.comparison = 'GreaterThanOrEqual' 
+  conditionalStep.args[0].Conditional.condition.Compare.left = { Number: 15 }
+  conditionalStep.args[0].Conditional.condition.Compare.right = { Number: 15 }
   output = await execute([ conditionalStep ],false)
   t.is(output.rolls.length, 1, 'does not compare GreaterThanOrEqual')
 })
 
 test('it executes a <= Conditional step', async (t) => {
-  conditionalStep.args[0].Conditional.comparison = 'LessThanOrEqual' 
-  conditionalStep.args[0].Conditional.left = { Float: 5.25 }
-  conditionalStep.args[0].Conditional.right = { Number: 6 }
+  conditionalStep.args[0].Conditional.condition.Compare.comparison = 'LessThanOrEqual' 
+  conditionalStep.args[0].Conditional.condition.Compare.left = { Float: 5.25 }
+  conditionalStep.args[0].Conditional.condition.Compare.right = { Number: 6 }
   let output = await execute([ conditionalStep ], false)
   t.is(output.rolls.length, 1, 'does not compare LessThanOrEqual')
 
-  conditionalStep.args[0].Conditional.comparison = 'LessThanOrEqual' 
-  conditionalStep.args[0].Conditional.left = { Number: 25 }
-  conditionalStep.args[0].Conditional.right = { Number: 5 }
+  conditionalStep.args[0].Conditional.condition.Compare.comparison = 'LessThanOrEqual' 
+  conditionalStep.args[0].Conditional.condition.Compare.left = { Number: 25 }
+  conditionalStep.args[0].Conditional.condition.Compare.right = { Number: 5 }
   output = await execute([ conditionalStep ], false)
   t.is(output.rolls.length, 0, 'does not compare LessThanOrEqual')
 
-  conditionalStep.args[0].Conditional.comparison = 'LessThanOrEqual' 
-  conditionalStep.args[0].Conditional.left = { Number: 2 }
-  conditionalStep.args[0].Conditional.right = { Number: 2 }
+  conditionalStep.args[0].Conditional.condition.Compare.comparison = 'LessThanOrEqual' 
+  conditionalStep.args[0].Conditional.condition.Compare.left = { Number: 2 }
+  conditionalStep.args[0].Conditional.condition.Compare.right = { Number: 2 }
   output = await execute([ conditionalStep ], false)
   t.is(output.rolls.length, 1, 'does not compare LessThanOrEqual')
 })
 
 test('it executes a > Conditional step', async (t) => {
-  conditionalStep.args[0].Conditional.comparison = 'GreaterThan' 
-  conditionalStep.args[0].Conditional.left = { Float: 15.5 }
-  conditionalStep.args[0].Conditional.right = { Number: 10 }
+  conditionalStep.args[0].Conditional.condition.Compare.comparison = 'GreaterThan' 
+  conditionalStep.args[0].Conditional.condition.Compare.left = { Float: 15.5 }
+  conditionalStep.args[0].Conditional.condition.Compare.right = { Number: 10 }
   let output = await execute([ conditionalStep ], false)
   t.is(output.rolls.length, 1, 'does not compare GreaterThan')
 
-  conditionalStep.args[0].Conditional.comparison = 'GreaterThan' 
-  conditionalStep.args[0].Conditional.left = { Number: 5 }
-  conditionalStep.args[0].Conditional.right = { Number: 15 }
+  conditionalStep.args[0].Conditional.condition.Compare.comparison = 'GreaterThan' 
+  conditionalStep.args[0].Conditional.condition.Compare.left = { Number: 5 }
+  conditionalStep.args[0].Conditional.condition.Compare.right = { Number: 15 }
   output = await execute([ conditionalStep ], false)
   t.is(output.rolls.length, 0, 'does not compare GreaterThan')
 
-  conditionalStep.args[0].Conditional.comparison = 'GreaterThan' 
-  conditionalStep.args[0].Conditional.left = { Number: 15 }
-  conditionalStep.args[0].Conditional.right = { Number: 15 }
+  conditionalStep.args[0].Conditional.condition.Compare.comparison = 'GreaterThan' 
+  conditionalStep.args[0].Conditional.condition.Compare.left = { Number: 15 }
+  conditionalStep.args[0].Conditional.condition.Compare.right = { Number: 15 }
   output = await execute([ conditionalStep ], false)
   t.is(output.rolls.length, 0, 'does not compare GreaterThan')
 })
 
 test('it executes a < Conditional step', async (t) => {
-  conditionalStep.args[0].Conditional.comparison = 'LessThan' 
-  conditionalStep.args[0].Conditional.left = { Float: 5.25 }
-  conditionalStep.args[0].Conditional.right = { Number: 6 }
+  conditionalStep.args[0].Conditional.condition.Compare.comparison = 'LessThan' 
+  conditionalStep.args[0].Conditional.condition.Compare.left = { Float: 5.25 }
+  conditionalStep.args[0].Conditional.condition.Compare.right = { Number: 6 }
   let output = await execute([ conditionalStep ], false)
   t.is(output.rolls.length, 1, 'does not compare LessThan')
 
   output.rolls = []
-  conditionalStep.args[0].Conditional.comparison = 'LessThan' 
-  conditionalStep.args[0].Conditional.left = { Number: 25 }
-  conditionalStep.args[0].Conditional.right = { Number: 5 }
+  conditionalStep.args[0].Conditional.condition.Compare.comparison = 'LessThan' 
+  conditionalStep.args[0].Conditional.condition.Compare.left = { Number: 25 }
+  conditionalStep.args[0].Conditional.condition.Compare.right = { Number: 5 }
   output = await execute([ conditionalStep ], false)
   t.is(output.rolls.length, 0, 'does not compare LessThan')
 
   output.rolls = []
-  conditionalStep.args[0].Conditional.comparison = 'LessThan' 
-  conditionalStep.args[0].Conditional.left = { Number: 2 }
-  conditionalStep.args[0].Conditional.right = { Number: 2 }
+  conditionalStep.args[0].Conditional.condition.Compare.comparison = 'LessThan' 
+  conditionalStep.args[0].Conditional.condition.Compare.left = { Number: 2 }
+  conditionalStep.args[0].Conditional.condition.Compare.right = { Number: 2 }
   output = await execute([ conditionalStep ], false)
   t.is(output.rolls.length, 0, 'does not compare LessThan')
 })
 
+test('it executes a Conditional step with &&, || and !', async (t) => {
+  const compare = (left, comparison, right) => ({
+    Compare: { left: { Number: left }, comparison, right: { Number: right } },
+  })
+  const conditional = conditionalStep.args[0].Conditional
+
+  // 10 >= 5 && !(1 == 2)
+  conditional.condition = { And: [compare(10, 'GreaterThanOrEqual', 5), { Not: compare(1, 'EqualTo', 2) }] }
+  let output = await execute([ conditionalStep ], false)
+  t.is(output.rolls.length, 1, 'does not check And/Not')
+
+  // 1 > 2 || 3 != 3
+  conditional.condition = { Or: [compare(1, 'GreaterThan', 2), compare(3, 'NotEqualTo', 3)] }
+  output = await execute([ conditionalStep ], false)
+  t.is(output.rolls.length, 0, 'does not check Or/NotEqualTo')
+})
+
+test('it executes a Conditional step comparing text and booleans', async (t) => {
+  const compare = (left, comparison, right) => ({ Compare: { left, comparison, right } })
+  const conditional = conditionalStep.args[0].Conditional
+
+  // 'abc' == 'abc', 'abc' != 'abd' and 'abc' < 'abd'
+  conditional.condition = compare({ Text: 'abc' }, 'EqualTo', { Text: 'abc' })
+  let output = await execute([ conditionalStep ], false)
+  t.is(output.rolls.length, 1, 'does not compare equal text')
+
+  conditional.condition = compare({ Text: 'abc' }, 'EqualTo', { Text: 'abd' })
+  output = await execute([ conditionalStep ], false)
+  t.is(output.rolls.length, 0, 'compares different text as equal')
+
+  conditional.condition = compare({ Text: 'abc' }, 'LessThan', { Text: 'abd' })
+  output = await execute([ conditionalStep ], false)
+  t.is(output.rolls.length, 1, 'does not order text')
+
+  // text that reads as a number still compares as one
+  conditional.condition = compare({ Text: '10' }, 'EqualTo', { Number: 10 })
+  output = await execute([ conditionalStep ], false)
+  t.is(output.rolls.length, 1, 'does not compare numeric text as a number')
+
+  // true == true, true != false and true != 1
+  conditional.condition = compare({ Boolean: true }, 'EqualTo', { Boolean: true })
+  output = await execute([ conditionalStep ], false)
+  t.is(output.rolls.length, 1, 'does not compare equal booleans')
+
+  conditional.condition = compare({ Boolean: true }, 'EqualTo', { Boolean: false })
+  output = await execute([ conditionalStep ], false)
+  t.is(output.rolls.length, 0, 'compares different booleans as equal')
+
+  conditional.condition = compare({ Boolean: true }, 'EqualTo', { Number: 1 })
+  output = await execute([ conditionalStep ], false)
+  t.is(output.rolls.length, 0, 'compares a boolean and a number as equal')
+})
+
+test('it executes the first else-if of a Conditional step that holds', async (t) => {
+  const compare = (left, comparison, right) => ({
+    Compare: { left: { Number: left }, comparison, right: { Number: right } },
+  })
+  const conditional = conditionalStep.args[0].Conditional
+
+  // 1 > 2 ? !r 1d20 : 3 > 2 ? { !r 1d20 !r 1d20 } : 4 > 2 ? !r 1d20 : !exit
+  conditional.condition = compare(1, 'GreaterThan', 2)
+  conditional.else_if = [
+    { condition: compare(3, 'GreaterThan', 2), success: [stepRoll, stepRoll] },
+    { condition: compare(4, 'GreaterThan', 2), success: [stepRoll] },
+  ]
+  let output = await execute([ conditionalStep ], false)
+  t.is(output.rolls.length, 2, 'does not run the first else-if that holds')
+
+  // no condition holds so the failure branch runs
+  conditional.else_if[0].condition = compare(1, 'GreaterThan', 2)
+  conditional.else_if[1].condition = compare(1, 'GreaterThan', 2)
+  output = await execute([ conditionalStep ], false)
+  t.is(output.rolls.length, 0, 'does not fall through to the failure branch')
+})
+
+test('it executes a Loop step', async (t) => {
+  const say = (parts) => ({ args: [{ Say: { Message: { parts } } }], op: 'Say', result: 'Ignore' })
+  const loop = (variable, over, steps) => ({ args: [{ Loop: { variable, over, steps } }], op: 'Loop', result: 'Ignore' })
+
+  // !for $i in 1..3 { !say "Round $i" }
+  let output = await execute([
+    loop({ Variable: 'i' }, { Range: [{ Number: 1 }, { Number: 3 }] }, [say([{ Text: 'Round ' }, { Variable: 'i' }])]),
+  ], false)
+  t.deepEqual(output.messages.map(message => message.message), ['Round 1', 'Round 2', 'Round 3'])
+
+  // !for @player in [@me, @ash] { !say "@player.hp" }
+  TTML.setToken('me', token, {})
+  TTML.setToken('ash', tokenMe, {})
+  const players = { Items: { Array: [
+    { Token: { attribute: null, macro_name: null, name: 'me' } },
+    { Token: { attribute: null, macro_name: null, name: 'ash' } },
+  ] } }
+  output = await execute([
+    loop({ Token: { attribute: null, macro_name: null, name: 'player' } }, players, [
+      say([{ Token: { attribute: 'hp', macro_name: null, name: 'player' } }]),
+    ]),
+  ], false)
+  t.deepEqual(output.messages.map(message => message.message), ['42', '15'])
+
+  // loops are capped like the interpreter
+  const error = await t.throws(execute([
+    loop({ Variable: 'i' }, { Range: [{ Number: 1 }, { Number: 101 }] }, []),
+  ], false))
+  t.is(error.message, 'Too many loop iterations (the limit is 100)')
+})
+
+test('it executes every step of a Conditional branch', async (t) => {
+  conditionalStep.args[0].Conditional.success = [stepRoll, stepRoll]
+  const output = await execute([ conditionalStep ], false)
+  t.is(output.rolls.length, 2)
+})
+
 test('it executes a Exit step', async (t) => {
   const exitStep = {
     args: [],
@@ -743,6 +867,66 @@ test('it executes a Roll step using the api', async (t) => {
   }
   output = await execute([ roll5 ], false)
   t.true(executor.api.calledWith(`1d20['Comment Strike (+2 Proficiency)']`))
+
+  // 1d20 + 1d4 and 1d20 - 2d4kh1 + 3
+  const roll6 = {
+    args: [
+      { Roll: { N: { Number: 1 } } },
+      { Roll: { D: { Number: 20 } } },
+      { Roll: { GroupPos: [{ N: { Number: 1 } }, { D: { Number: 4 } }] } },
+    ],
+    op: 'Roll',
+    result: 'Ignore',
+  }
+  output = await execute([ roll6 ], false)
+  t.true(executor.api.calledWith('1d20+1d4'))
+
+  const roll7 = {
+    args: [
+      { Roll: { N: { Number: 1 } } },
+      { Roll: { D: { Number: 20 } } },
+      { Roll: { GroupNeg: [{ N: { Number: 2 } }, { D: { Number: 4 } }, { H: { Number: 1 } }] } },
+      { Roll: { ModifierPos: { Number: 3 } } },
+    ],
+    op: 'Roll',
+    result: 'Ignore',
+  }
+  output = await execute([ roll7 ], false)
+  t.true(executor.api.calledWith('1d20-2d4kh1+3'))
+
+  // 1d20 + (@me.dexterity_mod * 2) - (1 + 1)
+  TTML.setToken('me', token, {})
+  const roll8 = {
+    args: [
+      { Roll: { N: { Number: 1 } } },
+      { Roll: { D: { Number: 20 } } },
+      { Roll: { ModifierPos: { Expr: { Binary: [
+        { Value: { Token: { attribute: 'dexterity_mod', macro_name: null, name: 'me' } } },
+        'Multiply',
+        { Value: { Number: 2 } },
+      ] } } } },
+      { Roll: { ModifierNeg: { Expr: { Binary: [{ Value: { Number: 1 } }, 'Add', { Value: { Number: 1 } }] } } } },
+    ],
+    op: 'Roll',
+    result: 'Ignore',
+  }
+  output = await execute([ roll8 ], false)
+  t.true(executor.api.calledWith('1d20+4-2'))
+
+  // 4d10cs>=8cf1gl<=2
+  const roll9 = {
+    args: [
+      { Roll: { N: { Number: 4 } } },
+      { Roll: { D: { Number: 10 } } },
+      { Roll: { CS: { op: 'GreaterThanOrEqual', value: { Number: 8 } } } },
+      { Roll: { CF: { op: 'LessThanOrEqual', value: { Number: 1 } } } },
+      { Roll: { GL: { op: 'LessThanOrEqual', value: { Number: 2 } } } },
+    ],
+    op: 'Roll',
+    result: 'Ignore',
+  }
+  output = await execute([ roll9 ], false)
+  t.true(executor.api.calledWith('4d10cs>=8cf<=1gl<=2'))
 })
 
 test.only('it executes a Roll advantage', async (t) => {
@@ -1820,16 +2004,18 @@ test('it sets variables using concat strings', async (t) => {
   t.is(executor.tokens.me.attributes.raz.Text, 'Test String Combined')
 })
 
-test('it sets variables using primitives', async (t) => {
+test('it sets token attributes using expressions', async (t) => {
   const assignStep = {
     args: [
       {
         Assign: {
           left: { Token: { attribute: 'roo', macro_name: null, name: 'me' } },
           right: [
-            { Token: { attribute: 'hp', macro_name: null, name: 'me' } },
-            { Primitive: 'Subtract' },
-            { Float: 5.5 },
+            { Expr: { Binary: [
+              { Value: { Token: { attribute: 'hp', macro_name: null, name: 'me' } } },
+              'Subtract',
+              { Value: { Float: 5.5 } },
+            ] } },
           ],
         },
       },
@@ -1925,16 +2111,18 @@ test('it sets variables using concat strings', async (t) => {
   t.is(executor.results.baz, 'Test String Combined And More')
 })
 
-test('it sets variables using primitives', async (t) => {
+test('it sets variables using expressions', async (t) => {
   const assignStep = {
     args: [
       {
         Assign: {
           left: { Variable: 'foo' },
           right: [
-            { Token: { attribute: 'hp', macro_name: null, name: 'me' } },
-            { Primitive: 'Add' },
-            { Number: 5 },
+            { Expr: { Binary: [
+              { Value: { Token: { attribute: 'hp', macro_name: null, name: 'me' } } },
+              'Add',
+              { Value: { Number: 5 } },
+            ] } },
           ],
         },
       },
@@ -1950,6 +2138,61 @@ test('it sets variables using primitives', async (t) => {
   t.is(executor.results.foo, 47)
 })
 
+test('it works out expressions with precedence', async (t) => {
+  const number = (n) => ({ Value: { Number: n } })
+  const assign = (left, expr) => ({
+    args: [{ Assign: { left: { Variable: left }, right: [{ Expr: expr }] } }],
+    op: 'Lambda',
+    result: 'Ignore',
+  })
+
+  // 2 + 3 * 4, -(2 + 3) % 4 and 7 \ 2
+  await execute([
+    assign('sum', { Binary: [number(2), 'Add', { Binary: [number(3), 'Multiply', number(4)] }] }),
+    assign('mod', { Binary: [{ Negate: { Binary: [number(2), 'Add', number(3)] } }, 'Modulo', number(4)] }),
+    assign('half', { Binary: [number(7), 'IntegerDivide', number(2)] }),
+  ], false)
+  t.is(executor.results.sum, 14)
+  t.is(executor.results.mod, 3)
+  t.is(executor.results.half, 3)
+
+  const error = await t.throws(execute([
+    assign('nope', { Binary: [{ Value: { Float: 1.5 } }, 'Divide', number(0)] }),
+  ], false))
+  t.is(error.message, 'Cannot divide by zero')
+})
+
+test('it gets items and lengths of arrays, objects and text', async (t) => {
+  const assign = (left, value) => ({
+    args: [{ Assign: { left: { Variable: left }, right: [value] } }],
+    op: 'Lambda',
+    result: 'Ignore',
+  })
+  const list = { Variable: 'list' }
+  TTML.setToken('me', token, {})
+
+  // $last = $list[len($list) - 1], $str = $stats.str and $letters = len(@me.name)
+  const output = await execute([
+    assign('list', { Array: [{ Number: 4 }, { Number: 6 }, { Number: 8 }] }),
+    assign('stats', { Object: { str: { Number: 12 } } }),
+    assign('last', { Index: [list, { Expr: { Binary: [{ Value: { Length: list } }, 'Subtract', { Value: { Number: 1 } }] } }] }),
+    assign('str', { Index: [{ Variable: 'stats' }, { Text: 'str' }] }),
+    assign('letters', { Length: { Token: { attribute: 'name', macro_name: null, name: 'me' } } }),
+    {
+      args: [{ Say: { Message: { parts: [{ Text: 'First is ' }, { Index: [list, { Number: 0 }] }] } } }],
+      op: 'Say',
+      result: 'Ignore',
+    },
+  ], false)
+  t.is(executor.results.last, 8)
+  t.is(executor.results.str, 12)
+  t.is(executor.results.letters, 6)
+  t.is(output.messages[0].message, 'First is 4')
+
+  const error = await t.throws(execute([ assign('missing', { Index: [list, { Number: 3 }] }) ], false))
+  t.is(error.message, 'Cannot find 3 in [4,6,8]')
+})
+
 test.skip('it initializes with a TTML object', (t) => {})
 
 test('throws an error if a variable cannot be found', async (t) => {
@@ -11,6 +11,7 @@ pub enum Arg {
     Deduct(Assign),
     Function(ArgValue),
     Input(TextInterpolated),
+    Loop(Box<Loop>),
     Prompt(Prompt),
     Roll(RollArg),
     Say(SayArg),
@@ -27,10 +28,14 @@ pub enum Arg {
 pub enum ArgValue {
     Array(Vec<ArgValue>),
     Boolean(bool),
+    Expr(Box<Expr>),
     Float(f32),
+    /// An item of an array or object (e.g. $list[0] or $stats.str)
+    Index(Box<ArgValue>, Box<ArgValue>),
+    /// Items in an array or object, or characters in text (e.g. len($list))
+    Length(Box<ArgValue>),
     Number(i32),
     Object(HashMap<String, ArgValue>),
-    Primitive(Primitive),
     Step(Step),
     Text(String),
     TextInterpolated(TextInterpolated),
@@ -54,6 +59,7 @@ pub struct Case {
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub enum ComparisonArg {
     EqualTo,
+    NotEqualTo,
     GreaterThan,
     GreaterThanOrEqual,
     LessThan,
@@ -68,11 +74,80 @@ pub struct Comparitive {
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct Conditional {
+    pub condition: Condition,
+    /// Steps run when the condition holds
+    pub success: Vec<Step>,
+    /// Conditions checked in order when the first one fails (e.g. "? ... : $1 > 10 ? ... : ...")
+    pub else_if: Vec<ElseIf>,
+    /// Steps run when no condition holds
+    pub failure: Vec<Step>,
+}
+
+/// A condition chained onto a conditional, checked when every condition before it fails
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ElseIf {
+    pub condition: Condition,
+    pub success: Vec<Step>,
+}
+
+/// Two values compared with each other (e.g. $1 >= 15)
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Comparison {
     pub left: ArgValue,
     pub comparison: ComparisonArg,
     pub right: ArgValue,
-    pub success: Option<Step>,
-    pub failure: Option<Step>,
+}
+
+/// The condition a conditional checks (e.g. $1 >= 15 && @me.hp > 0)
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Condition {
+    /// A single comparison (e.g. $1 >= 15)
+    Compare(Box<Comparison>),
+    /// Both hold, the right side is skipped when the left fails (&&)
+    And(Box<Condition>, Box<Condition>),
+    /// Either holds, the right side is skipped when the left passes (||)
+    Or(Box<Condition>, Box<Condition>),
+    /// The condition does not hold (!)
+    Not(Box<Condition>),
+}
+
+/// An arithmetic expression (e.g. 2 + 3 * 4), parentheses only change its shape
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Expr {
+    /// A number, variable, token or function call
+    Value(ArgValue),
+    /// Unary minus (e.g. -$foo)
+    Negate(Box<Expr>),
+    /// Two expressions joined by an operator
+    Binary(Box<Expr>, Primitive, Box<Expr>),
+}
+
+impl Expr {
+    /// A value on its own is returned as is, anything else as an ArgValue::Expr
+    pub fn into_value(self) -> ArgValue {
+        match self {
+            Expr::Value(value) => value,
+            expr => ArgValue::Expr(Box::new(expr)),
+        }
+    }
+}
+
+/// Steps run once for every item (e.g. !for $i in 1..3 { !say "$i" })
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Loop {
+    /// Set to each item in turn, a variable (e.g. $i) or a token standing in for each token (e.g. @player)
+    pub variable: ArgValue,
+    pub over: LoopOver,
+    pub steps: Vec<Step>,
+}
+
+/// What a loop runs over
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum LoopOver {
+    /// Every whole number from the first to the last, including both (e.g. 1..$count)
+    Range(ArgValue, ArgValue),
+    /// Every item of an array (e.g. [@alice, @bob] or $list)
+    Items(ArgValue),
 }
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
@@ -87,6 +162,8 @@ pub enum MacroOp {
     Input,
     /// Lamda (assignment or conditional argument)
     Lambda,
+    /// Loop (!for or !each)
+    Loop,
     /// Macro Name
     Name(String),
     /// Primitive operations
@@ -115,6 +192,10 @@ pub enum MacroOp {
 pub enum Primitive {
     Add,
     Divide,
+    /// Division rounded down (`\`)
+    IntegerDivide,
+    /// Remainder of division rounded down (`%`)
+    Modulo,
     Multiply,
     Subtract,
 }
@@ -135,12 +216,17 @@ pub struct SwitchOption {
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub enum RollArg {
     Advantage,
+    CF(Comparitive), // e.g. cf<=1 (failures cancel successes)
     Comment(ArgValue),
+    CS(Comparitive), // e.g. cs>=8 (count successes instead of adding dice)
     D(ArgValue), // e.g. d20
     Disadvantage,
     E(ArgValue),
     GT(ArgValue),
     GTE(ArgValue),
+    GroupNeg(Vec<RollArg>), // e.g. - 1d4 (another group of dice with its own flags)
+    GroupPos(Vec<RollArg>), // e.g. + 2d6kh1
+    GL(Comparitive), // e.g. gl<=1 (glitch when more than half the dice match)
     H(ArgValue),
     L(ArgValue),
     LT(ArgValue),
@@ -156,6 +242,25 @@ pub enum RollArg {
     Sides(Vec<ArgValue>),
 }
 
+/// A range of bytes in the macro a node was parsed from
+///
+/// Spans are left out when comparing nodes, so the same macro laid out differently
+/// still parses to equal programs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Span {
+    /// Offset of the first byte
+    pub start: usize,
+    /// Offset just past the last byte
+    pub end: usize,
+}
+
+impl Span {
+    /// The part of the macro the span covers
+    pub fn text<'a>(&self, input: &'a str) -> &'a str {
+        input.get(self.start..self.end).unwrap_or_default()
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub enum SayArg {
     Message(TextInterpolated),
@@ -168,9 +273,18 @@ pub enum TargetArg {
     Message(TextInterpolated),
 }
 
-#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct TextInterpolated {
     pub parts: Vec<ArgValue>,
+    /// Where the text was parsed from, including its quotes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub span: Option<Span>,
+}
+
+impl PartialEq for TextInterpolated {
+    fn eq(&self, other: &TextInterpolated) -> bool {
+        self.parts == other.parts
+    }
 }
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
@@ -179,11 +293,20 @@ pub enum TemplateArg {
     Attributes(ArgValue),
 }
 
-#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct TokenArg {
     pub name: String,
     pub attribute: Option<String>,
     pub macro_name: Option<String>,
+    /// Where the token was parsed from (e.g. the "@me.dexterity" in "!r 1d20+@me.dexterity")
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub span: Option<Span>,
+}
+
+impl PartialEq for TokenArg {
+    fn eq(&self, other: &TokenArg) -> bool {
+        self.name == other.name && self.attribute == other.attribute && self.macro_name == other.macro_name
+    }
 }
 
 impl TokenArg {
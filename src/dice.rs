@@ -0,0 +1,576 @@
+use arg::{Arg, ArgValue, ComparisonArg, Comparitive, RollArg};
+use error::ExecutionError;
+use limits::Limit;
+use random::RandomSource;
+use std::fmt;
+
+/// The most times a single die can be rerolled or explode by default
+pub const MAX_REROLLS: usize = 100;
+
+/// A die to roll
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Die {
+    /// A die numbered one through the number of sides (e.g. d20)
+    Sides(i32),
+    /// A die with custom faces (e.g. d[-1, 0, 1])
+    Faces(Vec<i32>),
+}
+
+/// A resolved comparison against a die (e.g. the "<2" in ro<2)
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Comparison {
+    pub op: ComparisonArg,
+    pub value: i32,
+}
+
+impl Comparison {
+    /// Check if a die matches the comparison
+    pub fn matches(&self, face: i32) -> bool {
+        match self.op {
+            ComparisonArg::EqualTo => face == self.value,
+            ComparisonArg::NotEqualTo => face != self.value,
+            ComparisonArg::GreaterThan => face > self.value,
+            ComparisonArg::GreaterThanOrEqual => face >= self.value,
+            ComparisonArg::LessThan => face < self.value,
+            ComparisonArg::LessThanOrEqual => face <= self.value,
+        }
+    }
+
+    fn to_symbol(&self) -> &'static str {
+        match self.op {
+            ComparisonArg::EqualTo => "==",
+            ComparisonArg::NotEqualTo => "!=",
+            ComparisonArg::GreaterThan => ">",
+            ComparisonArg::GreaterThanOrEqual => ">=",
+            ComparisonArg::LessThan => "<",
+            ComparisonArg::LessThanOrEqual => "<=",
+        }
+    }
+}
+
+/// A roll with every argument resolved to a number (e.g. 2d20kh1+5)
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Dice {
+    /// Number of dice to roll ("N" in NdD)
+    pub count: i32,
+    /// The die to roll ("D" in NdD), a flat number is rolled without one
+    pub die: Option<Die>,
+    /// Roll another die every time a die is at or above this (e)
+    pub explode: Option<i32>,
+    /// Only keep dice matching every comparison (gt, gte, lt, lte)
+    pub keep: Vec<Comparison>,
+    /// Keep the highest number of dice (kh)
+    pub keep_highest: Option<i32>,
+    /// Keep the lowest number of dice (kl)
+    pub keep_lowest: Option<i32>,
+    /// Count dice matching as successes instead of adding them up (cs)
+    pub success: Option<Comparison>,
+    /// Count dice matching as failures, each one cancels a success (cf)
+    pub failure: Option<Comparison>,
+    /// Glitch when more than half the dice match (gl)
+    pub glitch: Option<Comparison>,
+    /// The lowest a die can be (min)
+    pub min: Option<i32>,
+    /// The highest a die can be (max)
+    pub max: Option<i32>,
+    /// Reroll a die once if it matches (ro)
+    pub reroll_once: Option<Comparison>,
+    /// Reroll a die for as long as it matches (rr)
+    pub reroll: Option<Comparison>,
+    /// Most times a single die can be rerolled or explode
+    pub max_rerolls: usize,
+    /// Other groups of dice added to or taken from the total (e.g. +1d4)
+    pub groups: Vec<DiceGroup>,
+    /// Sum of every modifier (e.g. +5-2)
+    pub modifier: i32,
+    /// Comment attached to the roll
+    pub comment: Option<String>,
+}
+
+/// A group of dice added to or taken from another roll (e.g. the -1d4 in 1d20-1d4)
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DiceGroup {
+    pub dice: Dice,
+    /// Whether the group is taken from the total
+    pub negative: bool,
+}
+
+/// The outcome of a roll
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RollResult {
+    /// The roll in dice notation (e.g. 2d20kh1+5)
+    pub equation: String,
+    /// Every die rolled, including dice that were dropped
+    pub dice: Vec<DieRoll>,
+    /// Outcomes of the other groups of dice, in order
+    pub groups: Vec<GroupResult>,
+    /// Successes less failures, when counting successes (cs)
+    pub successes: Option<i32>,
+    /// Dice counted as failures (cf)
+    pub failures: i32,
+    /// Whether no dice succeeded and at least one failed (cf)
+    pub is_botch: bool,
+    /// Whether more than half the dice glitched (gl)
+    pub is_glitch: bool,
+    /// Sum of the dice before modifiers, including other groups, or the successes
+    /// when counting successes
+    pub raw_value: i32,
+    /// Total of the roll, including modifiers
+    pub value: i32,
+    /// Comment attached to the roll
+    pub comment: Option<String>,
+}
+
+/// The outcome of a group of dice added to or taken from another roll
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct GroupResult {
+    pub result: RollResult,
+    /// Whether the group was taken from the total
+    pub negative: bool,
+}
+
+impl RollResult {
+    /// Faces of the dice counted towards the total, leaving out other groups
+    pub fn kept(&self) -> Vec<i32> {
+        self.dice.iter().filter(|die| die.dropped.is_none()).map(|die| die.value).collect()
+    }
+}
+
+impl fmt::Display for RollResult {
+    /// Render the roll for chat (e.g. "2d20kh1+1d4: [17, ~~4~~] + [3]")
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let faces = |dice: &[DieRoll]| dice.iter().map(|die| die.to_string()).collect::<Vec<String>>().join(", ");
+        write!(f, "{}: [{}]", self.equation, faces(&self.dice))?;
+        for group in &self.groups {
+            let sign = if group.negative { "-" } else { "+" };
+            write!(f, " {} [{}]", sign, faces(&group.result.dice))?;
+        }
+        Ok(())
+    }
+}
+
+/// A single die of a roll
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DieRoll {
+    /// The face counted towards the total
+    pub value: i32,
+    /// The face rolled, before min or max changed it
+    pub rolled: i32,
+    /// Faces thrown away by rerolling the die (ro, rr)
+    pub rerolls: Vec<i32>,
+    /// Set when min or max changed the face
+    pub clamped: Option<Clamp>,
+    /// Set when the die does not count towards the total
+    pub dropped: Option<Dropped>,
+    /// Whether the die exploded, adding another die (e)
+    pub is_exploded: bool,
+    /// Whether the die was added by another die exploding
+    pub is_explosion: bool,
+}
+
+impl fmt::Display for DieRoll {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for reroll in &self.rerolls {
+            write!(f, "~~{}~~ ", reroll)?;
+        }
+        match self.dropped {
+            Some(_) => write!(f, "~~{}~~", self.value)?,
+            None => write!(f, "{}", self.value)?,
+        }
+        if self.is_exploded {
+            write!(f, "!")?;
+        }
+        Ok(())
+    }
+}
+
+/// How min or max changed a die
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Clamp {
+    /// Raised to the minimum (min)
+    Min,
+    /// Lowered to the maximum (max)
+    Max,
+}
+
+/// Why a die does not count towards the total
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Dropped {
+    /// Not one of the highest dice (kh)
+    KeepHighest,
+    /// Not one of the lowest dice (kl)
+    KeepLowest,
+    /// Did not match a threshold (gt, gte, lt, lte)
+    Threshold,
+}
+
+impl Dice {
+    /// Build dice from roll arguments, resolving variables and tokens with `resolve`
+    pub fn from_args<F>(args: &[Arg], mut resolve: F) -> Result<Dice, ExecutionError>
+        where F: FnMut(&ArgValue) -> Result<ArgValue, ExecutionError>
+    {
+        let rolls = args.iter().filter_map(|arg| match *arg {
+            Arg::Roll(ref roll) => Some(roll),
+            _ => None,
+        });
+        Dice::from_rolls(rolls, &mut resolve)
+    }
+
+    fn from_rolls<'a, I, F>(rolls: I, resolve: &mut F) -> Result<Dice, ExecutionError>
+        where I: IntoIterator<Item = &'a RollArg>, F: FnMut(&ArgValue) -> Result<ArgValue, ExecutionError>
+    {
+        let mut dice = Dice {
+            count: 1,
+            die: None,
+            explode: None,
+            keep: vec![],
+            keep_highest: None,
+            keep_lowest: None,
+            success: None,
+            failure: None,
+            glitch: None,
+            min: None,
+            max: None,
+            reroll_once: None,
+            reroll: None,
+            max_rerolls: MAX_REROLLS,
+            groups: vec![],
+            modifier: 0,
+            comment: None,
+        };
+        let mut advantage = None;
+
+        for roll in rolls {
+            match *roll {
+                RollArg::Advantage => advantage = Some(true),
+                RollArg::CF(ref comparitive) => dice.failure = Some(resolve_comparitive(comparitive, resolve)?),
+                RollArg::Comment(ref comment) => {
+                    if let ArgValue::Text(text) = resolve(comment)? {
+                        dice.comment = Some(text);
+                    }
+                },
+                RollArg::CS(ref comparitive) => dice.success = Some(resolve_comparitive(comparitive, resolve)?),
+                RollArg::D(ref value) => dice.die = Some(Die::Sides(to_number(&resolve(value)?)?)),
+                RollArg::Disadvantage => advantage = Some(false),
+                RollArg::E(ref value) => dice.explode = Some(to_number(&resolve(value)?)?),
+                RollArg::GT(ref value) => dice.keep.push(comparison(ComparisonArg::GreaterThan, &resolve(value)?)?),
+                RollArg::GTE(ref value) => dice.keep.push(comparison(ComparisonArg::GreaterThanOrEqual, &resolve(value)?)?),
+                RollArg::GroupNeg(ref rolls) => dice.groups.push(DiceGroup {
+                    dice: Dice::from_rolls(rolls, resolve)?,
+                    negative: true,
+                }),
+                RollArg::GroupPos(ref rolls) => dice.groups.push(DiceGroup {
+                    dice: Dice::from_rolls(rolls, resolve)?,
+                    negative: false,
+                }),
+                RollArg::GL(ref comparitive) => dice.glitch = Some(resolve_comparitive(comparitive, resolve)?),
+                RollArg::H(ref value) => dice.keep_highest = Some(to_number(&resolve(value)?)?),
+                RollArg::L(ref value) => dice.keep_lowest = Some(to_number(&resolve(value)?)?),
+                RollArg::LT(ref value) => dice.keep.push(comparison(ComparisonArg::LessThan, &resolve(value)?)?),
+                RollArg::LTE(ref value) => dice.keep.push(comparison(ComparisonArg::LessThanOrEqual, &resolve(value)?)?),
+                RollArg::Max(ref value) => dice.max = Some(to_number(&resolve(value)?)?),
+                RollArg::Min(ref value) => dice.min = Some(to_number(&resolve(value)?)?),
+                RollArg::ModifierNeg(ref value) => {
+                    dice.modifier = dice.modifier.saturating_sub(to_number(&resolve(value)?)?);
+                },
+                RollArg::ModifierPos(ref value) => {
+                    dice.modifier = dice.modifier.saturating_add(to_number(&resolve(value)?)?);
+                },
+                RollArg::N(ref value) => dice.count = to_number(&resolve(value)?)?,
+                RollArg::Primitive(_) => {},
+                RollArg::RO(ref comparitive) => dice.reroll_once = Some(resolve_comparitive(comparitive, resolve)?),
+                RollArg::RR(ref comparitive) => dice.reroll = Some(resolve_comparitive(comparitive, resolve)?),
+                RollArg::Sides(ref sides) => {
+                    let mut faces = vec![];
+                    for side in sides {
+                        faces.push(to_number(&resolve(side)?)?);
+                    }
+                    dice.die = Some(Die::Faces(faces));
+                },
+            }
+        }
+
+        // advantage and disadvantage roll twice and keep one
+        if let Some(advantage) = advantage {
+            dice.count = 2;
+            if dice.die.is_none() {
+                dice.die = Some(Die::Sides(20));
+            }
+            if advantage {
+                dice.keep_highest = Some(1);
+            } else {
+                dice.keep_lowest = Some(1);
+            }
+        }
+
+        dice.validate()?;
+        Ok(dice)
+    }
+
+    fn validate(&self) -> Result<(), ExecutionError> {
+        if self.count < 0 {
+            return Err(ExecutionError::InvalidRoll(format!("Cannot roll {} dice", self.count)));
+        }
+        match self.die {
+            Some(Die::Sides(sides)) if sides < 1 => {
+                Err(ExecutionError::InvalidRoll(format!("Cannot roll a die with {} sides", sides)))
+            },
+            Some(Die::Faces(ref faces)) if faces.is_empty() => {
+                Err(ExecutionError::InvalidRoll("Cannot roll a die without sides".to_string()))
+            },
+            _ => Ok(()),
+        }
+    }
+
+    /// The roll in dice notation (e.g. 2d20kh1+5)
+    pub fn equation(&self) -> String {
+        let mut equation = self.count.to_string();
+        match self.die {
+            Some(Die::Sides(sides)) => equation.push_str(&format!("d{}", sides)),
+            Some(Die::Faces(ref faces)) => {
+                let faces: Vec<String> = faces.iter().map(|f| f.to_string()).collect();
+                equation.push_str(&format!("d[{}]", faces.join(",")));
+            },
+            None => {},
+        }
+        if let Some(explode) = self.explode {
+            equation.push_str(&format!("e{}", explode));
+        }
+        if let Some(ref reroll) = self.reroll_once {
+            equation.push_str(&format!("ro{}{}", reroll.to_symbol(), reroll.value));
+        }
+        if let Some(ref reroll) = self.reroll {
+            equation.push_str(&format!("rr{}{}", reroll.to_symbol(), reroll.value));
+        }
+        if let Some(min) = self.min {
+            equation.push_str(&format!("min{}", min));
+        }
+        if let Some(max) = self.max {
+            equation.push_str(&format!("max{}", max));
+        }
+        for keep in &self.keep {
+            let flag = match keep.op {
+                ComparisonArg::GreaterThan => "gt",
+                ComparisonArg::GreaterThanOrEqual => "gte",
+                ComparisonArg::LessThan => "lt",
+                _ => "lte",
+            };
+            equation.push_str(&format!("{}{}", flag, keep.value));
+        }
+        if let Some(highest) = self.keep_highest {
+            equation.push_str(&format!("kh{}", highest));
+        }
+        if let Some(lowest) = self.keep_lowest {
+            equation.push_str(&format!("kl{}", lowest));
+        }
+        let counts = [("cs", &self.success), ("cf", &self.failure), ("gl", &self.glitch)];
+        for &(flag, count) in &counts {
+            if let Some(ref count) = *count {
+                equation.push_str(&format!("{}{}{}", flag, count.to_symbol(), count.value));
+            }
+        }
+        for group in &self.groups {
+            equation.push(if group.negative { '-' } else { '+' });
+            equation.push_str(&group.dice.equation());
+        }
+        if self.modifier > 0 {
+            equation.push_str(&format!("+{}", self.modifier));
+        } else if self.modifier < 0 {
+            equation.push_str(&self.modifier.to_string());
+        }
+        equation
+    }
+
+    /// Set the most times a die can be rerolled or explode, including in other groups
+    pub fn limit_rerolls(&mut self, max_rerolls: usize) {
+        self.max_rerolls = max_rerolls;
+        for group in &mut self.groups {
+            group.dice.limit_rerolls(max_rerolls);
+        }
+    }
+
+    /// Roll the dice, failing if a die would reroll or explode more than the limit
+    pub fn roll(&self, random: &mut dyn RandomSource) -> Result<RollResult, ExecutionError> {
+        let mut result = RollResult {
+            equation: self.equation(),
+            dice: vec![],
+            groups: vec![],
+            successes: None,
+            failures: 0,
+            is_botch: false,
+            is_glitch: false,
+            raw_value: 0,
+            value: 0,
+            comment: self.comment.clone(),
+        };
+        result.raw_value = match self.die {
+            Some(ref die) => {
+                result.dice = self.roll_dice(die, random)?;
+                self.count_successes(&mut result);
+                match result.successes {
+                    Some(successes) => successes,
+                    None => result.kept().iter().fold(0i32, |total, face| total.saturating_add(*face)),
+                }
+            },
+            // without a die we "roll" a flat number
+            None => self.count,
+        };
+
+        for group in &self.groups {
+            let rolled = group.dice.roll(random)?;
+            result.raw_value = if group.negative {
+                result.raw_value.saturating_sub(rolled.value)
+            } else {
+                result.raw_value.saturating_add(rolled.value)
+            };
+            result.groups.push(GroupResult {
+                result: rolled,
+                negative: group.negative,
+            });
+        }
+        result.value = result.raw_value.saturating_add(self.modifier);
+        Ok(result)
+    }
+
+    /// Count successes, failures and glitches among the kept dice (cs, cf, gl)
+    fn count_successes(&self, result: &mut RollResult) {
+        let kept = result.kept();
+        let matching = |count: &Option<Comparison>| match *count {
+            Some(ref count) => kept.iter().filter(|face| count.matches(**face)).count() as i32,
+            None => 0,
+        };
+
+        result.failures = matching(&self.failure);
+        result.is_glitch = matching(&self.glitch) * 2 > kept.len() as i32;
+        if self.success.is_some() {
+            let successes = matching(&self.success);
+            result.is_botch = successes == 0 && result.failures > 0;
+            result.successes = Some(successes - result.failures);
+        }
+    }
+
+    /// Roll every die of this group, marking the ones that are dropped
+    fn roll_dice(&self, die: &Die, random: &mut dyn RandomSource) -> Result<Vec<DieRoll>, ExecutionError> {
+        let mut dice = vec![];
+        for _ in 0..self.count {
+            let mut rerolls = vec![];
+            let mut face = roll_die(die, random);
+
+            if let Some(ref reroll) = self.reroll {
+                while reroll.matches(face) {
+                    if rerolls.len() >= self.max_rerolls {
+                        return Err(ExecutionError::LimitExceeded(Limit::Rerolls, self.max_rerolls));
+                    }
+                    rerolls.push(face);
+                    face = roll_die(die, random);
+                }
+            }
+            if let Some(ref reroll) = self.reroll_once {
+                if reroll.matches(face) {
+                    rerolls.push(face);
+                    face = roll_die(die, random);
+                }
+            }
+            dice.push(self.die_roll(face, rerolls, false));
+
+            if let Some(explode) = self.explode {
+                let mut explosions = 0;
+                while face >= explode {
+                    if explosions >= self.max_rerolls {
+                        return Err(ExecutionError::LimitExceeded(Limit::Rerolls, self.max_rerolls));
+                    }
+                    if let Some(last) = dice.last_mut() {
+                        last.is_exploded = true;
+                    }
+                    face = roll_die(die, random);
+                    dice.push(self.die_roll(face, vec![], true));
+                    explosions += 1;
+                }
+            }
+        }
+
+        for die in &mut dice {
+            if !self.keep.iter().all(|keep| keep.matches(die.value)) {
+                die.dropped = Some(Dropped::Threshold);
+            }
+        }
+
+        // keep the highest or lowest of the remaining dice
+        let mut order: Vec<usize> = (0..dice.len()).filter(|i| dice[*i].dropped.is_none()).collect();
+        order.sort_by_key(|i| dice[*i].value);
+        if let Some(highest) = self.keep_highest {
+            let drop = order.len().saturating_sub(highest.max(0) as usize);
+            for i in &order[..drop] {
+                dice[*i].dropped = Some(Dropped::KeepHighest);
+            }
+            order = order[drop..].to_vec();
+        }
+        if let Some(lowest) = self.keep_lowest {
+            for i in order.iter().skip(lowest.max(0) as usize) {
+                dice[*i].dropped = Some(Dropped::KeepLowest);
+            }
+        }
+        Ok(dice)
+    }
+
+    /// Apply min and max to a rolled face
+    fn die_roll(&self, rolled: i32, rerolls: Vec<i32>, is_explosion: bool) -> DieRoll {
+        let mut value = rolled;
+        let mut clamped = None;
+        if let Some(min) = self.min {
+            if value < min {
+                value = min;
+                clamped = Some(Clamp::Min);
+            }
+        }
+        if let Some(max) = self.max {
+            if value > max {
+                value = max;
+                clamped = Some(Clamp::Max);
+            }
+        }
+
+        DieRoll {
+            value,
+            rolled,
+            rerolls,
+            clamped,
+            dropped: None,
+            is_exploded: false,
+            is_explosion,
+        }
+    }
+}
+
+/// Roll a single die
+pub fn roll_die(die: &Die, random: &mut dyn RandomSource) -> i32 {
+    match *die {
+        Die::Sides(sides) => random.below(sides as u64) as i32 + 1,
+        Die::Faces(ref faces) => faces[random.below(faces.len() as u64) as usize],
+    }
+}
+
+fn comparison(op: ComparisonArg, value: &ArgValue) -> Result<Comparison, ExecutionError> {
+    Ok(Comparison {
+        op,
+        value: to_number(value)?,
+    })
+}
+
+fn resolve_comparitive<F>(comparitive: &Comparitive, resolve: &mut F) -> Result<Comparison, ExecutionError>
+    where F: FnMut(&ArgValue) -> Result<ArgValue, ExecutionError>
+{
+    comparison(comparitive.op.clone(), &resolve(&comparitive.value)?)
+}
+
+/// Get a whole number from a resolved value
+fn to_number(value: &ArgValue) -> Result<i32, ExecutionError> {
+    match *value {
+        ArgValue::Number(n) => Ok(n),
+        ArgValue::Float(f) => Ok(f.round() as i32),
+        ArgValue::Text(ref text) => text.trim().parse::<i32>().map_err(|_| {
+            ExecutionError::InvalidRoll(format!("\"{}\" is not a number", text))
+        }),
+        _ => Err(ExecutionError::InvalidRoll("Roll arguments must be numbers".to_string())),
+    }
+}
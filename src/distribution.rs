@@ -0,0 +1,368 @@
+//! Exact probabilities for the totals of a roll
+//!
+//! A die that keeps rerolling or exploding past `max_rerolls` makes `Dice::roll` fail with
+//! `LimitExceeded(Rerolls)` rather than settle on its last face, so the analysis does the
+//! same: those rolls count towards `Distribution::failure` instead of any total.
+
+use arg::{Arg, ArgValue};
+use dice::{Comparison, Dice, Die};
+use error::ExecutionError;
+use interpreter::{calculate, index, length};
+use limits::ExecutionLimits;
+use std::collections::BTreeMap;
+
+/// The chance of a roll landing on a total
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Outcome {
+    pub value: i32,
+    pub probability: f64,
+}
+
+/// Every total a roll can land on and how likely each one is
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Distribution {
+    /// Possible totals from lowest to highest
+    pub outcomes: Vec<Outcome>,
+    /// Chance of the roll failing because a die rerolls or explodes too many times
+    pub failure: f64,
+    /// Average of the totals, leaving out rolls that fail
+    pub mean: f64,
+    pub variance: f64,
+    pub min: i32,
+    pub max: i32,
+}
+
+impl Distribution {
+    fn from_totals(totals: BTreeMap<i32, f64>) -> Distribution {
+        let outcomes: Vec<Outcome> = totals.into_iter()
+            .filter(|&(_, probability)| probability > 0.0)
+            .map(|(value, probability)| Outcome { value, probability })
+            .collect();
+        let total = outcomes.iter().fold(0.0, |sum, o| sum + o.probability).max(f64::MIN_POSITIVE);
+        // rounding leaves tiny gaps that are not real failures
+        let failure = if 1.0 - total > 1e-12 { 1.0 - total } else { 0.0 };
+        let mean = outcomes.iter().fold(0.0, |sum, o| sum + o.value as f64 * o.probability) / total;
+        let variance = outcomes.iter().fold(0.0, |sum, o| {
+            sum + (o.value as f64 - mean).powi(2) * o.probability
+        }) / total;
+
+        Distribution {
+            min: outcomes.first().map_or(0, |o| o.value),
+            max: outcomes.last().map_or(0, |o| o.value),
+            outcomes,
+            failure,
+            mean,
+            variance,
+        }
+    }
+
+    /// The chance of the total being exactly `value`
+    pub fn probability(&self, value: i32) -> f64 {
+        self.outcomes.iter().find(|o| o.value == value).map_or(0.0, |o| o.probability)
+    }
+
+    /// The chance of the total being `value` or higher
+    pub fn at_least(&self, value: i32) -> f64 {
+        self.outcomes.iter().filter(|o| o.value >= value).fold(0.0, |sum, o| sum + o.probability)
+    }
+}
+
+/// Work out the exact distribution of the arguments of a roll step (e.g. 2d20kh1+5)
+///
+/// Every argument must be written out as a number, rolls that use variables or tokens
+/// can not be analyzed. Rolls too big for the default limits are rejected.
+pub fn analyze(args: &[Arg]) -> Result<Distribution, ExecutionError> {
+    analyze_limited(args, &ExecutionLimits::default())
+}
+
+/// Work out the exact distribution of the arguments of a roll step within `limits`
+pub fn analyze_limited(args: &[Arg], limits: &ExecutionLimits) -> Result<Distribution, ExecutionError> {
+    let mut dice = Dice::from_args(args, literal)?;
+    dice.limit_rerolls(limits.max_rerolls);
+    limits.check_analysis(&dice)?;
+    distribution(&dice)
+}
+
+fn literal(value: &ArgValue) -> Result<ArgValue, ExecutionError> {
+    match *value {
+        ArgValue::Expr(ref expr) => calculate(expr, &mut literal),
+        ArgValue::Index(ref value, ref key) => index(&literal(value)?, &literal(key)?),
+        ArgValue::Length(ref value) => length(&literal(value)?),
+        ArgValue::Variable(ref name) => Err(not_literal(&format!("${}", name))),
+        ArgValue::VariableReserved(n) => Err(not_literal(&format!("${}", n))),
+        ArgValue::Token(ref token) => Err(not_literal(&format!("@{}", token.name))),
+        ref value => Ok(value.clone()),
+    }
+}
+
+/// Work out the exact distribution of dice
+///
+/// The work grows with the dice and sides rolled, check the dice against
+/// `ExecutionLimits::check_analysis` first.
+pub fn distribution(dice: &Dice) -> Result<Distribution, ExecutionError> {
+    Ok(Distribution::from_totals(totals(dice)?))
+}
+
+/// Chance of each total of dice, including other groups and modifiers
+fn totals(dice: &Dice) -> Result<BTreeMap<i32, f64>, ExecutionError> {
+    let mut total = rolled(dice)?;
+    for group in &dice.groups {
+        let group_totals = totals(&group.dice)?;
+        if group.negative {
+            total = add(&total, &negate(&group_totals));
+        } else {
+            total = add(&total, &group_totals);
+        }
+    }
+    Ok(add(&total, &single(dice.modifier)))
+}
+
+/// Chance of each total of the dice of one group, before modifiers
+fn rolled(dice: &Dice) -> Result<BTreeMap<i32, f64>, ExecutionError> {
+    let die = match dice.die {
+        Some(ref die) => die,
+        None => return Ok(single(dice.count)),
+    };
+
+    let keeps_some = dice.keep_highest.is_some() || dice.keep_lowest.is_some();
+    if keeps_some && dice.explode.is_some() {
+        return Err(ExecutionError::InvalidRoll(
+            "Cannot analyze exploding dice that keep the highest or lowest".to_string()
+        ));
+    }
+    if keeps_some && dice.success.is_some() {
+        return Err(ExecutionError::InvalidRoll(
+            "Cannot analyze counting successes with dice that keep the highest or lowest".to_string()
+        ));
+    }
+
+    let faces = faces(die);
+    let rolled = reroll_once(&reroll(&faces, &dice.reroll, dice.max_rerolls), &faces, &dice.reroll_once);
+
+    let totals = if keeps_some {
+        keep(dice, &rolled)
+    } else {
+        // every die is independent, so add them up one at a time
+        let each = match dice.explode {
+            Some(explode) => exploding(dice, &rolled, &faces, explode),
+            None => counted(dice, &rolled, &single(0)),
+        };
+        (0..dice.count).fold(single(0), |total, _| add(&total, &each))
+    };
+    Ok(totals)
+}
+
+fn not_literal(name: &str) -> ExecutionError {
+    ExecutionError::InvalidRoll(format!("Cannot analyze a roll that uses {}", name))
+}
+
+fn single(value: i32) -> BTreeMap<i32, f64> {
+    let mut totals = BTreeMap::new();
+    totals.insert(value, 1.0);
+    totals
+}
+
+/// Chance of each face of a fair die
+fn faces(die: &Die) -> BTreeMap<i32, f64> {
+    let mut faces = BTreeMap::new();
+    match *die {
+        Die::Sides(sides) => {
+            for face in 1..=sides {
+                faces.insert(face, 1.0 / sides as f64);
+            }
+        },
+        Die::Faces(ref sides) => {
+            for face in sides {
+                *faces.entry(*face).or_insert(0.0) += 1.0 / sides.len() as f64;
+            }
+        },
+    }
+    faces
+}
+
+/// Reroll matching faces up to `max_rerolls` times (rr), a face still matching after that fails
+fn reroll(faces: &BTreeMap<i32, f64>, comparison: &Option<Comparison>, max_rerolls: usize) -> BTreeMap<i32, f64> {
+    let comparison = match *comparison {
+        Some(ref comparison) => comparison,
+        None => return faces.clone(),
+    };
+    let matched = faces.iter().filter(|&(face, _)| comparison.matches(*face)).fold(0.0, |sum, (_, p)| sum + p);
+    let attempts = (0..=max_rerolls).fold(0.0, |sum, k| sum + matched.powi(k as i32));
+
+    faces.iter().map(|(face, p)| {
+        if comparison.matches(*face) {
+            (*face, 0.0)
+        } else {
+            (*face, p * attempts)
+        }
+    }).collect()
+}
+
+/// Reroll matching faces one time with a fresh die (ro)
+fn reroll_once(rolled: &BTreeMap<i32, f64>, faces: &BTreeMap<i32, f64>, comparison: &Option<Comparison>) -> BTreeMap<i32, f64> {
+    let comparison = match *comparison {
+        Some(ref comparison) => comparison,
+        None => return rolled.clone(),
+    };
+    let matched = rolled.iter().filter(|&(face, _)| comparison.matches(*face)).fold(0.0, |sum, (_, p)| sum + p);
+
+    let mut totals: BTreeMap<i32, f64> = rolled.iter()
+        .filter(|&(face, _)| !comparison.matches(*face))
+        .map(|(face, p)| (*face, *p))
+        .collect();
+    for (face, p) in faces {
+        *totals.entry(*face).or_insert(0.0) += matched * p;
+    }
+    totals
+}
+
+/// The value a face counts for after min and max, or None when a threshold drops it
+fn value(dice: &Dice, face: i32) -> Option<i32> {
+    let mut value = face;
+    if let Some(min) = dice.min {
+        value = value.max(min);
+    }
+    if let Some(max) = dice.max {
+        value = value.min(max);
+    }
+    if dice.keep.iter().all(|keep| keep.matches(value)) {
+        Some(value)
+    } else {
+        None
+    }
+}
+
+/// What a kept die adds to the total, one for a success and minus one for a failure when
+/// counting successes (cs, cf)
+fn score(dice: &Dice, value: i32) -> i32 {
+    let success = match dice.success {
+        Some(ref success) => success,
+        None => return value,
+    };
+    let failed = match dice.failure {
+        Some(ref failure) => failure.matches(value),
+        None => false,
+    };
+    match (success.matches(value), failed) {
+        (true, false) => 1,
+        (false, true) => -1,
+        _ => 0,
+    }
+}
+
+/// What a die adds to the total, plus whatever `after` adds
+fn counted(dice: &Dice, rolled: &BTreeMap<i32, f64>, after: &BTreeMap<i32, f64>) -> BTreeMap<i32, f64> {
+    let mut totals = BTreeMap::new();
+    for (face, p) in rolled {
+        let value = value(dice, *face).map_or(0, |value| score(dice, value));
+        for (rest, q) in after {
+            *totals.entry(value.saturating_add(*rest)).or_insert(0.0) += p * q;
+        }
+    }
+    totals
+}
+
+/// What a die adds to the total along with every die it explodes into (e), a die that
+/// would explode more than `max_rerolls` times fails
+fn exploding(dice: &Dice, rolled: &BTreeMap<i32, f64>, faces: &BTreeMap<i32, f64>, explode: i32) -> BTreeMap<i32, f64> {
+    if dice.max_rerolls == 0 {
+        let stopped: BTreeMap<i32, f64> = rolled.iter().filter(|&(face, _)| *face < explode).map(|(face, p)| (*face, *p)).collect();
+        return counted(dice, &stopped, &single(0));
+    }
+    let (exploded, stopped): (BTreeMap<i32, f64>, BTreeMap<i32, f64>) = faces.iter().partition(|&(face, _)| *face >= explode);
+
+    // the last explosion fails if it explodes again
+    let mut chain = counted(dice, &stopped, &single(0));
+    for _ in 1..dice.max_rerolls {
+        let mut next = counted(dice, &stopped, &single(0));
+        for (total, p) in counted(dice, &exploded, &chain) {
+            *next.entry(total).or_insert(0.0) += p;
+        }
+        chain = next;
+    }
+
+    let (exploded, stopped): (BTreeMap<i32, f64>, BTreeMap<i32, f64>) = rolled.iter().partition(|&(face, _)| *face >= explode);
+    let mut totals = counted(dice, &stopped, &single(0));
+    for (total, p) in counted(dice, &exploded, &chain) {
+        *totals.entry(total).or_insert(0.0) += p;
+    }
+    totals
+}
+
+/// Add two independent totals together
+fn add(left: &BTreeMap<i32, f64>, right: &BTreeMap<i32, f64>) -> BTreeMap<i32, f64> {
+    let mut totals = BTreeMap::new();
+    for (a, p) in left {
+        for (b, q) in right {
+            *totals.entry(a.saturating_add(*b)).or_insert(0.0) += p * q;
+        }
+    }
+    totals
+}
+
+/// Flip totals that are taken away instead of added
+fn negate(totals: &BTreeMap<i32, f64>) -> BTreeMap<i32, f64> {
+    totals.iter().map(|(value, p)| (value.saturating_neg(), *p)).collect()
+}
+
+/// Keep the highest or lowest dice (kh, kl)
+///
+/// Dice kept by the thresholds are handed out from the highest value down, tracking the
+/// total of the dice whose rank lands in the kept range.
+fn keep(dice: &Dice, rolled: &BTreeMap<i32, f64>) -> BTreeMap<i32, f64> {
+    let count = dice.count.max(0) as usize;
+    let mut values: BTreeMap<i32, f64> = BTreeMap::new();
+    let mut dropped = 0.0;
+    for (face, p) in rolled {
+        match value(dice, *face) {
+            Some(value) => *values.entry(value).or_insert(0.0) += p,
+            None => dropped += p,
+        }
+    }
+    let survives = 1.0 - dropped;
+
+    let mut totals = BTreeMap::new();
+    for remaining in 0..=count {
+        let chance = choose(count, remaining) * survives.powi(remaining as i32) * dropped.powi((count - remaining) as i32);
+        if chance == 0.0 {
+            continue;
+        }
+
+        // ranks counted from the highest die: kh keeps the top, kl the bottom of those
+        let highest = dice.keep_highest.map_or(remaining, |h| (h.max(0) as usize).min(remaining));
+        let lowest = dice.keep_lowest.map_or(highest, |l| (l.max(0) as usize).min(highest));
+        let (from, to) = (highest - lowest, highest);
+
+        // states[i] holds the kept total after handing out i dice
+        let mut states: Vec<BTreeMap<i32, f64>> = vec![BTreeMap::new(); remaining + 1];
+        states[0].insert(0, 1.0);
+        for (value, p) in values.iter().rev() {
+            let p = p / survives;
+            let mut next: Vec<BTreeMap<i32, f64>> = vec![BTreeMap::new(); remaining + 1];
+            for (given, totals) in states.iter().enumerate() {
+                for (total, q) in totals {
+                    for taken in 0..=(remaining - given) {
+                        let kept = overlap(given, given + taken, from, to) as i32;
+                        let chance = q * choose(remaining - given, taken) * p.powi(taken as i32);
+                        *next[given + taken].entry(total.saturating_add(kept.saturating_mul(*value))).or_insert(0.0) += chance;
+                    }
+                }
+            }
+            states = next;
+        }
+
+        for (total, p) in &states[remaining] {
+            *totals.entry(*total).or_insert(0.0) += chance * p;
+        }
+    }
+    totals
+}
+
+/// How many of the ranks start..end fall within from..to
+fn overlap(start: usize, end: usize, from: usize, to: usize) -> usize {
+    end.min(to).saturating_sub(start.max(from))
+}
+
+fn choose(n: usize, k: usize) -> f64 {
+    (0..k).fold(1.0, |total, i| total * (n - i) as f64 / (i + 1) as f64)
+}
@@ -0,0 +1,158 @@
+use function::ArgType;
+use host::Request;
+use limits::Limit;
+use std::error::Error;
+use std::fmt;
+
+/// Errors encountered while executing a program
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum ExecutionError {
+    /// A function was called with the wrong number of arguments (name, expected, given)
+    ArgumentCount(String, usize, usize),
+    /// A function argument has the wrong type (name, argument number, expected type)
+    ArgumentType(String, usize, ArgType),
+    /// A token attribute could not be found (e.g. @me.hp)
+    AttributeNotFound(String),
+    /// A host callback returned an error or was cancelled
+    Callback(String),
+    /// No user defined function is registered under the name
+    FunctionNotFound(String),
+    /// A roll could not be made with the given arguments
+    InvalidRoll(String),
+    /// A value could not be used for an operation
+    InvalidValue(String),
+    /// A macro went over one of the execution limits, with the maximum allowed
+    LimitExceeded(Limit, usize),
+    /// A token macro called itself, directly or through other macros
+    MacroCycle(String),
+    /// A token macro could not be found (e.g. @npc->attack)
+    MacroNotFound(String),
+    /// An interactive command was run without the host providing a callback
+    MissingCallback(String),
+    /// @target was referenced before a target was selected
+    NoTarget,
+    /// The host deferred an interactive command, execution must be started with
+    /// `Interpreter::start` to suspend
+    Suspended(Box<Request>),
+    /// A token could not be found (e.g. @npc)
+    TokenNotFound(String),
+    /// A variable was referenced before it was set
+    VariableNotSet(String),
+}
+
+impl fmt::Display for ExecutionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ExecutionError::ArgumentCount(ref name, expected, given) => {
+                write!(f, "Function \"{}\" expects {} arguments but was given {}", name, expected, given)
+            },
+            ExecutionError::ArgumentType(ref name, n, ref expected) => {
+                write!(f, "Argument {} of function \"{}\" must be {}", n, name, expected)
+            },
+            ExecutionError::AttributeNotFound(ref attribute) => write!(f, "Cannot find token attribute {}", attribute),
+            ExecutionError::Callback(ref message)           => write!(f, "{}", message),
+            ExecutionError::FunctionNotFound(ref name)      => write!(f, "No defined function \"{}\"", name),
+            ExecutionError::InvalidRoll(ref message)        => write!(f, "{}", message),
+            ExecutionError::InvalidValue(ref message)       => write!(f, "{}", message),
+            ExecutionError::LimitExceeded(ref limit, max)   => write!(f, "{} (the limit is {})", limit, max),
+            ExecutionError::MacroCycle(ref name)            => write!(f, "Token macro {} calls itself", name),
+            ExecutionError::MacroNotFound(ref name)         => write!(f, "Token macro {} not found", name),
+            ExecutionError::MissingCallback(ref name)       => write!(f, "No {} callback provided", name),
+            ExecutionError::NoTarget                        => write!(f, "No target selected"),
+            ExecutionError::Suspended(ref request)          => write!(f, "Waiting on {}", request.name()),
+            ExecutionError::TokenNotFound(ref name)         => write!(f, "Cannot find token {}", name),
+            ExecutionError::VariableNotSet(ref name)        => write!(f, "Variable ${{{}}} is not set and can not be used", name),
+        }
+    }
+}
+
+impl Error for ExecutionError {}
+
+/// The part of a macro being parsed when an error was found
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Construct {
+    /// The macro name (e.g. #attack)
+    Name,
+    /// A command (e.g. !roll)
+    Command,
+    /// A conditional statement (e.g. $1 > 10 ? !say 'Hit' : !say 'Miss')
+    Conditional,
+    /// The options of !prompt or !case (e.g. [a:First, b:Second])
+    PromptOptions,
+    /// The message of !input
+    InputMessage,
+}
+
+impl Construct {
+    /// Find the construct for one of the parser's custom error codes
+    pub fn from_code(code: u32) -> Option<Construct> {
+        match code {
+            1 => Some(Construct::Name),
+            2 => Some(Construct::Command),
+            3 => Some(Construct::Conditional),
+            4 => Some(Construct::PromptOptions),
+            5 => Some(Construct::InputMessage),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Construct {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Construct::Name          => write!(f, "macro name"),
+            Construct::Command       => write!(f, "command"),
+            Construct::Conditional   => write!(f, "conditional statement"),
+            Construct::PromptOptions => write!(f, "prompt options"),
+            Construct::InputMessage  => write!(f, "input message"),
+        }
+    }
+}
+
+/// Errors encountered while parsing a program
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ParseError {
+    /// Line of the error, starting at 1
+    pub line: usize,
+    /// Column of the error in characters, starting at 1
+    pub column: usize,
+    /// Byte offset of the error in the input
+    pub offset: usize,
+    /// What was being parsed, if known
+    pub construct: Option<Construct>,
+    /// What the parser expected to find instead (e.g. "'")
+    pub expected: Vec<String>,
+    /// A readable description (e.g. "unterminated single-quoted string")
+    pub message: String,
+    /// The limit the macro can never run within and its maximum, when that is the error
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub limit: Option<(Limit, usize)>,
+}
+
+impl ParseError {
+    /// Create an error at a byte offset of the input, working out the line and column
+    pub fn new(input: &[u8], offset: usize, construct: Option<Construct>, expected: Vec<String>, message: String) -> ParseError {
+        let offset = offset.min(input.len());
+        let before = String::from_utf8_lossy(&input[..offset]);
+        let line = before.matches('\n').count() + 1;
+        let column = before.rsplit('\n').next().map_or(0, |line| line.chars().count()) + 1;
+
+        ParseError {
+            line,
+            column,
+            offset,
+            construct,
+            expected,
+            message,
+            limit: None,
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}, col {}: {}", self.line, self.column, self.message)
+    }
+}
+
+impl Error for ParseError {}
@@ -0,0 +1,126 @@
+use arg::ArgValue;
+use error::ExecutionError;
+use std::collections::HashMap;
+use std::fmt;
+
+/// A user defined function, called with its resolved arguments
+pub type Function = Box<dyn FnMut(&[ArgValue]) -> Result<ArgValue, String>>;
+
+/// The type of value a function argument accepts
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum ArgType {
+    Any,
+    Array,
+    Boolean,
+    /// Whole numbers and floats
+    Number,
+    Object,
+    Text,
+}
+
+impl ArgType {
+    /// Check if a resolved value is of this type
+    pub fn matches(&self, value: &ArgValue) -> bool {
+        match *self {
+            ArgType::Any => true,
+            ArgType::Array => matches!(*value, ArgValue::Array(_)),
+            ArgType::Boolean => matches!(*value, ArgValue::Boolean(_)),
+            ArgType::Number => matches!(*value, ArgValue::Number(_) | ArgValue::Float(_)),
+            ArgType::Object => matches!(*value, ArgValue::Object(_)),
+            ArgType::Text => matches!(*value, ArgValue::Text(_)),
+        }
+    }
+}
+
+impl fmt::Display for ArgType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ArgType::Any     => write!(f, "any value"),
+            ArgType::Array   => write!(f, "an array"),
+            ArgType::Boolean => write!(f, "a boolean"),
+            ArgType::Number  => write!(f, "a number"),
+            ArgType::Object  => write!(f, "an object"),
+            ArgType::Text    => write!(f, "text"),
+        }
+    }
+}
+
+/// The arguments a function accepts
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Signature {
+    /// Types of the arguments every call must have
+    pub args: Vec<ArgType>,
+    /// Type of any extra arguments, calls can not have extra arguments without one
+    pub rest: Option<ArgType>,
+}
+
+impl Signature {
+    /// A function taking exactly these arguments (e.g. damage{1|@me.str})
+    pub fn new(args: Vec<ArgType>) -> Signature {
+        Signature { args, rest: None }
+    }
+
+    /// A function taking these arguments followed by any number of `rest`
+    pub fn variadic(args: Vec<ArgType>, rest: ArgType) -> Signature {
+        Signature { args, rest: Some(rest) }
+    }
+
+    /// Check a call to the function `name` has the right arguments
+    pub fn check(&self, name: &str, args: &[ArgValue]) -> Result<(), ExecutionError> {
+        if args.len() < self.args.len() || (self.rest.is_none() && args.len() > self.args.len()) {
+            return Err(ExecutionError::ArgumentCount(name.to_string(), self.args.len(), args.len()));
+        }
+        for (i, arg) in args.iter().enumerate() {
+            let expected = match self.args.get(i) {
+                Some(expected) => expected,
+                None => self.rest.as_ref().unwrap_or(&ArgType::Any),
+            };
+            if !expected.matches(arg) {
+                return Err(ExecutionError::ArgumentType(name.to_string(), i + 1, expected.clone()));
+            }
+        }
+        Ok(())
+    }
+}
+
+struct Registered {
+    signature: Signature,
+    function: Function,
+}
+
+/// Functions macros can call by name (e.g. damage{1|@me.str})
+#[derive(Default)]
+pub struct FunctionRegistry {
+    functions: HashMap<String, Registered>,
+}
+
+impl FunctionRegistry {
+    pub fn new() -> FunctionRegistry {
+        FunctionRegistry::default()
+    }
+
+    /// Add or replace a function
+    pub fn register(&mut self, name: &str, signature: Signature, function: Function) {
+        self.functions.insert(name.to_string(), Registered { signature, function });
+    }
+
+    /// Remove a function, returning true if it was registered
+    pub fn remove(&mut self, name: &str) -> bool {
+        self.functions.remove(name).is_some()
+    }
+
+    /// The arguments a function accepts
+    pub fn signature(&self, name: &str) -> Option<&Signature> {
+        self.functions.get(name).map(|registered| &registered.signature)
+    }
+
+    /// Check the arguments and call a function
+    pub fn call(&mut self, name: &str, args: &[ArgValue]) -> Result<ArgValue, ExecutionError> {
+        let registered = match self.functions.get_mut(name) {
+            Some(registered) => registered,
+            None => return Err(ExecutionError::FunctionNotFound(name.to_string())),
+        };
+        registered.signature.check(name, args)?;
+        (registered.function)(args).map_err(ExecutionError::Callback)
+    }
+}
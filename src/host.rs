@@ -0,0 +1,139 @@
+use arg::ArgValue;
+use error::ExecutionError;
+use interpreter::to_display;
+use std::error::Error;
+use std::fmt;
+
+/// An option displayed to the user by !prompt and !case
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PromptOption {
+    pub key: String,
+    pub value: String,
+}
+
+/// Errors a host can return from an interactive command
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum HostError {
+    /// The user dismissed the command without answering
+    Cancelled,
+    /// The user will answer later, execution should be suspended
+    Deferred,
+    /// The host could not complete the command
+    Failed(String),
+    /// The host does not provide the command
+    Unsupported,
+}
+
+impl fmt::Display for HostError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            HostError::Cancelled           => write!(f, "Cancelled by the user"),
+            HostError::Deferred            => write!(f, "Waiting on the user"),
+            HostError::Failed(ref message) => write!(f, "{}", message),
+            HostError::Unsupported         => write!(f, "Not supported by the host"),
+        }
+    }
+}
+
+impl Error for HostError {}
+
+impl HostError {
+    /// Convert to an execution error for the command that made `request`
+    pub fn into_execution_error(self, request: Request) -> ExecutionError {
+        match self {
+            HostError::Deferred => ExecutionError::Suspended(Box::new(request)),
+            HostError::Unsupported => ExecutionError::MissingCallback(request.name().to_string()),
+            error => ExecutionError::Callback(error.to_string()),
+        }
+    }
+}
+
+/// What an interactive command is waiting on
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Request {
+    Input { message: String },
+    Prompt { message: String, options: Vec<PromptOption> },
+    Case { input: ArgValue, options: Vec<PromptOption> },
+    Target { message: String },
+}
+
+impl Request {
+    /// The command making the request (e.g. "input")
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Request::Input { .. } => "input",
+            Request::Prompt { .. } => "prompt",
+            Request::Case { .. } => "case",
+            Request::Target { .. } => "target",
+        }
+    }
+}
+
+/// The answer to a request, used to resume a suspended execution
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Answer {
+    /// The value entered for !input
+    Input(ArgValue),
+    /// The index of the option picked for !prompt and !case
+    Option(usize),
+    /// The name of the token selected for !target
+    Target(String),
+}
+
+/// The application running macros, provides the UI for interactive commands
+///
+/// Every method is unsupported by default, so hosts only implement the commands they
+/// can display.
+pub trait Host {
+    /// Ask for a value (!input 'message')
+    fn input(&mut self, _message: &str) -> Result<ArgValue, HostError> {
+        Err(HostError::Unsupported)
+    }
+
+    /// Ask the user to pick an option (!prompt 'message' [options]), returns its index
+    fn prompt(&mut self, _message: &str, _options: &[PromptOption]) -> Result<usize, HostError> {
+        Err(HostError::Unsupported)
+    }
+
+    /// Pick the option of !case matching its input, returns its index
+    ///
+    /// When no key matches the input the user is prompted instead.
+    fn case(&mut self, input: &ArgValue, options: &[PromptOption]) -> Result<usize, HostError> {
+        let input = to_display(input);
+        match options.iter().position(|option| option.key == input) {
+            Some(index) => Ok(index),
+            None => self.prompt("", options),
+        }
+    }
+
+    /// Ask the user to select a token (!target 'message'), returns its name
+    fn target(&mut self, _message: &str) -> Result<String, HostError> {
+        Err(HostError::Unsupported)
+    }
+}
+
+/// A host without any interactive commands (default)
+#[derive(Clone, Debug, Default)]
+pub struct NoHost;
+
+impl Host for NoHost {}
+
+/// A host that answers every interactive command later
+///
+/// Used with `Interpreter::start` to suspend instead of waiting on the user.
+#[derive(Clone, Debug, Default)]
+pub struct DeferredHost;
+
+impl Host for DeferredHost {
+    fn input(&mut self, _message: &str) -> Result<ArgValue, HostError> {
+        Err(HostError::Deferred)
+    }
+
+    fn prompt(&mut self, _message: &str, _options: &[PromptOption]) -> Result<usize, HostError> {
+        Err(HostError::Deferred)
+    }
+
+    fn target(&mut self, _message: &str) -> Result<String, HostError> {
+        Err(HostError::Deferred)
+    }
+}
@@ -0,0 +1,1083 @@
+use arg::*;
+use dice::Dice;
+use error::ExecutionError;
+use function::FunctionRegistry;
+use host::{Answer, Host, NoHost, PromptOption, Request};
+use limits::{ExecutionLimits, Limit};
+use macros::MacroRegistry;
+use output::*;
+use nom::types::CompleteByteSlice;
+use parser::{Program, parse_roll_p};
+use random::{OsRandom, RandomSource};
+use step::*;
+use token::{MemoryTokenStore, TokenStore};
+use std::cell::Cell;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+/// The result of starting or resuming a program
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Execution {
+    /// Every step was executed
+    Complete(ExecutionOutput),
+    /// An interactive command is waiting on the user
+    Suspended(Box<Suspended>),
+}
+
+/// A program paused at an interactive command
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Suspended {
+    /// Output of the steps executed before pausing
+    pub output: ExecutionOutput,
+    /// What the command is waiting on
+    pub request: Request,
+    /// Everything needed to resume, can be stored until the user answers
+    pub continuation: Continuation,
+}
+
+/// The state of a paused program
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Continuation {
+    pub program: Program,
+    /// Where the step waiting on the user is, from the outermost step in, that step is
+    /// executed again when resuming
+    pub path: Vec<Frame>,
+    /// Saved results ($1, $2, ...)
+    pub results: Vec<ArgValue>,
+    /// Named variables ($foo)
+    pub variables: HashMap<String, ArgValue>,
+    /// The selected target (@target)
+    pub target: Option<String>,
+    pub run_as: Option<String>,
+    pub test: bool,
+    /// Token macros the paused step is running in, outermost first
+    pub calls: Vec<String>,
+    /// Steps executed before pausing, so resuming does not reset the step limit
+    pub steps_run: usize,
+}
+
+/// A step a paused program was part way through, used to pick up where it left off
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Frame {
+    /// Index of a step in a list of steps
+    Step(usize),
+    /// Steps picked by a conditional, 0 is the success steps, then each else-if, then
+    /// the failure steps
+    Branch { arg: usize, branch: usize },
+    /// A loop part way through its items
+    Loop { arg: usize, items: Vec<ArgValue>, iteration: usize },
+    /// A token macro (e.g. @npc->attack)
+    Macro { arg: usize },
+    /// Any other argument of a block, the arguments before it are not run again (e.g.
+    /// $name = !input 'Name?')
+    Arg { arg: usize },
+}
+
+/// Executes programs generated by the parser
+pub struct Interpreter {
+    /// User defined functions (e.g. damage{1|@me.strength})
+    pub functions: FunctionRegistry,
+
+    /// The application providing !input, !prompt, !case and !target
+    pub host: Box<dyn Host>,
+
+    /// Limits that keep a macro from stalling the table
+    pub limits: ExecutionLimits,
+
+    /// Macros tokens can run with @token->macro
+    pub macros: MacroRegistry,
+
+    /// Randomness used to roll dice, seeded by the operating system by default
+    pub random: Box<dyn RandomSource>,
+
+    /// Tokens available to macros, kept in memory by default
+    pub tokens: Box<dyn TokenStore>,
+
+    // Saved results ($1, $2, ...)
+    results: Vec<ArgValue>,
+    // Named variables ($foo)
+    variables: HashMap<String, ArgValue>,
+    // The selected target (@target)
+    selected: Option<String>,
+    // Inline macros should be run as the parent token
+    run_as: Option<String>,
+    // Test Mode (hide output from everyone)
+    test: bool,
+    // Set once !exit is encountered
+    exited: bool,
+    // Frames left to enter when resuming, innermost first
+    resuming: Vec<Frame>,
+    // Frames left while suspending, innermost first
+    suspended: Vec<Frame>,
+    // Steps executed so far, including nested steps
+    steps_run: usize,
+    // How deeply the current step is nested
+    depth: usize,
+    // Token macros currently running (e.g. @npc->attack)
+    calls: Vec<String>,
+    // Answer to the next interactive command when resuming
+    answer: Option<Answer>,
+    // Where the last execution failed
+    failed_at: Cell<Option<Span>>,
+    // Tokens standing in for others while looping (e.g. @player in !for @player in [@alice, @bob])
+    aliases: HashMap<String, String>,
+}
+
+impl Default for Interpreter {
+    fn default() -> Interpreter {
+        Interpreter::new()
+    }
+}
+
+impl Interpreter {
+    pub fn new() -> Interpreter {
+        Interpreter {
+            functions: FunctionRegistry::new(),
+            host: Box::new(NoHost),
+            limits: ExecutionLimits::default(),
+            macros: MacroRegistry::new(),
+            random: Box::new(OsRandom::new()),
+            tokens: Box::new(MemoryTokenStore::new()),
+            results: Vec::new(),
+            variables: HashMap::new(),
+            selected: None,
+            run_as: None,
+            test: false,
+            exited: false,
+            resuming: Vec::new(),
+            suspended: Vec::new(),
+            steps_run: 0,
+            depth: 0,
+            calls: Vec::new(),
+            answer: None,
+            failed_at: Cell::new(None),
+            aliases: HashMap::new(),
+        }
+    }
+
+    /// Execute every step of a program and return the output
+    ///
+    /// Interactive commands wait on the host, use `start` to suspend instead.
+    pub fn execute(&mut self, program: &Program) -> Result<ExecutionOutput, ExecutionError> {
+        self.failed_at.set(None);
+        self.limits.check_program(program)?;
+        let mut output = ExecutionOutput::default();
+        let result = self.execute_list(&program.steps, &mut output);
+        self.cleanup();
+        result.map(|_| output)
+    }
+
+    /// Execute a program, suspending when the host defers an interactive command
+    pub fn start(&mut self, program: &Program) -> Result<Execution, ExecutionError> {
+        self.failed_at.set(None);
+        self.limits.check_program(program)?;
+        self.run(program)
+    }
+
+    /// Continue a suspended program with the answer to its request
+    pub fn resume(&mut self, continuation: Continuation, answer: Answer) -> Result<Execution, ExecutionError> {
+        self.results = continuation.results;
+        self.variables = continuation.variables;
+        self.selected = continuation.target;
+        self.run_as = continuation.run_as;
+        self.test = continuation.test;
+        self.answer = Some(answer);
+        self.resuming = continuation.path.into_iter().rev().collect();
+        self.calls = continuation.calls;
+        self.steps_run = continuation.steps_run;
+        self.run(&continuation.program)
+    }
+
+    fn run(&mut self, program: &Program) -> Result<Execution, ExecutionError> {
+        self.failed_at.set(None);
+        let mut output = ExecutionOutput::default();
+        let result = match self.execute_list(&program.steps, &mut output) {
+            Ok(_) => Ok(Execution::Complete(output)),
+            Err(ExecutionError::Suspended(request)) => Ok(Execution::Suspended(Box::new(Suspended {
+                output,
+                request: *request,
+                continuation: Continuation {
+                    program: program.clone(),
+                    path: self.suspended.drain(..).rev().collect(),
+                    results: self.results.clone(),
+                    variables: self.variables.clone(),
+                    target: self.selected.clone(),
+                    run_as: self.run_as.clone(),
+                    test: self.test,
+                    calls: self.calls.clone(),
+                    // the step waiting on the user is counted again when it runs
+                    steps_run: self.steps_run - 1,
+                },
+            }))),
+            Err(error) => Err(error),
+        };
+        self.cleanup();
+        result
+    }
+
+    /// Where in the macro the last execution failed, if it was parsed with spans
+    ///
+    /// Points at the token that could not be read when there is one, otherwise at the
+    /// step that failed. Errors inside token macros point at the @token->macro running them.
+    pub fn error_span(&self) -> Option<Span> {
+        self.failed_at.get()
+    }
+
+    fn cleanup(&mut self) {
+        self.results.clear();
+        self.variables.clear();
+        self.selected = None;
+        self.run_as = None;
+        self.exited = false;
+        self.resuming.clear();
+        self.suspended.clear();
+        self.steps_run = 0;
+        self.depth = 0;
+        self.calls.clear();
+        self.answer = None;
+        self.aliases.clear();
+    }
+
+    /// Execute steps in order, returning the last value produced
+    ///
+    /// When resuming, starts from the step that was waiting on the user.
+    fn execute_list(&mut self, steps: &[Step], output: &mut ExecutionOutput) -> Result<Option<ArgValue>, ExecutionError> {
+        let start = match self.resuming.last() {
+            Some(&Frame::Step(index)) => {
+                self.resuming.pop();
+                index
+            },
+            _ => 0,
+        };
+
+        let mut result = None;
+        for (index, step) in steps.iter().enumerate().skip(start) {
+            // If we encounter an exit, stop processing
+            if self.exited {
+                break;
+            }
+            match self.execute_step(step, output) {
+                Ok(Some(value)) => result = Some(value),
+                Ok(None) => {},
+                Err(error) => return Err(self.suspended_in(error, Frame::Step(index))),
+            }
+        }
+        Ok(result)
+    }
+
+    /// Remember the frame a suspended step was in, so resuming can find it again
+    fn suspended_in(&mut self, error: ExecutionError, frame: Frame) -> ExecutionError {
+        if let ExecutionError::Suspended(_) = error {
+            self.suspended.push(frame);
+        }
+        error
+    }
+
+    /// Index of the argument to pick up from when resuming, or 0
+    fn resume_arg(&self) -> usize {
+        match self.resuming.last() {
+            Some(&Frame::Branch { arg, .. })
+            | Some(&Frame::Loop { arg, .. })
+            | Some(&Frame::Macro { arg })
+            | Some(&Frame::Arg { arg }) => arg,
+            _ => 0,
+        }
+    }
+
+    /// Execute a single step, returning the value it produced (if any)
+    fn execute_step(&mut self, step: &Step, output: &mut ExecutionOutput) -> Result<Option<ArgValue>, ExecutionError> {
+        // steps entered again on the way back to a suspended step were already counted
+        if self.resuming.is_empty() {
+            self.steps_run += 1;
+            self.limits.check(Limit::Steps, self.steps_run)?;
+        }
+
+        self.depth += 1;
+        let result = match self.limits.check(Limit::Depth, self.depth) {
+            Ok(()) => self.execute_op(step, output),
+            Err(error) => Err(error),
+        };
+        self.depth -= 1;
+        if let Err(ref error) = result {
+            self.fail_at(step.span, error);
+        }
+        result
+    }
+
+    fn execute_op(&mut self, step: &Step, output: &mut ExecutionOutput) -> Result<Option<ArgValue>, ExecutionError> {
+        match step.op {
+            MacroOp::Case | MacroOp::Prompt => self.execute_prompt(step),
+            MacroOp::Exit => {
+                self.exited = true;
+                Ok(None)
+            },
+            MacroOp::Function(ref name) => self.execute_function(name, step),
+            MacroOp::Input => self.execute_input(step),
+            MacroOp::Lambda => self.execute_lambda(step, output),
+            MacroOp::Loop => self.execute_loop(step, output),
+            MacroOp::Name(_) | MacroOp::Primitive => Ok(None),
+            MacroOp::Roll | MacroOp::RollHidden | MacroOp::RollWhisper => self.execute_roll(step, output),
+            MacroOp::Say | MacroOp::Whisper => self.execute_say(step, output),
+            MacroOp::Target => self.execute_target(step),
+            MacroOp::Template => self.execute_template(step, output),
+            MacroOp::TestMode => {
+                self.test = match step.args.first() {
+                    Some(&Arg::TestMode(test)) => test,
+                    _ => !self.test,
+                };
+                Ok(None)
+            },
+        }
+    }
+
+    fn execute_function(&mut self, name: &str, step: &Step) -> Result<Option<ArgValue>, ExecutionError> {
+        let mut args = vec![];
+        for arg in &step.args {
+            if let Arg::Function(ref value) = *arg {
+                args.push(self.resolve(value)?);
+            }
+        }
+
+        let result = self.functions.call(name, &args)?;
+        self.results.push(result.clone());
+        Ok(Some(result))
+    }
+
+    fn execute_input(&mut self, step: &Step) -> Result<Option<ArgValue>, ExecutionError> {
+        let mut message = String::new();
+        for arg in &step.args {
+            if let Arg::Input(ref text) = *arg {
+                message = self.interpolate(text)?;
+            }
+        }
+
+        let result = match self.answer.take() {
+            Some(Answer::Input(value)) => value,
+            Some(answer) => return Err(unexpected(answer, "input")),
+            None => self.host.input(&message).map_err(|e| e.into_execution_error(Request::Input { message }))?,
+        };
+        self.results.push(result.clone());
+        Ok(Some(result))
+    }
+
+    fn execute_loop(&mut self, step: &Step, output: &mut ExecutionOutput) -> Result<Option<ArgValue>, ExecutionError> {
+        let mut result = None;
+        for (index, arg) in step.args.iter().enumerate().skip(self.resume_arg()) {
+            if let Arg::Loop(ref each) = *arg {
+                let resumed = match self.resuming.last() {
+                    Some(&Frame::Loop { .. }) => self.resuming.pop(),
+                    _ => None,
+                };
+                let (items, start, resumed) = match resumed {
+                    Some(Frame::Loop { items, iteration, .. }) => (items, iteration, true),
+                    _ => (self.loop_items(&each.over, output)?, 0, false),
+                };
+
+                for (iteration, item) in items.iter().enumerate().skip(start) {
+                    let alias = match each.variable {
+                        ArgValue::Token(ref token) => Some(self.alias(token, item)?),
+                        // the variable is already set when picking up part way through
+                        ref variable if !resumed || iteration != start => {
+                            self.assign(variable, item.clone())?;
+                            None
+                        },
+                        _ => None,
+                    };
+                    let body = self.execute_list(&each.steps, output);
+                    if let Some((name, previous)) = alias {
+                        match previous {
+                            Some(previous) => self.aliases.insert(name, previous),
+                            None => self.aliases.remove(&name),
+                        };
+                    }
+                    result = body.map_err(|error| {
+                        self.suspended_in(error, Frame::Loop { arg: index, items: items.clone(), iteration })
+                    })?;
+                    if self.exited {
+                        return Ok(result);
+                    }
+                }
+            }
+        }
+        Ok(result)
+    }
+
+    /// Let a token stand in for the token of a loop item, returning what it stood for before
+    fn alias(&mut self, token: &TokenArg, item: &ArgValue) -> Result<(String, Option<String>), ExecutionError> {
+        let name = match *item {
+            ArgValue::Text(ref name) => name.trim_start_matches('@').to_string(),
+            ref item => return Err(ExecutionError::InvalidValue(format!("{} is not a token", to_display(item)))),
+        };
+        Ok((token.name.clone(), self.aliases.insert(token.name.clone(), name)))
+    }
+
+    /// Find the items a loop runs over, checking there are not too many
+    fn loop_items(&mut self, over: &LoopOver, output: &mut ExecutionOutput) -> Result<Vec<ArgValue>, ExecutionError> {
+        match *over {
+            LoopOver::Range(ref first, ref last) => {
+                let (first, last) = match (self.value(first, output)?, self.value(last, output)?) {
+                    (ArgValue::Number(first), ArgValue::Number(last)) => (first, last),
+                    (first, last) => {
+                        return Err(ExecutionError::InvalidValue(format!("Cannot loop from {} to {}", to_display(&first), to_display(&last))));
+                    },
+                };
+                let count = (i64::from(last) - i64::from(first) + 1).max(0) as usize;
+                self.limits.check(Limit::Iterations, count)?;
+                Ok((first..=last).map(ArgValue::Number).collect())
+            },
+            LoopOver::Items(ref items) => match self.value(items, output)? {
+                ArgValue::Array(items) => {
+                    self.limits.check(Limit::Iterations, items.len())?;
+                    Ok(items)
+                },
+                items => Err(ExecutionError::InvalidValue(format!("Cannot loop over {}", to_display(&items)))),
+            },
+        }
+    }
+
+    fn execute_lambda(&mut self, step: &Step, output: &mut ExecutionOutput) -> Result<Option<ArgValue>, ExecutionError> {
+        let mut result = None;
+        for (index, arg) in step.args.iter().enumerate().skip(self.resume_arg()) {
+            if let Some(&Frame::Arg { .. }) = self.resuming.last() {
+                self.resuming.pop();
+            }
+            match *arg {
+                Arg::Conditional(ref conditional) => {
+                    let branch = match self.resuming.last() {
+                        Some(&Frame::Branch { branch, .. }) => {
+                            self.resuming.pop();
+                            branch
+                        },
+                        _ => self.branch(conditional, output)
+                            .map_err(|error| self.suspended_in(error, Frame::Arg { arg: index }))?,
+                    };
+                    result = self.execute_list(branch_steps(conditional, branch), output)
+                        .map_err(|error| self.suspended_in(error, Frame::Branch { arg: index, branch }))?;
+                },
+                Arg::Assign(ref assign) => {
+                    let value = self.evaluate(&assign.right, output)
+                        .map_err(|error| self.suspended_in(error, Frame::Arg { arg: index }))?;
+                    self.assign(&assign.left, value.clone())?;
+                    result = Some(value);
+                },
+                Arg::Concat(ref assign) => {
+                    let value = self.evaluate(&assign.right, output)
+                        .map_err(|error| self.suspended_in(error, Frame::Arg { arg: index }))?;
+                    // concatenating to a missing value is fine, so it is not a failure
+                    let failed_at = self.failed_at.get();
+                    let current = self.resolve(&assign.left).ok();
+                    self.failed_at.set(failed_at);
+                    let value = concat(current, value)?;
+                    self.assign(&assign.left, value.clone())?;
+                    result = Some(value);
+                },
+                Arg::Deduct(ref assign) => {
+                    let value = self.evaluate(&assign.right, output)
+                        .map_err(|error| self.suspended_in(error, Frame::Arg { arg: index }))?;
+                    let current = self.resolve(&assign.left)?;
+                    let value = deduct(current, value)?;
+                    self.assign(&assign.left, value.clone())?;
+                    result = Some(value);
+                },
+                Arg::Token(ref token) if token.macro_name.is_some() => {
+                    let resumed = match self.resuming.last() {
+                        Some(&Frame::Macro { .. }) => self.resuming.pop().is_some(),
+                        _ => false,
+                    };
+                    result = self.execute_macro(token, resumed, output).map_err(|error| {
+                        self.suspended_in(error, Frame::Macro { arg: index })
+                    }).inspect_err(|error| {
+                        // spans inside the macro point into its own source
+                        self.failed_at.set(None);
+                        self.fail_at(token.span, error);
+                    })?;
+                },
+                _ => {},
+            }
+        }
+        Ok(result)
+    }
+
+    /// Run a token's macro as the token, sharing variables and results with the caller
+    ///
+    /// A `resumed` macro is already in the calls restored from the continuation.
+    fn execute_macro(&mut self, token: &TokenArg, resumed: bool, output: &mut ExecutionOutput) -> Result<Option<ArgValue>, ExecutionError> {
+        let name = self.token_name(token)?;
+        let macro_name = token.macro_name.clone().unwrap_or_default();
+        let call = format!("@{}->{}", name, macro_name);
+
+        let program = match self.macros.get(&name, &macro_name) {
+            Some(program) => program.clone(),
+            None => return Err(ExecutionError::MacroNotFound(call)),
+        };
+        if !resumed {
+            if self.calls.contains(&call) {
+                return Err(ExecutionError::MacroCycle(call));
+            }
+            self.limits.check(Limit::Calls, self.calls.len() + 1)?;
+            self.limits.check_program(&program)?;
+            self.calls.push(call);
+        }
+
+        let run_as = self.run_as.replace(name);
+        let result = self.execute_list(&program.steps, output);
+
+        // a suspended macro stays in the calls saved with the continuation
+        if !matches!(result, Err(ExecutionError::Suspended(_))) {
+            self.calls.pop();
+        }
+        // !exit only stops the macro
+        self.run_as = run_as;
+        self.exited = false;
+        result
+    }
+
+    fn execute_prompt(&mut self, step: &Step) -> Result<Option<ArgValue>, ExecutionError> {
+        let mut message = String::new();
+        let mut options: &[SwitchOption] = &[];
+        let mut default = None;
+        for arg in &step.args {
+            match *arg {
+                Arg::Prompt(ref prompt) => {
+                    message = self.interpolate(&prompt.message)?;
+                    options = &prompt.options;
+                },
+                Arg::Case(ref case) => {
+                    default = Some(self.resolve(&case.input)?);
+                    options = &case.options;
+                },
+                _ => {},
+            }
+        }
+
+        let mut display = vec![];
+        for (i, option) in options.iter().enumerate() {
+            let value = match option.value {
+                ArgValue::Token(ref token) => token.to_string(),
+                ref value => to_display(&self.resolve(value)?),
+            };
+            display.push(PromptOption {
+                key: option.key.clone().unwrap_or_else(|| i.to_string()),
+                value,
+            });
+        }
+
+        if display.is_empty() {
+            return Ok(None);
+        }
+        let index = match (self.answer.take(), default) {
+            (Some(Answer::Option(index)), _) => index,
+            (Some(answer), Some(_)) => return Err(unexpected(answer, "case")),
+            (Some(answer), None) => return Err(unexpected(answer, "prompt")),
+            (None, Some(input)) => self.host.case(&input, &display).map_err(|e| {
+                e.into_execution_error(Request::Case { input: input.clone(), options: display.clone() })
+            })?,
+            (None, None) => self.host.prompt(&message, &display).map_err(|e| {
+                e.into_execution_error(Request::Prompt { message: message.clone(), options: display.clone() })
+            })?,
+        };
+
+        let option = options.get(index).ok_or_else(|| {
+            ExecutionError::Callback(format!("Option {} does not exist", index))
+        })?;
+        let result = match (&step.op, &option.key) {
+            (&MacroOp::Prompt, Some(key)) => ArgValue::Text(key.clone()),
+            _ => self.resolve(&option.value)?,
+        };
+        self.results.push(result.clone());
+        Ok(Some(result))
+    }
+
+    fn execute_roll(&mut self, step: &Step, output: &mut ExecutionOutput) -> Result<Option<ArgValue>, ExecutionError> {
+        let mut args = vec![];
+        let mut token = None;
+        for arg in &step.args {
+            match *arg {
+                Arg::Roll(_) => args.push(arg.clone()),
+                Arg::Token(ref t) => token = Some(t),
+                _ => {},
+            }
+        }
+
+        // if the only argument is a token attribute, it holds the roll command
+        let mut from = None;
+        let mut to = None;
+        if let Some(token) = token {
+            if args.is_empty() {
+                let command = to_display(&self.resolve(&ArgValue::Token(token.clone()))?);
+                args = match parse_roll_p(CompleteByteSlice(command.as_bytes())) {
+                    Ok((rest, ref args)) if rest.is_empty() && !args.is_empty() => args.clone(),
+                    _ => return Err(ExecutionError::InvalidRoll(format!("\"{}\" is not a valid roll", command))),
+                };
+            } else if step.op == MacroOp::Roll {
+                from = Some(token.name.clone());
+            } else {
+                to = Some(token.name.clone());
+            }
+        }
+
+        let mut dice = Dice::from_args(&args, |value| self.resolve(value))?;
+        dice.limit_rerolls(self.limits.max_rerolls);
+        self.limits.check_dice(&dice)?;
+        let result = dice.roll(&mut *self.random)?;
+        let value = result.value;
+
+        let roll = Roll {
+            result,
+            from,
+            to,
+            token: self.run_as.clone(),
+            is_test: self.test,
+        };
+        self.check_output(output)?;
+        output.rolls.push(roll.clone());
+        if step.op != MacroOp::RollHidden {
+            output.raw.push(OutputEntry::Roll(roll));
+        }
+
+        if step.result == StepResult::Save {
+            self.results.push(ArgValue::Number(value));
+        }
+        Ok(Some(ArgValue::Number(value)))
+    }
+
+    fn execute_say(&mut self, step: &Step, output: &mut ExecutionOutput) -> Result<Option<ArgValue>, ExecutionError> {
+        let mut message = Message {
+            from: None,
+            to: None,
+            message: String::new(),
+            is_test: self.test,
+        };
+
+        for arg in &step.args {
+            if let Arg::Say(ref say) = *arg {
+                match *say {
+                    SayArg::Message(ref text) => message.message = self.interpolate(text)?,
+                    SayArg::From(ref token) => message.from = Some(token.name.clone()),
+                    SayArg::To(ref token) => message.to = Some(token.name.clone()),
+                }
+            }
+        }
+
+        if message.from.is_none() {
+            message.from = self.run_as.clone();
+        }
+
+        self.check_output(output)?;
+        output.messages.push(message.clone());
+        output.raw.push(OutputEntry::Message(message));
+        Ok(None)
+    }
+
+    fn execute_target(&mut self, step: &Step) -> Result<Option<ArgValue>, ExecutionError> {
+        let mut message = "Choose a target".to_string();
+        for arg in &step.args {
+            match *arg {
+                Arg::Target(TargetArg::Message(ref text)) |
+                Arg::Say(SayArg::Message(ref text)) => message = self.interpolate(text)?,
+                _ => {},
+            }
+        }
+
+        let name = match self.answer.take() {
+            Some(Answer::Target(name)) => name,
+            Some(answer) => return Err(unexpected(answer, "target")),
+            None => self.host.target(&message).map_err(|e| e.into_execution_error(Request::Target { message }))?,
+        };
+        self.selected = Some(name.clone());
+        Ok(Some(ArgValue::Text(name)))
+    }
+
+    fn execute_template(&mut self, step: &Step, output: &mut ExecutionOutput) -> Result<Option<ArgValue>, ExecutionError> {
+        let mut template = Template {
+            name: "default".to_string(),
+            attributes: HashMap::new(),
+        };
+
+        for arg in &step.args {
+            match *arg {
+                Arg::Template(TemplateArg::Name(ref name)) => template.name = name.clone(),
+                Arg::Template(TemplateArg::Attributes(ArgValue::Object(ref attributes))) => {
+                    for (key, value) in attributes {
+                        template.attributes.insert(key.clone(), self.resolve(value)?);
+                    }
+                },
+                _ => {},
+            }
+        }
+
+        self.check_output(output)?;
+        output.templates.push(template.clone());
+        output.raw.push(OutputEntry::Template(template));
+        Ok(None)
+    }
+
+    /// Get the concrete value of an argument, running any steps it holds (e.g. functions)
+    fn value(&mut self, value: &ArgValue, output: &mut ExecutionOutput) -> Result<ArgValue, ExecutionError> {
+        match *value {
+            ArgValue::Step(ref step) => {
+                Ok(self.execute_step(step, output)?.unwrap_or_else(|| ArgValue::Text(String::new())))
+            },
+            ArgValue::Expr(ref expr) => calculate(expr, &mut |operand| self.value(operand, output)),
+            ref value => self.resolve(value),
+        }
+    }
+
+    /// Pick the branch of the first condition that holds, or the failure steps if none do
+    /// (see `branch_steps`)
+    fn branch(&mut self, conditional: &Conditional, output: &mut ExecutionOutput) -> Result<usize, ExecutionError> {
+        if self.condition(&conditional.condition, output)? {
+            return Ok(0);
+        }
+        for (index, else_if) in conditional.else_if.iter().enumerate() {
+            if self.condition(&else_if.condition, output)? {
+                return Ok(index + 1);
+            }
+        }
+        Ok(conditional.else_if.len() + 1)
+    }
+
+    /// Check a condition, skipping the right side of && and || once the left decides it
+    fn condition(&mut self, condition: &Condition, output: &mut ExecutionOutput) -> Result<bool, ExecutionError> {
+        match *condition {
+            Condition::Compare(ref comparison) => {
+                let left = self.value(&comparison.left, output)?;
+                let right = self.value(&comparison.right, output)?;
+                Ok(compare(&left, &comparison.comparison, &right))
+            },
+            Condition::And(ref left, ref right) => Ok(self.condition(left, output)? && self.condition(right, output)?),
+            Condition::Or(ref left, ref right) => Ok(self.condition(left, output)? || self.condition(right, output)?),
+            Condition::Not(ref condition) => Ok(!self.condition(condition, output)?),
+        }
+    }
+
+    /// Evaluate the right side of an assignment, joining text with spaces (arithmetic is an `Expr`)
+    fn evaluate(&mut self, values: &[ArgValue], output: &mut ExecutionOutput) -> Result<ArgValue, ExecutionError> {
+        let mut result = ArgValue::Number(0);
+        for value in values {
+            result = match (result, self.value(value, output)?) {
+                (ArgValue::Number(0), ArgValue::Text(text)) => ArgValue::Text(text),
+                (current, ArgValue::Text(text)) => ArgValue::Text(format!("{} {}", to_display(&current), text)),
+                (_, value) => value,
+            };
+        }
+        Ok(result)
+    }
+
+    /// Assign a value to a variable or token attribute
+    fn assign(&mut self, left: &ArgValue, value: ArgValue) -> Result<(), ExecutionError> {
+        if let ArgValue::Text(ref text) = value {
+            self.limits.check_string(text)?;
+        }
+        match *left {
+            ArgValue::Variable(ref name) => {
+                self.variables.insert(name.clone(), value);
+                Ok(())
+            },
+            ArgValue::Token(ref token) => {
+                let attribute = match token.attribute {
+                    Some(ref attribute) => attribute.clone(),
+                    None => return Err(ExecutionError::InvalidValue(format!("Cannot assign to token {}", token.to_string()))),
+                };
+                let name = self.token_name(token)?;
+                self.tokens.set(&name, &attribute, value)
+            },
+            _ => Err(ExecutionError::InvalidValue("Only variables and tokens can be assigned".to_string())),
+        }
+    }
+
+    /// Get the concrete value of a number, token, variable, etc
+    pub fn resolve(&self, value: &ArgValue) -> Result<ArgValue, ExecutionError> {
+        match *value {
+            ArgValue::Array(ref values) => {
+                let mut array = vec![];
+                for value in values {
+                    array.push(self.resolve(value)?);
+                }
+                Ok(ArgValue::Array(array))
+            },
+            ArgValue::Object(ref values) => {
+                let mut object = HashMap::new();
+                for (key, value) in values {
+                    object.insert(key.clone(), self.resolve(value)?);
+                }
+                Ok(ArgValue::Object(object))
+            },
+            ArgValue::Expr(ref expr) => calculate(expr, &mut |operand| self.resolve(operand)),
+            ArgValue::Index(ref value, ref key) => index(&self.resolve(value)?, &self.resolve(key)?),
+            ArgValue::Length(ref value) => length(&self.resolve(value)?),
+            ArgValue::TextInterpolated(ref text) => Ok(ArgValue::Text(self.interpolate(text)?)),
+            ArgValue::Token(ref token) => {
+                let value = self.token_name(token).and_then(|name| match token.attribute {
+                    Some(ref attribute) => self.tokens.get(&name, attribute),
+                    None => Ok(ArgValue::Text(name)),
+                });
+                if let Err(ref error) = value {
+                    self.fail_at(token.span, error);
+                }
+                value
+            },
+            ArgValue::Variable(ref name) => {
+                if let Some(value) = self.variables.get(name) {
+                    return Ok(value.clone());
+                }
+                // we sometimes parse Variable instead of VariableReserved
+                match name.parse::<i16>() {
+                    Ok(index) => self.resolve(&ArgValue::VariableReserved(index)),
+                    Err(_) => Err(ExecutionError::VariableNotSet(name.clone())),
+                }
+            },
+            ArgValue::VariableReserved(index) => {
+                // $0 is the last saved result
+                let result = if index == 0 {
+                    self.results.last()
+                } else if index > 0 {
+                    self.results.get(index as usize - 1)
+                } else {
+                    None
+                };
+                result.cloned().ok_or_else(|| ExecutionError::VariableNotSet(index.to_string()))
+            },
+            ArgValue::Step(_) => Err(ExecutionError::InvalidValue("Steps can only be used in assignments".to_string())),
+            ref value => Ok(value.clone()),
+        }
+    }
+
+    /// Build a string from interpolated text
+    fn interpolate(&self, text: &TextInterpolated) -> Result<String, ExecutionError> {
+        let mut string = String::new();
+        for part in &text.parts {
+            string.push_str(&to_display(&self.resolve(part)?));
+        }
+        self.limits.check_string(&string)?;
+        Ok(string)
+    }
+
+    /// Remember where an error happened, keeping the innermost span
+    fn fail_at(&self, span: Option<Span>, error: &ExecutionError) {
+        match *error {
+            ExecutionError::Suspended(_) => {},
+            _ if self.failed_at.get().is_some() => {},
+            _ => self.failed_at.set(span),
+        }
+    }
+
+    /// Make sure there is room for one more message, roll or template
+    fn check_output(&self, output: &ExecutionOutput) -> Result<(), ExecutionError> {
+        let count = output.messages.len() + output.rolls.len() + output.templates.len();
+        self.limits.check(Limit::Messages, count + 1)
+    }
+
+    /// Find the name of a token, replacing @target with the selected token and loop tokens with
+    /// the token they stand in for
+    fn token_name(&self, token: &TokenArg) -> Result<String, ExecutionError> {
+        if let Some(name) = self.aliases.get(&token.name) {
+            Ok(name.clone())
+        } else if token.name == "target" {
+            self.selected.clone().ok_or(ExecutionError::NoTarget)
+        } else {
+            Ok(token.name.clone())
+        }
+    }
+}
+
+/// The steps of a branch picked by `Interpreter::branch`
+fn branch_steps(conditional: &Conditional, branch: usize) -> &[Step] {
+    match branch {
+        0 => &conditional.success,
+        branch if branch <= conditional.else_if.len() => &conditional.else_if[branch - 1].success,
+        _ => &conditional.failure,
+    }
+}
+
+/// A resumed program was answered with the wrong kind of answer
+fn unexpected(answer: Answer, command: &str) -> ExecutionError {
+    ExecutionError::InvalidValue(format!("Cannot answer {} with {:?}", command, answer))
+}
+
+/// Display a resolved value as text
+pub fn to_display(value: &ArgValue) -> String {
+    match *value {
+        ArgValue::Array(ref values) => {
+            values.iter().map(to_display).collect::<Vec<String>>().join(", ")
+        },
+        ArgValue::Boolean(b) => b.to_string(),
+        ArgValue::Float(f) => f.to_string(),
+        ArgValue::Number(n) => n.to_string(),
+        ArgValue::Object(ref values) => {
+            let mut keys: Vec<&String> = values.keys().collect();
+            keys.sort();
+            keys.iter().map(|key| format!("{}: {}", key, to_display(&values[*key]))).collect::<Vec<String>>().join(", ")
+        },
+        ArgValue::Text(ref text) => text.clone(),
+        ArgValue::Token(ref token) => token.to_string(),
+        ArgValue::Variable(ref name) => format!("${}", name),
+        ArgValue::VariableReserved(index) => format!("${}", index),
+        _ => String::new(),
+    }
+}
+
+/// Get a number from a resolved value
+fn to_number(value: &ArgValue) -> Option<f64> {
+    match *value {
+        ArgValue::Boolean(b) => Some(if b { 1.0 } else { 0.0 }),
+        ArgValue::Float(f) => Some(f64::from(f)),
+        ArgValue::Number(n) => Some(f64::from(n)),
+        ArgValue::Text(ref text) => text.trim().parse::<f64>().ok(),
+        _ => None,
+    }
+}
+
+/// Take an item out of a resolved array by its position or a resolved object by its key
+pub fn index(value: &ArgValue, key: &ArgValue) -> Result<ArgValue, ExecutionError> {
+    let item = match (value, key) {
+        (ArgValue::Array(items), ArgValue::Number(i)) if *i >= 0 => items.get(*i as usize),
+        (ArgValue::Array(items), ArgValue::Text(i)) => i.parse::<usize>().ok().and_then(|i| items.get(i)),
+        (ArgValue::Object(items), key) => items.get(&to_display(key)),
+        _ => None,
+    };
+    item.cloned().ok_or_else(|| {
+        ExecutionError::InvalidValue(format!("Cannot find {} in {}", to_display(key), to_display(value)))
+    })
+}
+
+/// Count the items in a resolved array or object, or the characters in resolved text
+pub fn length(value: &ArgValue) -> Result<ArgValue, ExecutionError> {
+    let length = match *value {
+        ArgValue::Array(ref items) => items.len(),
+        ArgValue::Object(ref items) => items.len(),
+        ArgValue::Text(ref text) => text.chars().count(),
+        ref value => return Err(ExecutionError::InvalidValue(format!("Cannot find the length of {}", to_display(value)))),
+    };
+    Ok(ArgValue::Number(length as i32))
+}
+
+/// Compare two resolved values
+///
+/// Numbers compare by value, including text that reads as a number (e.g. an answer to a prompt),
+/// other text compares alphabetically and booleans, arrays and objects can only be equal or not.
+/// Values of different types are never equal (e.g. true and 1).
+fn compare(left: &ArgValue, comparison: &ComparisonArg, right: &ArgValue) -> bool {
+    let ordering = order(left, right);
+    match *comparison {
+        ComparisonArg::EqualTo => ordering == Some(Ordering::Equal),
+        ComparisonArg::NotEqualTo => ordering != Some(Ordering::Equal),
+        ComparisonArg::GreaterThan => ordering == Some(Ordering::Greater),
+        ComparisonArg::GreaterThanOrEqual => ordering == Some(Ordering::Greater) || ordering == Some(Ordering::Equal),
+        ComparisonArg::LessThan => ordering == Some(Ordering::Less),
+        ComparisonArg::LessThanOrEqual => ordering == Some(Ordering::Less) || ordering == Some(Ordering::Equal),
+    }
+}
+
+/// Order two resolved values, None when neither comes before the other and they are not equal
+fn order(left: &ArgValue, right: &ArgValue) -> Option<Ordering> {
+    let number = |value: &ArgValue| match *value {
+        ArgValue::Boolean(_) => None,
+        ref value => to_number(value),
+    };
+    if let (Some(l), Some(r)) = (number(left), number(right)) {
+        return if (l - r).abs() < f64::EPSILON { Some(Ordering::Equal) } else { l.partial_cmp(&r) };
+    }
+    match (left, right) {
+        (ArgValue::Text(l), ArgValue::Text(r)) => Some(l.cmp(r)),
+        (l, r) if l == r => Some(Ordering::Equal),
+        _ => None,
+    }
+}
+
+fn arithmetic(left: &ArgValue, op: &Primitive, right: &ArgValue) -> Result<ArgValue, ExecutionError> {
+    if let (&ArgValue::Number(l), &ArgValue::Number(r)) = (left, right) {
+        let result = match *op {
+            Primitive::Add => l.checked_add(r),
+            Primitive::Subtract => l.checked_sub(r),
+            Primitive::Multiply => l.checked_mul(r),
+            Primitive::Divide | Primitive::IntegerDivide | Primitive::Modulo if r == 0 => return Err(divide_by_zero()),
+            Primitive::Divide => match l.checked_rem(r) {
+                Some(0) => l.checked_div(r),
+                Some(_) => return Ok(ArgValue::Float(l as f32 / r as f32)),
+                None => None,
+            },
+            // round down like ability modifiers, so -3 \ 2 is -2 rather than -1
+            Primitive::IntegerDivide => l.checked_div(r).map(|q| if l % r != 0 && (l < 0) != (r < 0) { q - 1 } else { q }),
+            Primitive::Modulo => l.checked_rem(r).map(|m| if m != 0 && (m < 0) != (r < 0) { m + r } else { m }),
+        };
+        return result.map(ArgValue::Number).ok_or_else(|| {
+            ExecutionError::InvalidValue("Number is too large".to_string())
+        });
+    }
+
+    match (to_number(left), to_number(right)) {
+        (Some(l), Some(r)) => {
+            let result = match *op {
+                Primitive::Add => l + r,
+                Primitive::Subtract => l - r,
+                Primitive::Multiply => l * r,
+                Primitive::Divide | Primitive::IntegerDivide | Primitive::Modulo if r == 0.0 => return Err(divide_by_zero()),
+                Primitive::Divide => l / r,
+                Primitive::IntegerDivide => (l / r).floor(),
+                Primitive::Modulo => l - r * (l / r).floor(),
+            };
+            Ok(ArgValue::Float(result as f32))
+        },
+        _ => Err(ExecutionError::InvalidValue(format!("Cannot use {} in arithmetic", to_display(left)))),
+    }
+}
+
+fn divide_by_zero() -> ExecutionError {
+    ExecutionError::InvalidValue("Cannot divide by zero".to_string())
+}
+
+/// Work out an expression, finding the value of each operand with `value`
+pub fn calculate<F>(expr: &Expr, value: &mut F) -> Result<ArgValue, ExecutionError>
+    where F: FnMut(&ArgValue) -> Result<ArgValue, ExecutionError>
+{
+    match *expr {
+        Expr::Value(ref operand) => value(operand),
+        Expr::Negate(ref expr) => arithmetic(&ArgValue::Number(0), &Primitive::Subtract, &calculate(expr, value)?),
+        Expr::Binary(ref left, ref op, ref right) => {
+            let left = calculate(left, value)?;
+            let right = calculate(right, value)?;
+            arithmetic(&left, op, &right)
+        },
+    }
+}
+
+/// Append a value to the current value (+=)
+fn concat(current: Option<ArgValue>, value: ArgValue) -> Result<ArgValue, ExecutionError> {
+    match (current, value) {
+        (Some(ArgValue::Array(mut values)), value) => {
+            values.push(value);
+            Ok(ArgValue::Array(values))
+        },
+        (Some(ArgValue::Text(mut text)), value) => {
+            text.push_str(&to_display(&value));
+            Ok(ArgValue::Text(text))
+        },
+        (Some(ref current @ ArgValue::Number(_)), ref value @ ArgValue::Number(_)) => {
+            arithmetic(current, &Primitive::Add, value)
+        },
+        (Some(ref current @ ArgValue::Number(_)), ref value) |
+        (Some(ref current @ ArgValue::Float(_)), ref value) => {
+            Ok(arithmetic(current, &Primitive::Add, value).unwrap_or_else(|_| value.clone()))
+        },
+        (_, value) => Ok(value),
+    }
+}
+
+/// Remove a value from the current value (-=)
+fn deduct(current: ArgValue, value: ArgValue) -> Result<ArgValue, ExecutionError> {
+    match (current, value) {
+        (ArgValue::Array(values), value) => {
+            Ok(ArgValue::Array(values.into_iter().filter(|v| *v != value).collect()))
+        },
+        (ArgValue::Text(text), value) => {
+            let suffix = to_display(&value);
+            Ok(ArgValue::Text(if text.ends_with(&suffix) {
+                text[..text.len() - suffix.len()].to_string()
+            } else {
+                text
+            }))
+        },
+        (current, value) => arithmetic(&current, &Primitive::Subtract, &value),
+    }
+}
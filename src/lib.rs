@@ -6,24 +6,33 @@ extern crate serde_json;
 extern crate serde;
 
 pub mod arg;
+pub mod dice;
+pub mod distribution;
+pub mod error;
+pub mod function;
+pub mod host;
+pub mod interpreter;
+pub mod limits;
+pub mod macros;
 pub mod output;
 pub mod parser;
+pub mod random;
 pub mod step;
+pub mod token;
 
-use nom::Err::Error;
-use nom::types::CompleteByteSlice;
-use nom::simple_errors::Context::Code;
+use error::ParseError;
 
 #[derive(Debug, Serialize, Deserialize)]
 struct OutputError {
     error: String,
     context: String,
+    details: ParseError,
 }
 
 use std::mem;
 use std::ffi::{CString, CStr};
 use std::os::raw::{c_char, c_void};
-use parser::parse_p;
+use parser::parse_program;
 
 // In order to work with the memory we expose (de)allocation methods
 #[no_mangle]
@@ -63,28 +72,21 @@ pub extern "C" fn parse(raw_input: *mut c_char) -> *mut c_char {
 
 /// Run the internal parser and return a program or error
 fn run (input: Vec<u8>) -> String {
-    let prog = parse_p(CompleteByteSlice(input.as_slice()));
-    if prog.is_err() {
-        // Push the error
-        let error = prog.unwrap_err();
-
-        let mut output_error = OutputError {
-            error: format!("{:?}", error),
-            context: "".to_string(),
-        };
-
-        if let Error(Code(list, _code)) = error {
-            let context = String::from_utf8(list.to_vec()).unwrap();
-            output_error.context = context;
-        }
-
-        // return error JSON
-        serde_json::to_string(&output_error).unwrap()
-    } else {
-        let (_, program) = prog.unwrap();
-
-        // return success JSON
-        serde_json::to_string(&program).unwrap()
+    match parse_program(input.as_slice()) {
+        Ok(program) => {
+            // return success JSON
+            serde_json::to_string(&program).unwrap()
+        },
+        Err(error) => {
+            let output_error = OutputError {
+                error: error.to_string(),
+                context: String::from_utf8_lossy(&input[error.offset..]).to_string(),
+                details: error,
+            };
+
+            // return error JSON
+            serde_json::to_string(&output_error).unwrap()
+        },
     }
 }
 
@@ -94,19 +96,21 @@ fn safe_string(input: *mut c_char) -> Vec<u8> {
     }
 }
 
-// #[test]
-// fn test_error_handling() {
-    // // missing ending single quotation
-    // let input: Vec<u8> = "#test !prompt test [ok, not 'ok]".as_bytes().to_vec();
-    // let result = run(input);
+#[test]
+fn test_error_handling() {
+    // missing ending single quotation
+    let input: Vec<u8> = "#test !prompt test [ok, not 'ok]".as_bytes().to_vec();
+    let result = run(input);
 
-    // let json: OutputError = serde_json::from_str(&result).unwrap();
-    // assert_eq!(json.context, "[ok, not 'ok]".to_string());
+    let json: OutputError = serde_json::from_str(&result).unwrap();
+    assert_eq!(json.context, "'ok]".to_string());
+    assert_eq!(json.error, "line 1, col 29: unterminated single-quoted string".to_string());
 
-    // // missing prompt input
-    // let input: Vec<u8> = "#test !prompt [test, tester, testest]".as_bytes().to_vec();
-    // let result = run(input);
+    // missing prompt input
+    let input: Vec<u8> = "#test !prompt [test, tester, testest]".as_bytes().to_vec();
+    let result = run(input);
 
-    // let json: OutputError = serde_json::from_str(&result).unwrap();
-    // assert_eq!(json.context, "[test, tester, testest]".to_string());
-// }
+    let json: OutputError = serde_json::from_str(&result).unwrap();
+    assert_eq!(json.context, "[test, tester, testest]".to_string());
+    assert_eq!(json.details.construct, Some(error::Construct::PromptOptions));
+}
@@ -0,0 +1,320 @@
+use arg::*;
+use dice::{Dice, Die, MAX_REROLLS};
+use error::ExecutionError;
+use parser::Program;
+use std::fmt;
+use step::Step;
+
+/// A limit placed on macros
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Limit {
+    /// Token macros calling other token macros (e.g. @npc->attack)
+    Calls,
+    /// Dice in a single roll
+    Dice,
+    /// Steps nested inside other steps (e.g. a roll inside a conditional)
+    Depth,
+    /// Times a single loop runs
+    Iterations,
+    /// Messages, rolls and templates output by a program
+    Messages,
+    /// Totals worked out when analyzing a roll
+    Outcomes,
+    /// Rerolls or explosions of a single die
+    Rerolls,
+    /// Steps executed by a program, including nested steps
+    Steps,
+    /// Characters in a string
+    StringLength,
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Limit::Calls        => write!(f, "Too many nested token macros"),
+            Limit::Dice         => write!(f, "Too many dice in a roll"),
+            Limit::Depth        => write!(f, "Steps are nested too deeply"),
+            Limit::Iterations   => write!(f, "Too many loop iterations"),
+            Limit::Messages     => write!(f, "Too many messages"),
+            Limit::Outcomes     => write!(f, "Too many outcomes to analyze"),
+            Limit::Rerolls      => write!(f, "Too many rerolls"),
+            Limit::Steps        => write!(f, "Too many steps"),
+            Limit::StringLength => write!(f, "String is too long"),
+        }
+    }
+}
+
+/// Limits that keep one macro from stalling the table
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ExecutionLimits {
+    /// Most dice in a single roll (e.g. the 10 in 10d6)
+    pub max_dice: usize,
+    /// Most times a single die can be rerolled or explode
+    pub max_rerolls: usize,
+    /// Most steps a program can execute, including nested steps
+    pub max_steps: usize,
+    /// Most steps that can be nested inside each other
+    pub max_depth: usize,
+    /// Most times a single loop can run
+    pub max_iterations: usize,
+    /// Most messages, rolls and templates a program can output
+    pub max_messages: usize,
+    /// Most totals worked out when analyzing a roll, roughly dice times sides
+    pub max_outcomes: usize,
+    /// Most characters in a string
+    pub max_string_length: usize,
+    /// Most token macros that can call each other at once
+    pub max_call_depth: usize,
+}
+
+impl Default for ExecutionLimits {
+    fn default() -> ExecutionLimits {
+        ExecutionLimits {
+            max_dice: 1000,
+            max_rerolls: MAX_REROLLS,
+            max_steps: 1000,
+            max_depth: 16,
+            max_iterations: 100,
+            max_messages: 100,
+            max_outcomes: 100_000,
+            max_string_length: 10_000,
+            max_call_depth: 8,
+        }
+    }
+}
+
+impl ExecutionLimits {
+    /// Check a program can run within the limits before executing it
+    ///
+    /// Only values written out in the program are checked, values from variables and
+    /// tokens are checked while executing.
+    pub fn check_program(&self, program: &Program) -> Result<(), ExecutionError> {
+        self.check_steps(&program.steps).map_err(|(_, error)| error)
+    }
+
+    /// Check steps can run within the limits, failing with the index of the first step
+    /// that can not
+    pub fn check_steps(&self, steps: &[Step]) -> Result<(), (usize, ExecutionError)> {
+        let mut count = 0;
+        for (index, step) in steps.iter().enumerate() {
+            self.check_step(step, 1, &mut count).map_err(|error| (index, error))?;
+        }
+        Ok(())
+    }
+
+    fn check_step(&self, step: &Step, depth: usize, steps: &mut usize) -> Result<(), ExecutionError> {
+        *steps += 1;
+        self.check(Limit::Steps, *steps)?;
+        self.check(Limit::Depth, depth)?;
+
+        match step.op {
+            MacroOp::Roll | MacroOp::RollHidden | MacroOp::RollWhisper => {
+                // rolls using variables or tokens are checked when executed
+                let literal = Dice::from_args(&step.args, |value| match *value {
+                    ArgValue::Token(_) | ArgValue::Variable(_) | ArgValue::VariableReserved(_) => {
+                        Err(ExecutionError::InvalidValue(String::new()))
+                    },
+                    ref value => Ok(value.clone()),
+                });
+                if let Ok(dice) = literal {
+                    self.check_dice(&dice)?;
+                }
+            },
+            _ => {},
+        }
+
+        for arg in &step.args {
+            match *arg {
+                Arg::Conditional(ref conditional) => {
+                    self.check_condition(&conditional.condition, depth, steps)?;
+                    for else_if in &conditional.else_if {
+                        self.check_condition(&else_if.condition, depth, steps)?;
+                    }
+                    let branches = conditional.else_if.iter().map(|else_if| &else_if.success);
+                    for step in conditional.success.iter().chain(branches.flatten()).chain(&conditional.failure) {
+                        self.check_step(step, depth + 1, steps)?;
+                    }
+                },
+                Arg::Assign(ref assign) | Arg::Concat(ref assign) | Arg::Deduct(ref assign) => {
+                    for value in &assign.right {
+                        self.check_value(value, depth, steps)?;
+                    }
+                },
+                Arg::Loop(ref each) => {
+                    match each.over {
+                        LoopOver::Range(ArgValue::Number(first), ArgValue::Number(last)) => {
+                            self.check(Limit::Iterations, (i64::from(last) - i64::from(first) + 1).max(0) as usize)?;
+                        },
+                        LoopOver::Items(ArgValue::Array(ref items)) => self.check(Limit::Iterations, items.len())?,
+                        _ => {},
+                    }
+                    for step in &each.steps {
+                        self.check_step(step, depth + 1, steps)?;
+                    }
+                },
+                Arg::Function(ref value) | Arg::Unrecognized(ref value) => self.check_value(value, depth, steps)?,
+                Arg::Input(ref text) => self.check_text(text)?,
+                Arg::Say(SayArg::Message(ref text)) | Arg::Target(TargetArg::Message(ref text)) => self.check_text(text)?,
+                _ => {},
+            }
+        }
+        Ok(())
+    }
+
+    fn check_value(&self, value: &ArgValue, depth: usize, steps: &mut usize) -> Result<(), ExecutionError> {
+        match *value {
+            ArgValue::Array(ref values) => {
+                for value in values {
+                    self.check_value(value, depth, steps)?;
+                }
+                Ok(())
+            },
+            ArgValue::Object(ref values) => {
+                for value in values.values() {
+                    self.check_value(value, depth, steps)?;
+                }
+                Ok(())
+            },
+            ArgValue::Expr(ref expr) => self.check_expr(expr, depth, steps),
+            ArgValue::Index(ref value, ref key) => {
+                self.check_value(value, depth, steps)?;
+                self.check_value(key, depth, steps)
+            },
+            ArgValue::Length(ref value) => self.check_value(value, depth, steps),
+            ArgValue::Step(ref step) => self.check_step(step, depth + 1, steps),
+            ArgValue::Text(ref text) => self.check_string(text),
+            ArgValue::TextInterpolated(ref text) => self.check_text(text),
+            _ => Ok(()),
+        }
+    }
+
+    fn check_condition(&self, condition: &Condition, depth: usize, steps: &mut usize) -> Result<(), ExecutionError> {
+        match *condition {
+            Condition::Compare(ref compare) => {
+                self.check_value(&compare.left, depth, steps)?;
+                self.check_value(&compare.right, depth, steps)
+            },
+            Condition::And(ref left, ref right) | Condition::Or(ref left, ref right) => {
+                self.check_condition(left, depth, steps)?;
+                self.check_condition(right, depth, steps)
+            },
+            Condition::Not(ref condition) => self.check_condition(condition, depth, steps),
+        }
+    }
+
+    fn check_expr(&self, expr: &Expr, depth: usize, steps: &mut usize) -> Result<(), ExecutionError> {
+        match *expr {
+            Expr::Value(ref value) => self.check_value(value, depth, steps),
+            Expr::Negate(ref expr) => self.check_expr(expr, depth, steps),
+            Expr::Binary(ref left, _, ref right) => {
+                self.check_expr(left, depth, steps)?;
+                self.check_expr(right, depth, steps)
+            },
+        }
+    }
+
+    fn check_text(&self, text: &TextInterpolated) -> Result<(), ExecutionError> {
+        let length = text.parts.iter().fold(0, |length, part| match *part {
+            ArgValue::Text(ref text) => length + text.chars().count(),
+            _ => length,
+        });
+        self.check(Limit::StringLength, length)
+    }
+
+    /// Check a roll stays within the dice and reroll limits
+    ///
+    /// Rerolls or explosions that would happen on every face are rejected, since they
+    /// could never stop on their own.
+    pub fn check_dice(&self, dice: &Dice) -> Result<(), ExecutionError> {
+        self.check(Limit::Dice, count_dice(dice))?;
+        self.check_rerolls(dice)
+    }
+
+    fn check_rerolls(&self, dice: &Dice) -> Result<(), ExecutionError> {
+        for group in &dice.groups {
+            self.check_rerolls(&group.dice)?;
+        }
+
+        // a comparison matching the lowest side, the highest side and the side it compares
+        // against (e.g. the 3 in rr!=3) matches every side
+        let faces: Vec<i32> = match dice.die {
+            Some(Die::Sides(sides)) => {
+                let mut faces = vec![1, sides];
+                if let Some(ref reroll) = dice.reroll {
+                    if reroll.value > 1 && reroll.value < sides {
+                        faces.push(reroll.value);
+                    }
+                }
+                faces
+            },
+            Some(Die::Faces(ref faces)) => faces.clone(),
+            None => return Ok(()),
+        };
+        let endless = |matches: &dyn Fn(i32) -> bool| !faces.is_empty() && faces.iter().all(|face| matches(*face));
+
+        if let Some(ref reroll) = dice.reroll {
+            if endless(&|face| reroll.matches(face)) {
+                return Err(ExecutionError::LimitExceeded(Limit::Rerolls, self.max_rerolls));
+            }
+        }
+        if let Some(explode) = dice.explode {
+            if endless(&|face| face >= explode) {
+                return Err(ExecutionError::LimitExceeded(Limit::Rerolls, self.max_rerolls));
+            }
+        }
+        Ok(())
+    }
+
+    /// Check a roll is small enough to analyze, on top of the dice and reroll limits
+    pub fn check_analysis(&self, dice: &Dice) -> Result<(), ExecutionError> {
+        self.check_dice(dice)?;
+        self.check(Limit::Outcomes, count_outcomes(dice))
+    }
+
+    /// Check a string is within the length limit
+    pub fn check_string(&self, string: &str) -> Result<(), ExecutionError> {
+        self.check(Limit::StringLength, string.chars().count())
+    }
+
+    /// Check a count is within a limit
+    pub fn check(&self, limit: Limit, count: usize) -> Result<(), ExecutionError> {
+        let max = match limit {
+            Limit::Calls => self.max_call_depth,
+            Limit::Dice => self.max_dice,
+            Limit::Depth => self.max_depth,
+            Limit::Iterations => self.max_iterations,
+            Limit::Messages => self.max_messages,
+            Limit::Outcomes => self.max_outcomes,
+            Limit::Rerolls => self.max_rerolls,
+            Limit::Steps => self.max_steps,
+            Limit::StringLength => self.max_string_length,
+        };
+        if count > max {
+            Err(ExecutionError::LimitExceeded(limit, max))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Dice in a roll, including other groups
+fn count_dice(dice: &Dice) -> usize {
+    dice.groups.iter().fold(dice.count.max(0) as usize, |count, group| count.saturating_add(count_dice(&group.dice)))
+}
+
+/// Totals worked out when analyzing a roll, every die can land on every face and every
+/// explosion adds another die
+fn count_outcomes(dice: &Dice) -> usize {
+    let faces = match dice.die {
+        Some(Die::Sides(sides)) => sides.max(0) as usize,
+        Some(Die::Faces(ref faces)) => {
+            let lowest = faces.iter().min().cloned().unwrap_or(0);
+            let highest = faces.iter().max().cloned().unwrap_or(0);
+            (i64::from(highest) - i64::from(lowest) + 1) as usize
+        },
+        None => 1,
+    };
+    let chain = if dice.explode.is_some() { dice.max_rerolls.saturating_add(1) } else { 1 };
+    let outcomes = (dice.count.max(0) as usize).saturating_mul(faces).saturating_mul(chain);
+    dice.groups.iter().fold(outcomes, |outcomes, group| outcomes.saturating_add(count_outcomes(&group.dice)))
+}
@@ -0,0 +1,39 @@
+use arg::MacroOp;
+use parser::{Library, Program};
+use std::collections::HashMap;
+
+/// Macros tokens can run with @token->macro, by token and macro name
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct MacroRegistry {
+    macros: HashMap<String, HashMap<String, Program>>,
+}
+
+impl MacroRegistry {
+    pub fn new() -> MacroRegistry {
+        MacroRegistry::default()
+    }
+
+    /// Add or replace a token's macro
+    pub fn register(&mut self, token: &str, name: &str, program: Program) {
+        self.macros.entry(token.to_string()).or_default().insert(name.to_string(), program);
+    }
+
+    /// Add or replace every macro of a library as a token's macros
+    pub fn register_library(&mut self, token: &str, library: Library) {
+        for program in library.programs {
+            if let MacroOp::Name(name) = program.name.clone() {
+                self.register(token, &name, program);
+            }
+        }
+    }
+
+    /// Remove a token's macro, returning it if it was registered
+    pub fn remove(&mut self, token: &str, name: &str) -> Option<Program> {
+        self.macros.get_mut(token).and_then(|macros| macros.remove(name))
+    }
+
+    /// Find a token's macro
+    pub fn get(&self, token: &str, name: &str) -> Option<&Program> {
+        self.macros.get(token).and_then(|macros| macros.get(name))
+    }
+}
@@ -1,4 +1,7 @@
+use arg::ArgValue;
+use dice::RollResult;
 use parser::Program;
+use std::collections::HashMap;
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Output {
@@ -30,3 +33,68 @@ impl Output {
         }
     }
 }
+
+/// Output generated by the interpreter while executing a program
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct ExecutionOutput {
+    /// Messages sent with !say and !whisper
+    pub messages: Vec<Message>,
+
+    /// Every roll made, including hidden rolls
+    pub rolls: Vec<Roll>,
+
+    /// Templates rendered with !template
+    pub templates: Vec<Template>,
+
+    /// Everything visible to the room, in the order it was produced
+    pub raw: Vec<OutputEntry>,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub enum OutputEntry {
+    Message(Message),
+    Roll(Roll),
+    Template(Template),
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct Message {
+    /// The token the message is sent as
+    pub from: Option<String>,
+
+    /// The token the message is whispered to
+    pub to: Option<String>,
+
+    /// The interpolated message
+    pub message: String,
+
+    /// Whether the message was sent in test mode
+    pub is_test: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct Roll {
+    /// The outcome of the roll
+    pub result: RollResult,
+
+    /// The token the roll is made as
+    pub from: Option<String>,
+
+    /// The token the roll is whispered to
+    pub to: Option<String>,
+
+    /// The token running the macro, if run as another token
+    pub token: Option<String>,
+
+    /// Whether the roll was made in test mode
+    pub is_test: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct Template {
+    /// The template name
+    pub name: String,
+
+    /// Attributes passed to the template
+    pub attributes: HashMap<String, ArgValue>,
+}
@@ -2,21 +2,75 @@
 // e.g. parser::roll, parser::say, parser::core
 
 use arg::*;
+use error::{Construct, ExecutionError, ParseError};
 use nom::{
     IResult,
     alphanumeric,
     digit,
     recognize_float,
 };
+use limits::ExecutionLimits;
 use nom::types::CompleteByteSlice;
 use step::*;
+use std::cell::Cell;
 use std::collections::HashMap;
 use std::str;
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+thread_local! {
+    // Address and length of the input spans are offsets into (see `mark_p`)
+    static SOURCE: Cell<(usize, usize)> = const { Cell::new((0, 0)) };
+}
+
+/// Wraps a parser to also return the span of input it matched (see `mark_p`)
+macro_rules! spanned (
+    ($i:expr, $submac:ident!( $($args:tt)* )) => (
+        do_parse!($i,
+            start: mark_p >>
+            value: $submac!($($args)*) >>
+            end: mark_p >>
+            ((value, span(start, end)))
+        )
+    );
+    ($i:expr, $f:expr) => (
+        spanned!($i, call!($f))
+    );
+);
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct Program {
     pub name: MacroOp,
     pub steps: Vec<Step>,
+    /// Doc comments (/// or /** */) written before the macro name
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub docs: Vec<String>,
+}
+
+/// Macros parsed from one source, in the order they were written
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Library {
+    pub programs: Vec<Program>,
+}
+
+impl Library {
+    /// Find a macro by name (without the #)
+    pub fn get(&self, name: &str) -> Option<&Program> {
+        self.programs.iter().find(|program| program.name == MacroOp::Name(name.to_string()))
+    }
+
+    /// Names of every macro, in the order they were written
+    pub fn names(&self) -> Vec<&str> {
+        self.programs.iter().filter_map(|program| match program.name {
+            MacroOp::Name(ref name) => Some(name.as_str()),
+            _ => None,
+        }).collect()
+    }
+}
+
+/// A program parsed as far as possible, with every error found along the way
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PartialProgram {
+    pub program: Program,
+    pub errors: Vec<ParseError>,
 }
 
 /// Matches advantage roll argument
@@ -53,6 +107,7 @@ pub fn assignment_left_p(input: CompleteByteSlice) -> IResult<CompleteByteSlice,
 pub fn assignment_right_p(input: CompleteByteSlice) -> IResult<CompleteByteSlice, Vec<ArgValue>> {
     // we can assign almost anything else to them (except inline arguments, for now)
     many0!(input, alt_complete!(
+        expression_p            => { | a | a                                } |
         parse_inline_function_p => { | a | ArgValue::Step(a)                } |
         boolean_p               => { | a | ArgValue::Boolean(a)             } |
         num_p                   => { | a | ArgValue::Number(a)              } |
@@ -64,8 +119,7 @@ pub fn assignment_right_p(input: CompleteByteSlice) -> IResult<CompleteByteSlice
         variable_p              => { | a | ArgValue::Variable(a)            } |
         token_p                 => { | a | ArgValue::Token(a)               } |
         json_array_p            => { | a | ArgValue::Array(a)               } |
-        json_hash_p             => { | a | ArgValue::Object(a)              } |
-        primitive_p             => { | a | ArgValue::Primitive(a)           }
+        json_hash_p             => { | a | ArgValue::Object(a)              }
     ))
 }
 
@@ -114,18 +168,52 @@ pub fn arguments_input_p(input: CompleteByteSlice) -> IResult<CompleteByteSlice,
     add_return_error!(input, ErrorKind::Custom(5), do_parse!(
         message: alt_complete!(
             quoted_interpolated_p |
-            single_quoted_p => { | quote | TextInterpolated { parts: vec![ ArgValue::Text(quote) ] } }
+            single_quoted_p => { | quote | TextInterpolated { parts: vec![ ArgValue::Text(quote) ], span: None } }
         ) >>
         (Arg::Input(message))
     ))
 }
 
+/// Matches !for and !each arguments (e.g. "$i in 1..3 { !say \"$i\" }" or "@player in [@alice, @bob] ...")
+pub fn arguments_loop_p(input: CompleteByteSlice) -> IResult<CompleteByteSlice, Arg> {
+    do_parse!(input,
+        variable: ws!(alt_complete!(
+            variable_p  => { | a | ArgValue::Variable(a)    } |
+            token_p     => { | a | ArgValue::Token(a)       }
+        )) >>
+        ws!(tag_no_case!("in")) >>
+        over: alt_complete!(
+            separated_pair!(range_bound_p, tag!(".."), range_bound_p)  => { | (a, b) | LoopOver::Range(a, b)         } |
+            ws!(json_array_p)                                           => { | a | LoopOver::Items(ArgValue::Array(a))  } |
+            map!(ws!(expr_atom_p), Expr::into_value)                    => { | a | LoopOver::Items(a)                   }
+        ) >>
+        steps: ws!(alt_complete!(
+            block_p |
+            parse_step_p => { | a | vec![a] }
+        )) >>
+        (Arg::Loop(Box::new(Loop {
+            variable,
+            over,
+            steps,
+        })))
+    )
+}
+
+/// Matches either end of a range, arithmetic needs parentheses (e.g. 1 or ($count - 1))
+pub fn range_bound_p(input: CompleteByteSlice) -> IResult<CompleteByteSlice, ArgValue> {
+    ws!(input, alt_complete!(
+        roll_digit_p                        => { | a | ArgValue::Number(a)          } |
+        preceded!(tag!("-"), roll_digit_p)  => { | a: i32 | ArgValue::Number(-a)    } |
+        map!(expr_atom_p, Expr::into_value)
+    ))
+}
+
 /// Matches !prompt arguments
 pub fn arguments_prompt_p(input: CompleteByteSlice) -> IResult<CompleteByteSlice, Arg> {
     add_return_error!(input, ErrorKind::Custom(4), do_parse!(
         message: ws!(alt_complete!(
             quoted_interpolated_p |
-            single_quoted_p => { |quote| TextInterpolated { parts: vec![ ArgValue::Text(quote) ] } }
+            single_quoted_p => { |quote| TextInterpolated { parts: vec![ ArgValue::Text(quote) ], span: None } }
         )) >>
         options: switch!(options_p,
             Some(opts) => value!(opts) |
@@ -155,7 +243,10 @@ pub fn arguments_roll_p(input: CompleteByteSlice) -> IResult<CompleteByteSlice,
         disadvantage_p          |
         roll_num_p              |
         roll_die_p              |
+        roll_flag_cf_p          |
+        roll_flag_cs_p          |
         roll_flag_e_p           |
+        roll_flag_gl_p          |
         roll_flag_gt_p          |
         roll_flag_gte_p         |
         roll_flag_h_p           |
@@ -166,6 +257,8 @@ pub fn arguments_roll_p(input: CompleteByteSlice) -> IResult<CompleteByteSlice,
         roll_flag_min_p         |
         roll_flag_ro_p          |
         roll_flag_rr_p          |
+        roll_group_pos_p        |
+        roll_group_neg_p        |
         roll_modifier_pos_p     |
         roll_modifier_neg_p     |
         quoted_interpolated_p   => { | a | Arg::Roll(RollArg::Comment(ArgValue::TextInterpolated(a)))   } |
@@ -200,6 +293,7 @@ pub fn arguments_say_p(input: CompleteByteSlice) -> IResult<CompleteByteSlice, A
         quoted_interpolated_p   => { | a | Arg::Say(SayArg::Message(a))     } |
         single_quoted_p         => { | a | Arg::Say(SayArg::Message(TextInterpolated {
             parts: vec![ ArgValue::Text(a) ],
+            span: None,
         }))                                                                 } |
         token_p                 => { | a | Arg::Say(SayArg::From(a))        }
     )
@@ -211,6 +305,7 @@ pub fn arguments_target_p(input: CompleteByteSlice) -> IResult<CompleteByteSlice
         quoted_interpolated_p   => { | a | Arg::Say(SayArg::Message(a))     } |
         single_quoted_p         => { | a | Arg::Say(SayArg::Message(TextInterpolated {
             parts: vec![ ArgValue::Text(a) ],
+            span: None,
         }))                                                                 }
     )
 }
@@ -236,6 +331,7 @@ pub fn arguments_whisper_p(input: CompleteByteSlice) -> IResult<CompleteByteSlic
         quoted_interpolated_p   => { | a | Arg::Say(SayArg::Message(a))     } |
         single_quoted_p         => { | a | Arg::Say(SayArg::Message(TextInterpolated {
             parts: vec![ ArgValue::Text(a) ],
+            span: None,
         }))                                                                 } |
         token_p                 => { | a | Arg::Say(SayArg::To(a))          }
     )
@@ -267,6 +363,7 @@ pub fn command_p(input: CompleteByteSlice) -> IResult<CompleteByteSlice, MacroOp
         tag_no_case!("!exit")                               => { |_| MacroOp::Exit          } |
         tag_no_case!("!template")                           => { |_| MacroOp::Template      } |
         tag_no_case!("!test")                               => { |_| MacroOp::TestMode      } |
+        alt!(tag_no_case!("!each") | tag_no_case!("!for"))  => { |_| MacroOp::Loop          } |
         alt!(tag_no_case!("!case") | tag_no_case!("!c"))    => { |_| MacroOp::Case          } |
         alt!(tag_no_case!("!hroll") | tag_no_case!("!hr"))  => { |_| MacroOp::RollHidden    } |
         alt!(tag_no_case!("!input") | tag_no_case!("!i"))   => { |_| MacroOp::Input         } |
@@ -282,6 +379,7 @@ pub fn command_p(input: CompleteByteSlice) -> IResult<CompleteByteSlice, MacroOp
 pub fn comparison_p(input: CompleteByteSlice) -> IResult<CompleteByteSlice, ComparisonArg> {
     ws!(input, alt_complete!(
         tag!("==")  => { |_| ComparisonArg::EqualTo             } |
+        tag!("!=")  => { |_| ComparisonArg::NotEqualTo          } |
         tag!(">=")  => { |_| ComparisonArg::GreaterThanOrEqual  } |
         tag!("<=")  => { |_| ComparisonArg::LessThanOrEqual     } |
         tag!(">")   => { |_| ComparisonArg::GreaterThan         } |
@@ -289,45 +387,91 @@ pub fn comparison_p(input: CompleteByteSlice) -> IResult<CompleteByteSlice, Comp
     ))
 }
 
-/// Matches conditional statements (e.g. "1 > 2 ? success : failure")
+/// Matches conditional statements (e.g. "1 > 2 ? success : failure"), with any number of
+/// else-if conditions before the failure (e.g. "$1 > 15 ? ... : $1 > 10 ? ... : ...")
 pub fn conditional_p(input: CompleteByteSlice) -> IResult<CompleteByteSlice, Conditional> {
     add_return_error!(input, ErrorKind::Custom(3), do_parse!(
-        left: ws!(alt_complete!(
-            variable_reserved_p => { | a | ArgValue::VariableReserved(a)    } |
-            variable_p          => { | a | ArgValue::Variable(a)            } |
-            token_p             => { | a | ArgValue::Token(a)               } |
-            num_p               => { | a | ArgValue::Number(a)              } |
-            float_p             => { | a | ArgValue::Float(a)               }
-        )) >>
-        comparison: comparison_p >>
-        // but we can assign almost anything else to them (except inline arguments)
-        right: ws!(alt_complete!(
-            num_p               => { | a | ArgValue::Number(a)              } |
-            float_p             => { | a | ArgValue::Float(a)               } |
-            token_p             => { | a | ArgValue::Token(a)               } |
-            variable_reserved_p => { | a | ArgValue::VariableReserved(a)    } |
-            variable_p          => { | a | ArgValue::Variable(a)            }
-        )) >>
+        condition: condition_p >>
         ws!(tag!("?")) >>
-        success: ws!(alt_complete!(
-            tag!("|") => { |_| None } |
-            opt!(parse_step_p)
-        )) >>
+        success: branch_p >>
+        else_if: many0!(complete!(do_parse!(
+            ws!(tag!(":")) >>
+            condition: condition_p >>
+            ws!(tag!("?")) >>
+            success: branch_p >>
+            (ElseIf { condition, success })
+        ))) >>
         ws!(tag!(":")) >>
-        failure: ws!(alt_complete!(
-            tag!("|") => { |_| None } |
-            opt!(parse_step_p)
-        )) >>
+        failure: branch_p >>
         (Conditional {
-            left,
-            comparison,
-            right,
-            success: success,
-            failure: failure,
+            condition,
+            success,
+            else_if,
+            failure,
         })
     ))
 }
 
+/// Matches the steps on one side of a conditional: a single step, several steps in braces
+/// (e.g. "{ !r 1d8 >> !say 'Hit for $1' }") or | for none
+pub fn branch_p(input: CompleteByteSlice) -> IResult<CompleteByteSlice, Vec<Step>> {
+    ws!(input, alt_complete!(
+        tag!("|") => { |_| vec![] } |
+        block_p |
+        opt!(parse_step_p) => { | a: Option<Step> | a.into_iter().collect() }
+    ))
+}
+
+/// Matches several steps in braces (e.g. "{ !r 1d8 >> !say 'Hit for $1' }")
+pub fn block_p(input: CompleteByteSlice) -> IResult<CompleteByteSlice, Vec<Step>> {
+    delimited!(input,
+        tag!("{"),
+        many0!(preceded!(not!(ws!(tag!("}"))), parse_step_p)),
+        ws!(tag!("}"))
+    )
+}
+
+/// Matches conditions joined with || (e.g. "$1 > 15 || $2 > 15")
+pub fn condition_p(input: CompleteByteSlice) -> IResult<CompleteByteSlice, Condition> {
+    do_parse!(input,
+        first: condition_and_p >>
+        rest: many0!(complete!(preceded!(ws!(tag!("||")), condition_and_p))) >>
+        (rest.into_iter().fold(first, |left, right| Condition::Or(Box::new(left), Box::new(right))))
+    )
+}
+
+/// Matches conditions joined with &&, which binds tighter than ||
+pub fn condition_and_p(input: CompleteByteSlice) -> IResult<CompleteByteSlice, Condition> {
+    do_parse!(input,
+        first: condition_not_p >>
+        rest: many0!(complete!(preceded!(ws!(tag!("&&")), condition_not_p))) >>
+        (rest.into_iter().fold(first, |left, right| Condition::And(Box::new(left), Box::new(right))))
+    )
+}
+
+/// Matches a condition with an optional ! in front of it (e.g. "!($1 > 15)")
+pub fn condition_not_p(input: CompleteByteSlice) -> IResult<CompleteByteSlice, Condition> {
+    ws!(input, alt_complete!(
+        condition_atom_p |
+        preceded!(tag!("!"), condition_not_p) => { | a | Condition::Not(Box::new(a)) }
+    ))
+}
+
+/// Matches a comparison or a condition in parentheses
+///
+/// Parentheses around arithmetic (e.g. "($1 + 2) * 3 > 10") are left to the comparison.
+pub fn condition_atom_p(input: CompleteByteSlice) -> IResult<CompleteByteSlice, Condition> {
+    alt_complete!(input,
+        delimited!(tag!("("), ws!(condition_p), tag!(")")) |
+        do_parse!(
+            left: condition_value_p >>
+            comparison: comparison_p >>
+            right: condition_value_p >>
+            (Condition::Compare(Box::new(Comparison { left, comparison, right })))
+        )
+    )
+}
+
 /// Matches disadvantage roll argument
 pub fn disadvantage_p(input: CompleteByteSlice) -> IResult<CompleteByteSlice, Arg> {
     map!(input, alt_complete!(tag!("disadvantage") | tag!("dis")), |_| Arg::Roll(RollArg::Disadvantage))
@@ -346,14 +490,182 @@ pub fn deduct_p(input: CompleteByteSlice) -> IResult<CompleteByteSlice, Assign>
     )
 }
 
-/// Matches arguments in double quotes ("") - no interpolation
-pub fn double_quoted_p(input: CompleteByteSlice) -> IResult<CompleteByteSlice, String> {
+/// Matches an arithmetic expression (e.g. 2 + 3 * 4), a single value is returned on its own
+pub fn expression_p(input: CompleteByteSlice) -> IResult<CompleteByteSlice, ArgValue> {
+    map!(input, expr_p, Expr::into_value)
+}
+
+/// Matches terms added or subtracted together
+pub fn expr_p(input: CompleteByteSlice) -> IResult<CompleteByteSlice, Expr> {
     do_parse!(input,
-        word: delimited!(tag!("\""),take_until!("\""), tag!("\"")) >>
-        (String::from_utf8(word.to_vec()).unwrap())
+        first: expr_term_p >>
+        rest: many0!(complete!(pair!(
+            ws!(alt!(
+                tag!("+") => { |_| Primitive::Add       } |
+                tag!("-") => { |_| Primitive::Subtract  }
+            )),
+            expr_term_p
+        ))) >>
+        (binary(first, rest))
+    )
+}
+
+/// Matches factors multiplied, divided or taken the remainder of, which bind tighter than + and -
+pub fn expr_term_p(input: CompleteByteSlice) -> IResult<CompleteByteSlice, Expr> {
+    do_parse!(input,
+        first: expr_unary_p >>
+        rest: many0!(complete!(pair!(
+            ws!(alt!(
+                tag!("*")   => { |_| Primitive::Multiply        } |
+                tag!("/")   => { |_| Primitive::Divide          } |
+                tag!("%")   => { |_| Primitive::Modulo          } |
+                tag!("\\")  => { |_| Primitive::IntegerDivide   }
+            )),
+            expr_unary_p
+        ))) >>
+        (binary(first, rest))
+    )
+}
+
+/// Matches a factor with an optional unary minus (e.g. -$foo)
+pub fn expr_unary_p(input: CompleteByteSlice) -> IResult<CompleteByteSlice, Expr> {
+    ws!(input, alt_complete!(
+        expr_atom_p |
+        preceded!(tag!("-"), expr_unary_p) => { | a | Expr::Negate(Box::new(a)) }
+    ))
+}
+
+/// Matches a value or an expression in parentheses
+pub fn expr_atom_p(input: CompleteByteSlice) -> IResult<CompleteByteSlice, Expr> {
+    alt_complete!(input,
+        delimited!(tag!("("), ws!(expr_p), tag!(")")) |
+        length_p                => { | a | Expr::Value(a)                             } |
+        parse_inline_function_p => { | a | Expr::Value(ArgValue::Step(a))             } |
+        num_p                   => { | a | Expr::Value(ArgValue::Number(a))           } |
+        float_p                 => { | a | Expr::Value(ArgValue::Float(a))            } |
+        indexed_p               => { | a | Expr::Value(a)                             }
     )
 }
 
+/// Matches a variable or token with any items taken out of it (e.g. $list[0], $stats.str or
+/// @me.inventory[$i])
+pub fn indexed_p(input: CompleteByteSlice) -> IResult<CompleteByteSlice, ArgValue> {
+    alt_complete!(input,
+        indexed_variable_p |
+        do_parse!(
+            token: token_p >>
+            keys: index_keys_p >>
+            (indexed(ArgValue::Token(token), keys))
+        )
+    )
+}
+
+/// Matches a variable with any items taken out of it (e.g. $list[0] or $stats.str)
+pub fn indexed_variable_p(input: CompleteByteSlice) -> IResult<CompleteByteSlice, ArgValue> {
+    do_parse!(input,
+        value: alt_complete!(
+            variable_reserved_p => { | a | ArgValue::VariableReserved(a)    } |
+            variable_p          => { | a | ArgValue::Variable(a)            }
+        ) >>
+        keys: index_keys_p >>
+        (indexed(value, keys))
+    )
+}
+
+/// Matches the keys of items taken out of a value, in order (e.g. [0] and .str)
+pub fn index_keys_p(input: CompleteByteSlice) -> IResult<CompleteByteSlice, Vec<ArgValue>> {
+    many0!(input, complete!(alt_complete!(
+        delimited!(tag!("["), ws!(alt_complete!(
+            single_quoted_p => { | a | ArgValue::Text(a) } |
+            expression_p
+        )), tag!("]")) |
+        preceded!(tag!("."), variable_word_p) => { | a | ArgValue::Text(a) }
+    )))
+}
+
+/// Take items out of a value with each key in turn
+fn indexed(value: ArgValue, keys: Vec<ArgValue>) -> ArgValue {
+    keys.into_iter().fold(value, |value, key| ArgValue::Index(Box::new(value), Box::new(key)))
+}
+
+/// Matches the length of an array, object or text (e.g. len($list))
+pub fn length_p(input: CompleteByteSlice) -> IResult<CompleteByteSlice, ArgValue> {
+    do_parse!(input,
+        tag!("len(") >>
+        value: ws!(alt_complete!(
+            json_array_p            => { | a | ArgValue::Array(a)               } |
+            json_hash_p             => { | a | ArgValue::Object(a)              } |
+            quoted_interpolated_p   => { | a | ArgValue::TextInterpolated(a)    } |
+            single_quoted_p         => { | a | ArgValue::Text(a)                } |
+            expression_p
+        )) >>
+        tag!(")") >>
+        (ArgValue::Length(Box::new(value)))
+    )
+}
+
+/// Join expressions with their operators from left to right
+fn binary(first: Expr, rest: Vec<(Primitive, Expr)>) -> Expr {
+    rest.into_iter().fold(first, |left, (op, right)| Expr::Binary(Box::new(left), op, Box::new(right)))
+}
+
+/// Matches either side of a comparison: booleans, quoted text, arrays and objects, or numbers,
+/// variables, tokens and arithmetic on them
+pub fn condition_value_p(input: CompleteByteSlice) -> IResult<CompleteByteSlice, ArgValue> {
+    ws!(input, alt_complete!(
+        boolean_p               => { | a | ArgValue::Boolean(a)             } |
+        quoted_interpolated_p   => { | a | ArgValue::TextInterpolated(a)    } |
+        single_quoted_p         => { | a | ArgValue::Text(a)                } |
+        json_array_p            => { | a | ArgValue::Array(a)               } |
+        json_hash_p             => { | a | ArgValue::Object(a)              } |
+        expression_p
+    ))
+}
+
+/// Matches arguments in double quotes ("") - no interpolation
+pub fn double_quoted_p(input: CompleteByteSlice) -> IResult<CompleteByteSlice, String> {
+    delimited!(input, tag!("\""), map!(opt!(call!(escaped_p, b"\"")), Option::unwrap_or_default), tag!("\""))
+}
+
+/// Matches text up to an unescaped byte in `stop`, decoding escapes (e.g. \" or \u{2694})
+///
+/// Backslashes that do not start an escape are kept as they are.
+pub fn escaped_p<'a>(input: CompleteByteSlice<'a>, stop: &[u8]) -> IResult<CompleteByteSlice<'a>, String> {
+    let mut text = vec![];
+    let mut i = 0;
+    while i < input.len() && !stop.contains(&input[i]) {
+        if input[i] == b'\\' {
+            if let Some((c, length)) = unescape(&input[i + 1..]) {
+                let mut buffer = [0; 4];
+                text.extend_from_slice(c.encode_utf8(&mut buffer).as_bytes());
+                i += length + 1;
+                continue;
+            }
+        }
+        text.push(input[i]);
+        i += 1;
+    }
+    if i == 0 {
+        return Err(nom::Err::Error(nom::Context::Code(input, nom::ErrorKind::Escaped)));
+    }
+    Ok((CompleteByteSlice(&input[i..]), String::from_utf8_lossy(&text).into_owned()))
+}
+
+/// Decode the escape after a backslash, returning the character and how many bytes it took
+fn unescape(input: &[u8]) -> Option<(char, usize)> {
+    match *input.first()? {
+        b'n' => Some(('\n', 1)),
+        c @ b'"' | c @ b'\'' | c @ b'@' | c @ b'$' | c @ b'\\' => Some((c as char, 1)),
+        b'u' if input.get(1) == Some(&b'{') => {
+            let end = input.iter().position(|c| *c == b'}')?;
+            let hex = str::from_utf8(&input[2..end]).ok()?;
+            let c = u32::from_str_radix(hex, 16).ok().and_then(char::from_u32)?;
+            Some((c, end + 1))
+        },
+        _ => None,
+    }
+}
+
 /// Match floats to argument strings
 pub fn float_p(input: CompleteByteSlice) -> IResult<CompleteByteSlice, f32> {
     flat_map!(input, recognize_float, parse_to!(f32))
@@ -434,10 +746,8 @@ pub fn name_p(input: CompleteByteSlice) -> IResult<CompleteByteSlice, MacroOp> {
 pub fn num_p(input: CompleteByteSlice) -> IResult<CompleteByteSlice, i32> {
     do_parse!(input,
         sign: opt!(tag!("-")) >>
-        num: digit >>
+        val: digits_p >>
         not!(tag!(".")) >>
-        s: value!(String::from_utf8(num.to_vec()).unwrap()) >>
-        val: value!(s.parse::<i32>().unwrap()) >>
         switch: switch!(value!(&sign),
             &Some(_) => value!(-1 * val) |
             &None => value!(val)
@@ -446,6 +756,11 @@ pub fn num_p(input: CompleteByteSlice) -> IResult<CompleteByteSlice, i32> {
     )
 }
 
+/// Matches digits that fit in a number of type `T`, failing rather than overflowing
+fn digits_p<T: str::FromStr>(input: CompleteByteSlice) -> IResult<CompleteByteSlice, T> {
+    map_res!(input, map_res!(digit, utf8), str::parse::<T>)
+}
+
 /// Matches any type of operation
 pub fn op_p(input: CompleteByteSlice) -> IResult<CompleteByteSlice, MacroOp> {
     alt_complete!(input,
@@ -504,16 +819,24 @@ pub fn parse_option_p(input: CompleteByteSlice) -> IResult<CompleteByteSlice, Sw
 
 /// Parse the complete macro
 pub fn parse_p(input: CompleteByteSlice) -> IResult<CompleteByteSlice, Program> {
+    source(&input);
     do_parse!(input,
         prog_name: name_p >>
         steps: many0!(parse_step_p) >>
         (Program {
             name: prog_name,
-            steps: steps,
+            steps,
+            docs: vec![],
         })
     )
 }
 
+/// Parse a roll command on its own (e.g. "1d20+5")
+pub fn parse_roll_p(input: CompleteByteSlice) -> IResult<CompleteByteSlice, Vec<Arg>> {
+    source(&input);
+    many0!(input, arguments_roll_p)
+}
+
 /// Parse a function
 /// Step order matters!
 ///
@@ -521,21 +844,29 @@ pub fn parse_p(input: CompleteByteSlice) -> IResult<CompleteByteSlice, Program>
 /// the word prior to the braces represents the name of the function
 pub fn parse_inline_function_p(input: CompleteByteSlice) -> IResult<CompleteByteSlice, Step> {
     let mut args = vec![];
+    let mut arg_spans = vec![];
 
     do_parse!(input,
+        start: mark_p >>
         function_name: variable_word_p >>
         delimited!(
             tag!("{"),
             separated_list_complete!(
                 tag!("|"),
-                map!(parse_option_key_value_p, | arg | args.push(Arg::Function(arg)))
+                map!(spanned!(parse_option_key_value_p), | (arg, span) | {
+                    args.push(Arg::Function(arg));
+                    arg_spans.push(span);
+                })
             ),
             tag!("}")
         ) >>
+        end: mark_p >>
         (Step {
             args,
             op: MacroOp::Function(function_name),
             result: StepResult::Ignore,
+            span: Some(span(start, end)),
+            arg_spans,
         })
     )
 }
@@ -543,31 +874,44 @@ pub fn parse_inline_function_p(input: CompleteByteSlice) -> IResult<CompleteByte
 /// Parse a step for possible assignment, it must be a command that starts with a "!"
 pub fn parse_inline_step_p(input: CompleteByteSlice) -> IResult<CompleteByteSlice, Step> {
     do_parse!(input,
+        start: mark_p >>
         op_type: command_p >>
-        args: many0!(switch!(value!(&op_type),
+        args: many0!(spanned!(switch!(value!(&op_type),
             &MacroOp::Case          => call!(arguments_case_p) |
             &MacroOp::Input         => call!(arguments_input_p) |
+            &MacroOp::Loop          => call!(arguments_loop_p) |
             &MacroOp::Prompt        => call!(arguments_prompt_p) |
             &MacroOp::Roll          => call!(arguments_roll_p) |
             &MacroOp::RollHidden    => call!(arguments_roll_p) |
             &MacroOp::RollWhisper   => call!(arguments_roll_p) |
             &MacroOp::Target        => call!(arguments_target_p)
-        )) >>
-        (Step {
-            args,
-            op: op_type,
-            result: StepResult::Ignore,
-        })
+        ))) >>
+        end: mark_p >>
+        (step(op_type, args, StepResult::Ignore, span(start, end)))
     )
 }
 
+/// Build a step from its arguments and the spans they were parsed from
+fn step(op: MacroOp, args: Vec<(Arg, Span)>, result: StepResult, span: Span) -> Step {
+    let (args, arg_spans) = args.into_iter().unzip();
+    Step {
+        args,
+        op,
+        result,
+        span: Some(span),
+        arg_spans,
+    }
+}
+
 /// Parse a step of the program
 pub fn parse_step_p(input: CompleteByteSlice) -> IResult<CompleteByteSlice, Step> {
     do_parse!(input,
+        start: mark_p >>
         op_type: op_p >>
-        args: many0!(switch!(value!(&op_type),
+        args: many0!(spanned!(switch!(value!(&op_type),
             &MacroOp::Case          => call!(arguments_case_p) |
             &MacroOp::Input         => call!(arguments_input_p) |
+            &MacroOp::Loop          => call!(arguments_loop_p) |
             &MacroOp::Prompt        => call!(arguments_prompt_p) |
             &MacroOp::Roll          => call!(arguments_roll_p) |
             &MacroOp::RollHidden    => call!(arguments_roll_p) |
@@ -578,53 +922,116 @@ pub fn parse_step_p(input: CompleteByteSlice) -> IResult<CompleteByteSlice, Step
             &MacroOp::TestMode      => call!(arguments_test_mode_p) |
             &MacroOp::Whisper       => call!(arguments_whisper_p) |
             _                       => call!(arguments_p)
-        )) >>
+        ))) >>
+        end: mark_p >>
         result: step_result_p >>
-        (Step {
-            args,
-            op: op_type,
-            result,
-        })
+        (step(op_type, args, result, span(start, end)))
     )
 }
 
 /// Matches primitive operations (starts with a number)
 pub fn primitive_p(input: CompleteByteSlice) -> IResult<CompleteByteSlice, Primitive> {
     ws!(input, alt_complete!(
-        tag!("+") => { |_| Primitive::Add       } |
-        tag!("-") => { |_| Primitive::Subtract  } |
-        tag!("/") => { |_| Primitive::Divide    } |
-        tag!("*") => { |_| Primitive::Multiply  }
+        tag!("+")   => { |_| Primitive::Add             } |
+        tag!("-")   => { |_| Primitive::Subtract        } |
+        tag!("/")   => { |_| Primitive::Divide          } |
+        tag!("*")   => { |_| Primitive::Multiply        } |
+        tag!("%")   => { |_| Primitive::Modulo          } |
+        tag!("\\")  => { |_| Primitive::IntegerDivide   }
     ))
 }
 
 /// Matches arguments in any type of quotes with variable interpolation
 pub fn quoted_interpolated_p(input: CompleteByteSlice) -> IResult<CompleteByteSlice, TextInterpolated> {
     do_parse!(input,
+        start: mark_p >>
         tag!("\"") >>
         parts: many0!(alt_complete!(
-            variable_reserved_p => { | a | ArgValue::VariableReserved(a)                            } |
-            variable_p          => { | a | ArgValue::Variable(a)                                    } |
-            token_p             => { | a | ArgValue::Token(a)                                       } |
-            not_a_token_or_variable_p     => { | a | ArgValue::Text(a)   }
+            indexed_p |
+            not_a_token_or_variable_p => { | a | ArgValue::Text(a) }
         )) >>
         tag!("\"") >>
+        end: mark_p >>
         (TextInterpolated {
             parts,
+            span: Some(span(start, end)),
         })
     )
 }
 
 pub fn not_a_token_or_variable_p(input: CompleteByteSlice) -> IResult<CompleteByteSlice, String> {
-    map!(input, is_not!("@$\""), | text | String::from_utf8(text.to_vec()).unwrap())
+    escaped_p(input, b"@$\"")
+}
+
+/// Matches nothing, marking where a span starts or ends (see `span`)
+///
+/// Spans are offsets from the start of the macro given to `parse_p`, `parse_program` and
+/// the like. A parser used on its own measures from where its first span starts.
+pub fn mark_p(input: CompleteByteSlice) -> IResult<CompleteByteSlice, CompleteByteSlice> {
+    let (start, len) = SOURCE.with(Cell::get);
+    let at = input.as_ptr() as usize;
+    if at < start || at + input.len() != start + len {
+        source(&input);
+    }
+    Ok((input, input))
+}
+
+/// Measure spans from the start of `input` (see `mark_p`)
+fn source(input: &[u8]) {
+    SOURCE.with(|source| source.set((input.as_ptr() as usize, input.len())));
+}
+
+/// The input between two marks as offsets from the start of the macro, without the
+/// whitespace around it
+fn span(start: CompleteByteSlice, end: CompleteByteSlice) -> Span {
+    let matched = &start[..start.len() - end.len()];
+    let leading = matched.iter().take_while(|c| c.is_ascii_whitespace()).count();
+    let trailing = matched[leading..].iter().rev().take_while(|c| c.is_ascii_whitespace()).count();
+    let offset = start.as_ptr() as usize - SOURCE.with(Cell::get).0;
+    Span { start: offset + leading, end: offset + matched.len() - trailing }
 }
 
 /// Matches digits for "D" and parses to i32
 pub fn roll_digit_p(input: CompleteByteSlice) -> IResult<CompleteByteSlice, i32> {
+    digits_p(input)
+}
+
+/// Matches roll flag "cf", counting failures that cancel successes (e.g. cf<=1)
+pub fn roll_flag_cf_p(input: CompleteByteSlice) -> IResult<CompleteByteSlice, Arg> {
     do_parse!(input,
-        var: digit >>
-        num: value!(String::from_utf8(var.to_vec()).unwrap()) >>
-        (num.parse::<i32>().unwrap())
+        tag!("cf") >>
+        op: map!(opt!(comparison_p), | op | op.unwrap_or(ComparisonArg::LessThanOrEqual)) >>
+        value: roll_flag_var_p >>
+        (Arg::Roll(RollArg::CF(Comparitive {
+            op,
+            value,
+        })))
+    )
+}
+
+/// Matches roll flag "cs", counting successes instead of adding the dice (e.g. cs>=8)
+pub fn roll_flag_cs_p(input: CompleteByteSlice) -> IResult<CompleteByteSlice, Arg> {
+    do_parse!(input,
+        tag!("cs") >>
+        op: map!(opt!(comparison_p), | op | op.unwrap_or(ComparisonArg::GreaterThanOrEqual)) >>
+        value: roll_flag_var_p >>
+        (Arg::Roll(RollArg::CS(Comparitive {
+            op,
+            value,
+        })))
+    )
+}
+
+/// Matches roll flag "gl", glitching when more than half the dice match (e.g. gl<=1)
+pub fn roll_flag_gl_p(input: CompleteByteSlice) -> IResult<CompleteByteSlice, Arg> {
+    do_parse!(input,
+        tag!("gl") >>
+        op: map!(opt!(comparison_p), | op | op.unwrap_or(ComparisonArg::LessThanOrEqual)) >>
+        value: roll_flag_var_p >>
+        (Arg::Roll(RollArg::GL(Comparitive {
+            op,
+            value,
+        })))
     )
 }
 
@@ -746,10 +1153,10 @@ pub fn roll_flag_rr_p(input: CompleteByteSlice) -> IResult<CompleteByteSlice, Ar
 /// Matches valid roll flag inputs
 pub fn roll_flag_var_p(input: CompleteByteSlice) -> IResult<CompleteByteSlice, ArgValue> {
     ws!(input, alt_complete!(
-        variable_reserved_p => { |n| ArgValue::VariableReserved(n)  } |
-        variable_p          => { |n| ArgValue::Variable(n)          } |
+        length_p |
+        indexed_variable_p |
         roll_digit_p        => { |n| ArgValue::Number(n)            }
-    )) 
+    ))
 }
 
 
@@ -769,13 +1176,53 @@ pub fn roll_modifier_pos_p(input: CompleteByteSlice) -> IResult<CompleteByteSlic
     )
 }
 
-/// Matches valid modifier inputs
+/// Matches valid modifier inputs, including multiplied or divided values (e.g. @me.level * 2)
 pub fn roll_modifier_var_p(input: CompleteByteSlice) -> IResult<CompleteByteSlice, ArgValue> {
-    alt!(input,
-        variable_reserved_p => { |n| ArgValue::VariableReserved(n)  } |
-        variable_p          => { |n| ArgValue::Variable(n)          } |
-        roll_digit_p        => { |n| ArgValue::Number(n)            } |
-        token_p             => { |n| ArgValue::Token(n)             }
+    map!(input, expr_term_p, Expr::into_value)
+}
+
+/// Matches another group of dice taken from the roll (e.g. - 1d4)
+pub fn roll_group_neg_p(input: CompleteByteSlice) -> IResult<CompleteByteSlice, Arg> {
+    do_parse!(input,
+        group: ws!(preceded!(tag!("-"), roll_group_p)) >>
+        (Arg::Roll(RollArg::GroupNeg(group)))
+    )
+}
+
+/// Matches another group of dice added to the roll (e.g. + 2d6kh1)
+pub fn roll_group_pos_p(input: CompleteByteSlice) -> IResult<CompleteByteSlice, Arg> {
+    do_parse!(input,
+        group: ws!(preceded!(tag!("+"), roll_group_p)) >>
+        (Arg::Roll(RollArg::GroupPos(group)))
+    )
+}
+
+/// Matches a group of dice with its own flags, a die is required so plain modifiers
+/// are not mistaken for groups
+pub fn roll_group_p(input: CompleteByteSlice) -> IResult<CompleteByteSlice, Vec<RollArg>> {
+    do_parse!(input,
+        count: opt!(roll_num_p) >>
+        die: roll_die_p >>
+        flags: many0!(ws!(alt_complete!(
+            roll_flag_cf_p      |
+            roll_flag_cs_p      |
+            roll_flag_e_p       |
+            roll_flag_gl_p      |
+            roll_flag_gt_p      |
+            roll_flag_gte_p     |
+            roll_flag_h_p       |
+            roll_flag_l_p       |
+            roll_flag_lt_p      |
+            roll_flag_lte_p     |
+            roll_flag_max_p     |
+            roll_flag_min_p     |
+            roll_flag_ro_p      |
+            roll_flag_rr_p
+        ))) >>
+        (count.into_iter().chain(Some(die)).chain(flags).filter_map(|arg| match arg {
+            Arg::Roll(roll) => Some(roll),
+            _ => None,
+        }).collect())
     )
 }
 
@@ -802,10 +1249,7 @@ pub fn roll_die_p(input: CompleteByteSlice) -> IResult<CompleteByteSlice, Arg> {
 
 /// Matches arguments in quotes ('')
 pub fn single_quoted_p(input: CompleteByteSlice) -> IResult<CompleteByteSlice, String> {
-    do_parse!(input,
-        word: delimited!(tag!("'"),take_until!("'"), tag!("'")) >>
-        (String::from_utf8(word.to_vec()).unwrap())
-    )
+    delimited!(input, tag!("'"), map!(opt!(call!(escaped_p, b"'")), Option::unwrap_or_default), tag!("'"))
 }
 
 /// Matches a passed or ignored result
@@ -821,19 +1265,39 @@ pub fn step_result_p(input: CompleteByteSlice) -> IResult<CompleteByteSlice, Ste
 pub fn token_p(input: CompleteByteSlice) -> IResult<CompleteByteSlice, TokenArg> {
     // @todo match that we cannot start with a digit
     do_parse!(input,
+        start: mark_p >>
         name: ws!(preceded!(tag!("@"), token_name_p)) >>
-        attribute: switch!(opt!(complete!(preceded!(tag!("."), variable_name_p))),
-            Some(a) => value!(Some(String::from_utf8(a.to_vec()).unwrap())) |
-            _ => value!(None)
-        ) >>
+        attribute: opt!(complete!(preceded!(tag!("."), token_attribute_p))) >>
         macro_name: switch!(opt!(complete!(preceded!(tag!("->"), variable_name_p))),
             Some(a) => value!(Some(String::from_utf8(a.to_vec()).unwrap())) |
             _ => value!(None)
         ) >>
-        (TokenArg { name, attribute, macro_name })
+        end: mark_p >>
+        (TokenArg { name, attribute, macro_name, span: Some(span(start, end)) })
+    )
+}
+
+/// Matches a token attribute, including nested paths (e.g. spells.0.level)
+pub fn token_attribute_p(input: CompleteByteSlice) -> IResult<CompleteByteSlice, String> {
+    do_parse!(input,
+        first: map_res!(variable_name_p, utf8) >>
+        rest: many0!(complete!(preceded!(tag!("."), map_res!(variable_name_p, utf8)))) >>
+        ({
+            let mut attribute = first.to_string();
+            for part in rest {
+                attribute.push('.');
+                attribute.push_str(part);
+            }
+            attribute
+        })
     )
 }
 
+/// Read matched input as text
+fn utf8<'a>(input: CompleteByteSlice<'a>) -> Result<&'a str, str::Utf8Error> {
+    str::from_utf8(input.0)
+}
+
 /// Parse a valid string for names
 pub fn token_name_p(input: CompleteByteSlice) -> IResult<CompleteByteSlice, String> {
     do_parse!(input,
@@ -865,12 +1329,11 @@ pub fn variable_p(input: CompleteByteSlice) -> IResult<CompleteByteSlice, String
 /// Matches reserved variables (digits only)
 pub fn variable_reserved_p(input: CompleteByteSlice) -> IResult<CompleteByteSlice, i16> {
     do_parse!(input,
-        var: ws!(preceded!(tag!("$"), alt_complete!(
-            delimited!(tag!("{"), digit, tag!("}")) |
-            digit
+        num: ws!(preceded!(tag!("$"), alt_complete!(
+            delimited!(tag!("{"), digits_p, tag!("}")) |
+            digits_p
         ))) >>
-        num: value!(String::from_utf8(var.to_vec()).unwrap()) >>
-        (num.parse::<i16>().unwrap())
+        (num)
     )
 }
 
@@ -901,3 +1364,374 @@ pub fn variable_word_p(input: CompleteByteSlice) -> IResult<CompleteByteSlice, S
     // };
     // err.to_string()
 // }
+
+/// Parse a complete macro, describing where and why it could not be parsed
+pub fn parse_program(input: &[u8]) -> Result<Program, ParseError> {
+    let partial = parse_recovering(input);
+    match partial.errors.into_iter().next() {
+        Some(error) => Err(error),
+        None => Ok(partial.program),
+    }
+}
+
+/// Parse a complete macro, failing at the first step that can never run within the limits
+///
+/// The error carries the limit and its maximum (see `ParseError::limit`).
+pub fn parse_program_limited(input: &[u8], limits: &ExecutionLimits) -> Result<Program, ParseError> {
+    let program = parse_program(input)?;
+    match limits.check_steps(&program.steps) {
+        Ok(()) => Ok(program),
+        Err((index, error)) => {
+            let offset = program.steps[index].span.map_or(0, |span| span.start);
+            let mut parse_error = ParseError::new(input, offset, None, vec![], error.to_string());
+            if let ExecutionError::LimitExceeded(ref limit, max) = error {
+                parse_error.limit = Some((limit.clone(), max));
+            }
+            Err(parse_error)
+        },
+    }
+}
+
+/// Parse as much of a macro as possible, skipping past errors
+///
+/// After an error the parser skips to the next command, ">>" or "|" and carries on, so
+/// every problem with the macro is reported at once. Steps that could not be parsed are
+/// left out of the program.
+pub fn parse_recovering(input: &[u8]) -> PartialProgram {
+    let uncommented = uncomment(input);
+    let mut errors: Vec<ParseError> = uncommented.error.into_iter().collect();
+    let (mut program, _) = parse_macro(&uncommented.input, 0, false, &mut errors);
+
+    let name = skip_whitespace(&uncommented.input, 0);
+    program.docs = uncommented.docs.into_iter().filter(|&(at, _)| at < name).map(|(_, doc)| doc).collect();
+    PartialProgram {
+        program,
+        errors: located(input, errors),
+    }
+}
+
+/// Parse every macro of a library (e.g. "#attack !r 1d20 #heal !r 2d4")
+///
+/// Each macro runs until the next macro name, doc comments belong to the macro named after
+/// them. Fails on the first parse error, or when two macros share a name.
+pub fn parse_library(input: &[u8]) -> Result<Library, ParseError> {
+    let uncommented = uncomment(input);
+    let mut errors: Vec<ParseError> = uncommented.error.into_iter().collect();
+    let library = parse_macros(&uncommented.input, uncommented.docs, &mut errors);
+    match located(input, errors).into_iter().next() {
+        Some(error) => Err(error),
+        None => Ok(library),
+    }
+}
+
+/// Parse macros until the first error
+fn parse_macros(input: &[u8], mut docs: Vec<(usize, String)>, errors: &mut Vec<ParseError>) -> Library {
+    let mut library = Library::default();
+    let mut starts: Vec<usize> = vec![];
+    let mut offset = skip_whitespace(input, 0);
+    while offset < input.len() {
+        let (mut program, end) = parse_macro(input, offset, true, errors);
+        if !errors.is_empty() {
+            break;
+        }
+
+        if let Some(index) = library.programs.iter().position(|other| other.name == program.name) {
+            let line = input[..starts[index]].iter().filter(|c| **c == b'\n').count() + 1;
+            let name = match program.name {
+                MacroOp::Name(ref name) => name.clone(),
+                _ => String::new(),
+            };
+            errors.push(ParseError::new(input, offset, Some(Construct::Name), vec![],
+                format!("duplicate macro name #{}, first defined on line {}", name, line)));
+            break;
+        }
+
+        let later = docs.split_off(docs.iter().take_while(|&&(at, _)| at < offset).count());
+        program.docs = docs.into_iter().map(|(_, doc)| doc).collect();
+        docs = later;
+
+        starts.push(offset);
+        library.programs.push(program);
+        offset = skip_whitespace(input, end);
+    }
+    library
+}
+
+/// Parse a step of a library, stopping at the name of the next macro
+pub fn library_step_p(input: CompleteByteSlice) -> IResult<CompleteByteSlice, Step> {
+    preceded!(input, not!(name_p), parse_step_p)
+}
+
+/// Parse a macro from `offset`, returning it with the offset parsing stopped at
+///
+/// In a library the macro ends at the next macro name, otherwise names are parsed as steps.
+fn parse_macro(input: &[u8], offset: usize, library: bool, errors: &mut Vec<ParseError>) -> (Program, usize) {
+    source(input);
+    let (mut offset, name) = match name_p(CompleteByteSlice(&input[offset..])) {
+        Ok((rest, name)) => (input.len() - rest.len(), name),
+        Err(_) => {
+            let at = skip_whitespace(input, offset);
+            errors.push(ParseError::new(input, at, Some(Construct::Name), vec!["#name".to_string()],
+                "expected a macro name starting with #".to_string()));
+            (next_boundary(input, at).unwrap_or(input.len()), MacroOp::Name(String::new()))
+        },
+    };
+
+    let mut steps: Vec<Step> = vec![];
+    loop {
+        let rest = CompleteByteSlice(&input[offset..]);
+        let parsed = if library { many0!(rest, library_step_p) } else { many0!(rest, parse_step_p) };
+        if let Ok((rest, mut parsed)) = parsed {
+            offset = input.len() - rest.len();
+            steps.append(&mut parsed);
+        }
+
+        // anything left over is input no step could parse
+        offset = skip_whitespace(input, offset);
+        if offset >= input.len() || (library && name_p(CompleteByteSlice(&input[offset..])).is_ok()) {
+            break;
+        }
+        let next = next_boundary(input, offset + 1);
+        let previous = steps.last().map(|step| step.op.clone());
+        errors.push(trailing_error(input, offset, next.unwrap_or(input.len()), previous.as_ref()));
+
+        // input left straight after a step (e.g. an unterminated string) is part of that step,
+        // so it did not parse and only the error is kept
+        let unfinished = steps.last().and_then(|step| step.span).is_some_and(|span| {
+            input[offset] != b'!' && input.get(span.end..offset).is_some_and(|gap| gap.iter().all(|c| c.is_ascii_whitespace()))
+        });
+        if unfinished {
+            steps.pop();
+        }
+        offset = match next {
+            Some(next) => next,
+            None => break,
+        };
+    }
+
+    (Program { name, steps, docs: vec![] }, offset)
+}
+
+fn skip_whitespace(input: &[u8], offset: usize) -> usize {
+    offset + input[offset..].iter().take_while(|c| c.is_ascii_whitespace()).count()
+}
+
+/// A macro with its comments blanked out, so offsets still point into the original input
+struct Uncommented {
+    input: Vec<u8>,
+    /// Doc comments with the offset they start at
+    docs: Vec<(usize, String)>,
+    /// A block comment that is never closed
+    error: Option<ParseError>,
+}
+
+/// Blank out // line comments and /* */ block comments, skipping over quoted strings
+fn uncomment(input: &[u8]) -> Uncommented {
+    let mut uncommented = Uncommented {
+        input: input.to_vec(),
+        docs: vec![],
+        error: None,
+    };
+    let mut i = 0;
+    while i < input.len() {
+        let end = match input[i] {
+            quote @ b'\'' | quote @ b'"' => {
+                match closing_quote(&input[i + 1..], quote) {
+                    Some(end) => i += end + 1,
+                    None => break,
+                }
+                None
+            },
+            b'/' if input[i..].starts_with(b"//") => {
+                let end = input[i..].iter().position(|c| *c == b'\n').map_or(input.len(), |end| i + end);
+                if input[i..].starts_with(b"///") {
+                    uncommented.docs.push((i, String::from_utf8_lossy(&input[i + 3..end]).trim().to_string()));
+                }
+                Some(end)
+            },
+            b'/' if input[i..].starts_with(b"/*") => {
+                match input[i + 2..].windows(2).position(|c| c == b"*/") {
+                    Some(end) => {
+                        // "/**/" is an empty comment, not a doc comment
+                        if input[i..].starts_with(b"/**") && end > 0 {
+                            uncommented.docs.push((i, block_doc(&input[i + 3..i + 2 + end])));
+                        }
+                        Some(i + end + 4)
+                    },
+                    None => {
+                        uncommented.error = Some(ParseError::new(input, i, None, vec!["*/".to_string()],
+                            "unterminated block comment".to_string()));
+                        Some(input.len())
+                    },
+                }
+            },
+            _ => None,
+        };
+
+        match end {
+            Some(end) => {
+                for c in &mut uncommented.input[i..end] {
+                    if *c != b'\n' {
+                        *c = b' ';
+                    }
+                }
+                i = end;
+            },
+            None => i += 1,
+        }
+    }
+    uncommented
+}
+
+/// The text of a /** */ comment, without the stars starting each line
+fn block_doc(text: &[u8]) -> String {
+    let text = String::from_utf8_lossy(text);
+    let lines: Vec<&str> = text.lines().map(|line| line.trim().trim_start_matches('*').trim()).collect();
+    lines.join("\n").trim().to_string()
+}
+
+/// Work out lines and columns from the original input, comments may not be one byte per character
+fn located(input: &[u8], mut errors: Vec<ParseError>) -> Vec<ParseError> {
+    errors.sort_by_key(|error| error.offset);
+    errors.into_iter()
+        .map(|error| ParseError::new(input, error.offset, error.construct, error.expected, error.message))
+        .collect()
+}
+
+/// Find where parsing can carry on after an error: the next command or macro name, or the
+/// input after ">>" or "|"
+fn next_boundary(input: &[u8], from: usize) -> Option<usize> {
+    let mut i = from;
+    while i < input.len() {
+        match input[i] {
+            quote @ b'\'' | quote @ b'"' => {
+                // skip over closed strings, they may hold anything
+                if let Some(end) = closing_quote(&input[i + 1..], quote) {
+                    i += end + 1;
+                }
+            },
+            b'!' if command_p(CompleteByteSlice(&input[i..])).is_ok() => return Some(i),
+            b'#' if i > 0 && input[i - 1].is_ascii_whitespace() && name_p(CompleteByteSlice(&input[i..])).is_ok() => return Some(i),
+            b'>' if input[i..].starts_with(b">>") => return Some(i + 2),
+            b'|' => return Some(i + 1),
+            _ => {},
+        }
+        i += 1;
+    }
+    None
+}
+
+/// Commands a step can start with
+const COMMANDS: [&str; 14] = [
+    "!case", "!each", "!exit", "!for", "!hroll", "!input", "!prompt", "!roll", "!say", "!target", "!template", "!test",
+    "!whisper", "!wroll",
+];
+
+/// Describe input from `offset` to `end` that could not be parsed, `previous` is the last
+/// step that was parsed
+fn trailing_error(input: &[u8], offset: usize, end: usize, previous: Option<&MacroOp>) -> ParseError {
+    let rest = &input[offset..end];
+    let construct = match previous {
+        Some(&MacroOp::Case) | Some(&MacroOp::Prompt) => Some(Construct::PromptOptions),
+        Some(&MacroOp::Input) => Some(Construct::InputMessage),
+        _ => None,
+    };
+    let error = |at: usize, construct: Option<Construct>, expected: &[&str], message: String| {
+        let expected = expected.iter().map(|e| e.to_string()).collect();
+        ParseError::new(input, offset + at, construct, expected, message)
+    };
+
+    if let Some((at, quote)) = unterminated_quote(rest) {
+        let kind = if quote == b'\'' { "single" } else { "double" };
+        let quote = (quote as char).to_string();
+        return error(at, construct, &[&quote], format!("unterminated {}-quoted string", kind));
+    }
+
+    let word = String::from_utf8_lossy(rest).split_whitespace().next().unwrap_or_default().to_string();
+    if let Some(at) = too_large_number(word.as_bytes()) {
+        return error(at, None, &[], format!("number is too large (the largest is {})", i32::MAX));
+    }
+    match rest[0] {
+        b'!' if command_p(CompleteByteSlice(rest)).is_err() => {
+            error(0, Some(Construct::Command), &COMMANDS, format!("unrecognized command {}", word))
+        },
+        b'[' if !rest.contains(&b']') => {
+            error(rest.len(), Some(Construct::PromptOptions), &["]"], "unterminated prompt options".to_string())
+        },
+        b'=' | b'<' | b'>' | b'?' | b':' if unterminated_block(rest).is_some() => {
+            let at = unterminated_block(rest).unwrap_or_default();
+            error(at, Some(Construct::Conditional), &["}"], "unterminated block".to_string())
+        },
+        b'=' | b'<' | b'>' | b'?' | b':' => {
+            error(0, Some(Construct::Conditional), &["$variable", "@token", "number"], "incomplete conditional statement".to_string())
+        },
+        _ if previous == Some(&MacroOp::Loop) && unterminated_block(rest).is_some() => {
+            let at = unterminated_block(rest).unwrap_or_default();
+            error(at, None, &["}"], "unterminated block".to_string())
+        },
+        _ if construct.is_some() => error(0, construct, &["'", "\""], "expected a quoted message".to_string()),
+        _ => error(0, None, &["!command", "$variable", "@token"], format!("unexpected \"{}\"", word)),
+    }
+}
+
+/// Find the first brace that is never closed, skipping over quoted strings
+fn unterminated_block(input: &[u8]) -> Option<usize> {
+    let mut open = vec![];
+    let mut i = 0;
+    while i < input.len() {
+        match input[i] {
+            quote @ b'\'' | quote @ b'"' => match closing_quote(&input[i + 1..], quote) {
+                Some(end) => i += end + 1,
+                None => break,
+            },
+            b'{' => open.push(i),
+            b'}' => { open.pop(); },
+            _ => {},
+        }
+        i += 1;
+    }
+    open.first().cloned()
+}
+
+/// Find the first run of digits too large to be a number
+fn too_large_number(input: &[u8]) -> Option<usize> {
+    let mut i = 0;
+    while i < input.len() {
+        let digits = input[i..].iter().take_while(|c| c.is_ascii_digit()).count();
+        if digits > 0 && str::from_utf8(&input[i..i + digits]).ok()?.parse::<i32>().is_err() {
+            return Some(i);
+        }
+        i += digits.max(1);
+    }
+    None
+}
+
+/// Find the first quote that is never closed, skipping over quoted strings
+fn unterminated_quote(input: &[u8]) -> Option<(usize, u8)> {
+    let mut i = 0;
+    while i < input.len() {
+        let quote = input[i];
+        if quote == b'\'' || quote == b'"' {
+            match closing_quote(&input[i + 1..], quote) {
+                Some(end) => i += end + 1,
+                None => return Some((i, quote)),
+            }
+        }
+        i += 1;
+    }
+    None
+}
+
+/// Find the quote closing a string, skipping escaped quotes (e.g. \")
+fn closing_quote(input: &[u8], quote: u8) -> Option<usize> {
+    let mut i = 0;
+    while i < input.len() {
+        match input[i] {
+            b'\\' => i += 1,
+            c if c == quote => return Some(i),
+            _ => {},
+        }
+        i += 1;
+    }
+    None
+}
@@ -0,0 +1,81 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A source of random numbers for rolling dice
+pub trait RandomSource {
+    /// The next random number
+    fn next_u64(&mut self) -> u64;
+
+    /// Pick a number from zero up to (but not including) `max` without bias
+    fn below(&mut self, max: u64) -> u64 {
+        let zone = u64::MAX - (u64::MAX % max);
+        loop {
+            let n = self.next_u64();
+            if n < zone {
+                return n % max;
+            }
+        }
+    }
+}
+
+/// Random numbers seeded by the operating system (default)
+pub struct OsRandom {
+    inner: SeededRandom,
+}
+
+impl Default for OsRandom {
+    fn default() -> OsRandom {
+        OsRandom::new()
+    }
+}
+
+impl OsRandom {
+    pub fn new() -> OsRandom {
+        let mut hasher = RandomState::new().build_hasher();
+        if let Ok(time) = SystemTime::now().duration_since(UNIX_EPOCH) {
+            hasher.write_u64(time.as_secs());
+            hasher.write_u32(time.subsec_nanos());
+        }
+        OsRandom {
+            inner: SeededRandom::new(hasher.finish()),
+        }
+    }
+}
+
+impl RandomSource for OsRandom {
+    fn next_u64(&mut self) -> u64 {
+        self.inner.next_u64()
+    }
+}
+
+/// Random numbers that are the same every time for the same seed (SplitMix64)
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SeededRandom {
+    seed: u64,
+    state: u64,
+}
+
+impl SeededRandom {
+    pub fn new(seed: u64) -> SeededRandom {
+        SeededRandom {
+            seed,
+            state: seed,
+        }
+    }
+
+    /// The seed the generator was created with
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+}
+
+impl RandomSource for SeededRandom {
+    fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+}
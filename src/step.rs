@@ -1,10 +1,22 @@
-use arg::{Arg, MacroOp};
+use arg::{Arg, MacroOp, Span};
 
-#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Step {
     pub args: Vec<Arg>,
     pub op: MacroOp,
     pub result: StepResult,
+    /// Where the step was parsed from, without its ">>" or "|"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub span: Option<Span>,
+    /// Where each argument was parsed from, in the same order as `args`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub arg_spans: Vec<Span>,
+}
+
+impl PartialEq for Step {
+    fn eq(&self, other: &Step) -> bool {
+        self.args == other.args && self.op == other.op && self.result == other.result
+    }
 }
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
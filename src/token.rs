@@ -0,0 +1,122 @@
+use arg::ArgValue;
+use error::ExecutionError;
+use std::collections::HashMap;
+
+/// A token macros can read from and write to
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Token {
+    pub attributes: HashMap<String, ArgValue>,
+}
+
+/// Where the interpreter finds tokens and their attributes
+///
+/// Attributes are paths separated by dots, numbers index into arrays
+/// (e.g. "spells.0.level" in @me.spells.0.level).
+pub trait TokenStore {
+    /// Check if a token exists
+    fn has_token(&self, name: &str) -> bool;
+
+    /// Read the attribute of a token
+    fn get(&self, name: &str, attribute: &str) -> Result<ArgValue, ExecutionError>;
+
+    /// Write the attribute of a token, creating the last part of the path if needed
+    fn set(&mut self, name: &str, attribute: &str, value: ArgValue) -> Result<(), ExecutionError>;
+}
+
+/// Tokens kept in memory (default)
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct MemoryTokenStore {
+    pub tokens: HashMap<String, Token>,
+}
+
+impl MemoryTokenStore {
+    pub fn new() -> MemoryTokenStore {
+        MemoryTokenStore::default()
+    }
+
+    /// Add or replace a token
+    pub fn insert(&mut self, name: &str, token: Token) {
+        self.tokens.insert(name.to_string(), token);
+    }
+}
+
+impl TokenStore for MemoryTokenStore {
+    fn has_token(&self, name: &str) -> bool {
+        self.tokens.contains_key(name)
+    }
+
+    fn get(&self, name: &str, attribute: &str) -> Result<ArgValue, ExecutionError> {
+        let token = self.tokens.get(name).ok_or_else(|| token_not_found(name))?;
+        let not_found = || attribute_not_found(name, attribute);
+
+        let mut parts = attribute.split('.');
+        let first = parts.next().unwrap_or_default();
+        let mut value = token.attributes.get(first).ok_or_else(not_found)?;
+        for part in parts {
+            value = child(value, part).ok_or_else(not_found)?;
+        }
+        Ok(value.clone())
+    }
+
+    fn set(&mut self, name: &str, attribute: &str, value: ArgValue) -> Result<(), ExecutionError> {
+        let token = self.tokens.get_mut(name).ok_or_else(|| token_not_found(name))?;
+        let not_found = || attribute_not_found(name, attribute);
+
+        let parts: Vec<&str> = attribute.split('.').collect();
+        let (last, parents) = match parts.split_last() {
+            Some((last, parents)) if !parents.is_empty() => (last, parents),
+            _ => {
+                token.attributes.insert(attribute.to_string(), value);
+                return Ok(());
+            },
+        };
+
+        let mut current = token.attributes.get_mut(parents[0]).ok_or_else(not_found)?;
+        for part in &parents[1..] {
+            current = child_mut(current, part).ok_or_else(not_found)?;
+        }
+
+        match *current {
+            ArgValue::Object(ref mut values) => {
+                values.insert(last.to_string(), value);
+                Ok(())
+            },
+            ArgValue::Array(ref mut values) => {
+                let index = last.parse::<usize>().map_err(|_| not_found())?;
+                if index < values.len() {
+                    values[index] = value;
+                } else if index == values.len() {
+                    values.push(value);
+                } else {
+                    return Err(not_found());
+                }
+                Ok(())
+            },
+            _ => Err(not_found()),
+        }
+    }
+}
+
+fn child<'a>(value: &'a ArgValue, part: &str) -> Option<&'a ArgValue> {
+    match *value {
+        ArgValue::Array(ref values) => part.parse::<usize>().ok().and_then(|index| values.get(index)),
+        ArgValue::Object(ref values) => values.get(part),
+        _ => None,
+    }
+}
+
+fn child_mut<'a>(value: &'a mut ArgValue, part: &str) -> Option<&'a mut ArgValue> {
+    match *value {
+        ArgValue::Array(ref mut values) => part.parse::<usize>().ok().and_then(move |index| values.get_mut(index)),
+        ArgValue::Object(ref mut values) => values.get_mut(part),
+        _ => None,
+    }
+}
+
+fn token_not_found(name: &str) -> ExecutionError {
+    ExecutionError::TokenNotFound(format!("@{}", name))
+}
+
+fn attribute_not_found(name: &str, attribute: &str) -> ExecutionError {
+    ExecutionError::AttributeNotFound(format!("@{}.{}", name, attribute))
+}
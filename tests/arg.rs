@@ -8,6 +8,7 @@ fn token_arg_to_string() {
         name: "test".to_string(),
         attribute: Some("test_attr".to_string()),
         macro_name: None,
+        span: None,
     };
     assert_eq!(token.to_string(), "@test.test_attr".to_string());
 
@@ -15,6 +16,7 @@ fn token_arg_to_string() {
         name: "test".to_string(),
         attribute: None,
         macro_name: Some("macro_name".to_string()),
+        span: None,
     };
     assert_eq!(token.to_string(), "@test->macro_name".to_string());
 
@@ -22,6 +24,7 @@ fn token_arg_to_string() {
         name: "test_token".to_string(),
         attribute: None,
         macro_name: None,
+        span: None,
     };
     assert_eq!(token.to_string(), "@test_token".to_string());
 }
@@ -0,0 +1,200 @@
+extern crate ttml;
+extern crate nom;
+
+use nom::types::CompleteByteSlice;
+use ttml::arg::*;
+use ttml::dice::*;
+use ttml::error::ExecutionError;
+use ttml::limits::Limit;
+use ttml::parser::parse_roll_p;
+use ttml::random::*;
+
+/// Random numbers picked in advance, in order
+struct Rolls(Vec<u64>);
+
+impl RandomSource for Rolls {
+    fn next_u64(&mut self) -> u64 {
+        self.0.remove(0)
+    }
+}
+
+fn dice(input: &str) -> Dice {
+    let (_, args) = parse_roll_p(CompleteByteSlice(input.as_bytes())).unwrap();
+    Dice::from_args(&args, |value| Ok(value.clone())).unwrap()
+}
+
+#[test]
+fn it_builds_dice_from_roll_arguments() {
+    let result = dice("2d20kh1+5-2 'Attack'");
+    assert_eq!(result.count, 2);
+    assert_eq!(result.die, Some(Die::Sides(20)));
+    assert_eq!(result.keep_highest, Some(1));
+    assert_eq!(result.modifier, 3);
+    assert_eq!(result.comment, Some("Attack".to_string()));
+    assert_eq!(result.equation(), "2d20kh1+3".to_string());
+
+    assert_eq!(dice("adv").equation(), "2d20kh1".to_string());
+    assert_eq!(dice("dis").equation(), "2d20kl1".to_string());
+    assert_eq!(dice("1d8ro<2").equation(), "1d8ro<2".to_string());
+    assert_eq!(dice("4d[-1,0,1]").equation(), "4d[-1,0,1]".to_string());
+}
+
+#[test]
+fn it_rejects_invalid_dice() {
+    let (_, args) = parse_roll_p(CompleteByteSlice(b"1d0")).unwrap();
+    assert!(Dice::from_args(&args, |value| Ok(value.clone())).is_err());
+
+    let (_, args) = parse_roll_p(CompleteByteSlice(b"1d$foo")).unwrap();
+    let result = Dice::from_args(&args, |_| Ok(ArgValue::Text("foo".to_string())));
+    assert_eq!(result, Err(ExecutionError::InvalidRoll("\"foo\" is not a number".to_string())));
+}
+
+#[test]
+fn it_rolls_within_bounds() {
+    let mut random = OsRandom::new();
+    for _ in 0..100 {
+        let result = dice("3d6+1").roll(&mut random).unwrap();
+        assert_eq!(result.dice.len(), 3);
+        assert!(result.value >= 4 && result.value <= 19);
+        assert_eq!(result.value, result.raw_value + 1);
+    }
+}
+
+#[test]
+fn it_rolls_with_flags() {
+    let mut random = OsRandom::new();
+    for _ in 0..100 {
+        // keep highest/lowest
+        let result = dice("4d6kh3").roll(&mut random).unwrap();
+        assert_eq!(result.kept().len(), 3);
+        let result = dice("4d6kl1").roll(&mut random).unwrap();
+        assert_eq!(result.kept().len(), 1);
+
+        // keep above/below a threshold
+        let result = dice("10d6gt3").roll(&mut random).unwrap();
+        assert!(result.kept().iter().all(|face| *face > 3));
+        let result = dice("10d6lte2").roll(&mut random).unwrap();
+        assert!(result.kept().iter().all(|face| *face <= 2));
+
+        // minimum and maximum
+        assert_eq!(dice("1d6min6").roll(&mut random).unwrap().value, 6);
+        assert_eq!(dice("1d6max1").roll(&mut random).unwrap().value, 1);
+
+        // rerolls
+        assert_eq!(dice("1d2rr2").roll(&mut random).unwrap().value, 2);
+        let result = dice("1d2ro>1").roll(&mut random).unwrap();
+        assert!(result.value == 1 || result.value == 2);
+
+        // custom sides
+        assert_eq!(dice("2d[5,5]").roll(&mut random).unwrap().value, 10);
+
+        // flat numbers
+        assert_eq!(dice("5+2").roll(&mut random).unwrap().value, 7);
+    }
+
+    // dice that never stop rerolling or exploding fail at the limit
+    assert_eq!(
+        dice("1d1e1").roll(&mut random).unwrap_err(),
+        ExecutionError::LimitExceeded(Limit::Rerolls, MAX_REROLLS)
+    );
+    assert_eq!(
+        dice("1d[1,1]rr<2").roll(&mut random).unwrap_err(),
+        ExecutionError::LimitExceeded(Limit::Rerolls, MAX_REROLLS)
+    );
+    let mut limited = dice("2d6 + 1d[6]e6");
+    limited.limit_rerolls(3);
+    assert_eq!(
+        limited.roll(&mut random).unwrap_err(),
+        ExecutionError::LimitExceeded(Limit::Rerolls, 3)
+    );
+}
+
+#[test]
+fn it_breaks_down_every_die() {
+    let mut random = OsRandom::new();
+
+    // dropped dice
+    let result = dice("2d20kh1").roll(&mut random).unwrap();
+    let kept: Vec<&DieRoll> = result.dice.iter().filter(|die| die.dropped.is_none()).collect();
+    let dropped: Vec<&DieRoll> = result.dice.iter().filter(|die| die.dropped.is_some()).collect();
+    assert_eq!(kept.len(), 1);
+    assert_eq!(dropped[0].dropped, Some(Dropped::KeepHighest));
+    assert!(kept[0].value >= dropped[0].value);
+    assert_eq!(result.to_string(), format!("2d20kh1: [{}]", result.dice.iter().map(|die| {
+        if die.dropped.is_some() { format!("~~{}~~", die.value) } else { die.value.to_string() }
+    }).collect::<Vec<String>>().join(", ")));
+
+    let result = dice("3d6gt6").roll(&mut random).unwrap();
+    assert!(result.dice.iter().all(|die| die.dropped == Some(Dropped::Threshold)));
+    assert_eq!(result.value, 0);
+
+    // clamped dice
+    let result = dice("1d1min3").roll(&mut random).unwrap();
+    assert_eq!(result.dice[0].rolled, 1);
+    assert_eq!(result.dice[0].value, 3);
+    assert_eq!(result.dice[0].clamped, Some(Clamp::Min));
+    let result = dice("1d[9]max4").roll(&mut random).unwrap();
+    assert_eq!(result.dice[0].clamped, Some(Clamp::Max));
+    assert_eq!(result.to_string(), "1d[9]max4: [4]".to_string());
+
+    // rerolled dice
+    let result = dice("1d[1,1,2]rr2").roll(&mut random).unwrap();
+    assert!(result.dice[0].rerolls.iter().all(|face| *face == 1));
+    assert_eq!(result.dice[0].value, 2);
+
+    // exploded dice
+    let result = dice("2d6e6").roll(&mut Rolls(vec![5, 5, 0, 0])).unwrap();
+    assert_eq!(result.dice.len(), 4);
+    assert!(result.dice[0].is_exploded);
+    assert!(!result.dice[0].is_explosion);
+    assert!(result.dice[1].is_explosion);
+    assert_eq!(result.to_string(), "2d6e6: [6!, 6!, 1, 1]".to_string());
+}
+
+#[test]
+fn it_rolls_groups_of_dice() {
+    let result = dice("1d20kh1 + 2d6 - 1d4 + 3");
+    assert_eq!(result.count, 1);
+    assert_eq!(result.groups.len(), 2);
+    assert_eq!(result.groups[1].dice.die, Some(Die::Sides(4)));
+    assert!(result.groups[1].negative);
+    assert_eq!(result.equation(), "1d20kh1+2d6-1d4+3".to_string());
+
+    let mut random = OsRandom::new();
+    for _ in 0..100 {
+        let result = dice("1d1 + 2d[5] - 1d[2]min2 + 3").roll(&mut random).unwrap();
+        assert_eq!(result.raw_value, 9);
+        assert_eq!(result.value, 12);
+        assert_eq!(result.to_string(), "1d1+2d[5]-1d[2]min2+3: [1] + [5, 5] - [2]".to_string());
+    }
+}
+
+#[test]
+fn it_counts_successes() {
+    assert_eq!(dice("10d10cs>=8cf1").equation(), "10d10cs>=8cf<=1".to_string());
+
+    let mut random = OsRandom::new();
+    for _ in 0..100 {
+        let result = dice("10d10cs>=8").roll(&mut random).unwrap();
+        let successes = result.dice.iter().filter(|die| die.value >= 8).count() as i32;
+        assert_eq!(result.successes, Some(successes));
+        assert_eq!(result.value, successes);
+    }
+
+    // failures cancel successes, with none left over it is a botch
+    let result = dice("2d[8]cs8 + 1").roll(&mut random).unwrap();
+    assert_eq!((result.successes, result.value, result.is_botch), (Some(2), 3, false));
+    let result = dice("3d[1]cs>=8cf1").roll(&mut random).unwrap();
+    assert_eq!((result.successes, result.failures, result.is_botch), (Some(-3), 3, true));
+    let result = dice("2d[1,9]rr<2cs>=8cf1").roll(&mut random).unwrap();
+    assert_eq!((result.successes, result.is_botch), (Some(2), false));
+
+    // glitches when more than half the dice match
+    let result = dice("3d[1]gl1").roll(&mut random).unwrap();
+    assert!(result.is_glitch);
+    assert_eq!(result.successes, None);
+    let result = dice("2d[1]cs5gl1 + 2d[6]").roll(&mut random).unwrap();
+    assert!(result.is_glitch);
+    let result = dice("4d[1]gt1gl1").roll(&mut random).unwrap();
+    assert!(!result.is_glitch);
+}
@@ -0,0 +1,145 @@
+extern crate ttml;
+extern crate nom;
+
+use nom::types::CompleteByteSlice;
+use ttml::distribution::*;
+use ttml::error::ExecutionError;
+use ttml::limits::{ExecutionLimits, Limit};
+use ttml::parser::parse_roll_p;
+
+fn analyze_roll(input: &str) -> Result<Distribution, ExecutionError> {
+    let (_, args) = parse_roll_p(CompleteByteSlice(input.as_bytes())).unwrap();
+    analyze(&args)
+}
+
+fn close(left: f64, right: f64) -> bool {
+    (left - right).abs() < 1e-9
+}
+
+#[test]
+fn it_analyzes_simple_rolls() {
+    let distribution = analyze_roll("3d6").unwrap();
+    assert_eq!(distribution.min, 3);
+    assert_eq!(distribution.max, 18);
+    assert_eq!(distribution.outcomes.len(), 16);
+    assert!(close(distribution.mean, 10.5));
+    assert!(close(distribution.variance, 8.75));
+    assert!(close(distribution.probability(3), 1.0 / 216.0));
+    assert!(close(distribution.probability(10), 27.0 / 216.0));
+
+    let distribution = analyze_roll("1d8+2-1").unwrap();
+    assert_eq!((distribution.min, distribution.max), (2, 9));
+    assert!(close(distribution.mean, 5.5));
+}
+
+#[test]
+fn it_analyzes_kept_dice() {
+    let distribution = analyze_roll("2d20kh1+5").unwrap();
+    assert_eq!((distribution.min, distribution.max), (6, 25));
+    assert!(close(distribution.probability(25), 39.0 / 400.0));
+    assert!(close(distribution.mean, 13.825 + 5.0));
+
+    let distribution = analyze_roll("4d6kl3").unwrap();
+    assert_eq!((distribution.min, distribution.max), (3, 18));
+    assert!(close(distribution.probability(18), 1.0 / 1296.0));
+
+    let distribution = analyze_roll("4d6kh3").unwrap();
+    assert!(close(distribution.mean, 15869.0 / 1296.0));
+}
+
+#[test]
+fn it_analyzes_rerolls_and_limits() {
+    // anything under 2 is rerolled once
+    let distribution = analyze_roll("1d8ro2").unwrap();
+    assert!(close(distribution.probability(1), 1.0 / 64.0));
+    assert!(close(distribution.probability(8), 1.0 / 8.0 + 1.0 / 64.0));
+
+    // a 1 after every reroll fails the roll instead of being kept
+    let distribution = analyze_roll("1d6rr2").unwrap();
+    assert!(close(distribution.probability(1), 0.0));
+    assert!(close(distribution.probability(6), 0.2));
+    assert!(close(distribution.failure, 0.0));
+
+    let distribution = analyze_roll("1d6min3").unwrap();
+    assert!(close(distribution.probability(3), 0.5));
+
+    let distribution = analyze_roll("3d6gt4").unwrap();
+    assert_eq!((distribution.min, distribution.max), (0, 18));
+    assert!(close(distribution.probability(0), 8.0 / 27.0));
+
+    // rolls too big to work out are rejected up front
+    assert_eq!(analyze_roll("1d20000000"), Err(ExecutionError::LimitExceeded(Limit::Outcomes, 100_000)));
+    assert_eq!(analyze_roll("2000d6"), Err(ExecutionError::LimitExceeded(Limit::Dice, 1000)));
+    assert_eq!(analyze_roll("1d6rr<7"), Err(ExecutionError::LimitExceeded(Limit::Rerolls, 100)));
+
+    let limits = ExecutionLimits { max_outcomes: 20, ..ExecutionLimits::default() };
+    let (_, args) = parse_roll_p(CompleteByteSlice(b"3d6")).unwrap();
+    assert!(analyze_limited(&args, &limits).is_ok());
+    let (_, args) = parse_roll_p(CompleteByteSlice(b"1d6e6")).unwrap();
+    assert_eq!(analyze_limited(&args, &limits), Err(ExecutionError::LimitExceeded(Limit::Outcomes, 20)));
+}
+
+#[test]
+fn it_analyzes_groups_of_dice() {
+    let distribution = analyze_roll("1d6 + 1d6").unwrap();
+    assert_eq!(distribution, analyze_roll("2d6").unwrap());
+
+    let distribution = analyze_roll("1d20 - 1d4 + 1").unwrap();
+    assert_eq!((distribution.min, distribution.max), (-2, 20));
+    assert!(close(distribution.mean, 10.5 - 2.5 + 1.0));
+}
+
+#[test]
+fn it_analyzes_success_counting() {
+    let distribution = analyze_roll("3d10cs>=8").unwrap();
+    assert_eq!((distribution.min, distribution.max), (0, 3));
+    assert!(close(distribution.probability(3), 0.027));
+    assert!(close(distribution.mean, 0.9));
+
+    let distribution = analyze_roll("2d10cs>=8cf1").unwrap();
+    assert_eq!((distribution.min, distribution.max), (-2, 2));
+    assert!(close(distribution.probability(-2), 0.01));
+
+    assert!(analyze_roll("4d10kh2cs>=8").is_err());
+}
+
+#[test]
+fn it_analyzes_exploding_dice() {
+    let distribution = analyze_roll("1d6e6").unwrap();
+    assert_eq!(distribution.min, 1);
+    assert!(close(distribution.probability(6), 0.0));
+    assert!(close(distribution.probability(7), 1.0 / 36.0));
+    assert!(close(distribution.mean, 4.2));
+}
+
+#[test]
+fn it_fails_dice_that_reroll_past_the_limit() {
+    let limits = ExecutionLimits { max_rerolls: 1, ..ExecutionLimits::default() };
+    let (_, args) = parse_roll_p(CompleteByteSlice(b"1d6rr2")).unwrap();
+    let distribution = analyze_limited(&args, &limits).unwrap();
+    assert!(close(distribution.probability(1), 0.0));
+    assert!(close(distribution.probability(2), 7.0 / 36.0));
+    assert!(close(distribution.failure, 1.0 / 36.0));
+    assert!(close(distribution.mean, 4.0));
+
+    let (_, args) = parse_roll_p(CompleteByteSlice(b"1d6e6")).unwrap();
+    let distribution = analyze_limited(&args, &limits).unwrap();
+    assert!(close(distribution.probability(12), 0.0));
+    assert!(close(distribution.probability(11), 1.0 / 36.0));
+    assert!(close(distribution.failure, 1.0 / 36.0));
+
+    let limits = ExecutionLimits { max_rerolls: 0, ..ExecutionLimits::default() };
+    let distribution = analyze_limited(&args, &limits).unwrap();
+    assert_eq!((distribution.min, distribution.max), (1, 5));
+    assert!(close(distribution.failure, 1.0 / 6.0));
+    assert!(close(distribution.mean, 3.0));
+}
+
+#[test]
+fn it_rejects_rolls_it_can_not_analyze() {
+    assert_eq!(
+        analyze_roll("1d20+$1"),
+        Err(ExecutionError::InvalidRoll("Cannot analyze a roll that uses $1".to_string()))
+    );
+    assert!(analyze_roll("2d6e6kh1").is_err());
+}
@@ -0,0 +1,588 @@
+extern crate ttml;
+extern crate nom;
+extern crate serde_json;
+
+use nom::types::CompleteByteSlice;
+use std::collections::HashMap;
+use ttml::arg::*;
+use ttml::error::ExecutionError;
+use ttml::function::*;
+use ttml::host::*;
+use ttml::interpreter::*;
+use ttml::limits::Limit;
+use ttml::output::*;
+use ttml::parser::*;
+use ttml::random::SeededRandom;
+use ttml::token::*;
+
+fn program(input: &str) -> Program {
+    let (_, program) = parse_p(CompleteByteSlice(input.as_bytes())).unwrap();
+    program
+}
+
+fn interpreter() -> Interpreter {
+    let mut interpreter = Interpreter::new();
+
+    let mut attributes = HashMap::new();
+    attributes.insert("dexterity".to_string(), ArgValue::Number(3));
+    attributes.insert("hp".to_string(), ArgValue::Number(20));
+    attributes.insert("name".to_string(), ArgValue::Text("Elf".to_string()));
+    let mut tokens = MemoryTokenStore::new();
+    tokens.insert("me", Token { attributes });
+    interpreter.tokens = Box::new(tokens);
+    interpreter
+}
+
+#[test]
+fn it_executes_say_and_whisper() {
+    let mut interpreter = interpreter();
+    let output = interpreter.execute(&program("#test !say \"Hello, @me.name!\" !whisper @gm 'Psst'")).unwrap();
+
+    assert_eq!(output.messages, vec![
+        Message {
+            from: None,
+            to: None,
+            message: "Hello, Elf!".to_string(),
+            is_test: false,
+        },
+        Message {
+            from: None,
+            to: Some("gm".to_string()),
+            message: "Psst".to_string(),
+            is_test: false,
+        },
+    ]);
+    assert_eq!(output.raw.len(), 2);
+}
+
+#[test]
+fn it_executes_rolls_and_saves_results() {
+    let mut interpreter = interpreter();
+    let output = interpreter.execute(&program("#test !roll 1d1+@me.dexterity >> !hroll $1d1 !say \"Rolled $1\"")).unwrap();
+    assert_eq!(output.rolls.len(), 2);
+    assert_eq!(output.rolls[0].result.equation, "1d1+3".to_string());
+    assert_eq!(output.rolls[0].result.value, 4);
+    assert_eq!(output.rolls[1].result.equation, "4d1".to_string());
+    // hidden rolls are not shown to the room
+    assert_eq!(output.raw.len(), 2);
+    assert_eq!(output.messages[0].message, "Rolled 4".to_string());
+
+    // token attributes can hold a roll command
+    interpreter.tokens.set("me", "attack", ArgValue::Text("2d1+1".to_string())).unwrap();
+    let output = interpreter.execute(&program("#test !roll @me.attack")).unwrap();
+    assert_eq!(output.rolls[0].result.value, 3);
+}
+
+#[test]
+fn it_rolls_the_same_dice_for_the_same_seed() {
+    let initiative = program("#initiative !roll 1d20+@me.dexterity !roll 8d6");
+
+    let mut interpreter = interpreter();
+    interpreter.random = Box::new(SeededRandom::new(42));
+    let first = interpreter.execute(&initiative).unwrap();
+
+    interpreter.random = Box::new(SeededRandom::new(42));
+    let second = interpreter.execute(&initiative).unwrap();
+    assert_eq!(first, second);
+}
+
+#[test]
+fn it_executes_assignments() {
+    let mut interpreter = interpreter();
+    let output = interpreter.execute(&program("#test $foo = 5 | $foo += 2 | @me.hp -= $foo | $bar = @me.hp * 2 | !say \"$foo, $bar, @me.hp\"")).unwrap();
+    assert_eq!(output.messages[0].message, "7, 26, 13".to_string());
+    assert_eq!(interpreter.tokens.get("me", "hp"), Ok(ArgValue::Number(13)));
+
+    let output = interpreter.execute(&program("#test $roll = !roll 1d1+9 !say \"$roll\"")).unwrap();
+    assert_eq!(output.messages[0].message, "10".to_string());
+}
+
+#[test]
+fn it_reads_and_writes_nested_attributes() {
+    let mut interpreter = interpreter();
+    let mut spell = HashMap::new();
+    spell.insert("level".to_string(), ArgValue::Number(2));
+    interpreter.tokens.set("me", "spells", ArgValue::Array(vec![ArgValue::Object(spell)])).unwrap();
+
+    let output = interpreter.execute(&program("#test @me.spells.0.level += 1 | !say \"Level @me.spells.0.level\"")).unwrap();
+    assert_eq!(output.messages[0].message, "Level 3".to_string());
+    assert_eq!(interpreter.tokens.get("me", "spells.0.level"), Ok(ArgValue::Number(3)));
+
+    assert_eq!(
+        interpreter.tokens.get("me", "spells.1.level"),
+        Err(ExecutionError::AttributeNotFound("@me.spells.1.level".to_string()))
+    );
+    assert_eq!(
+        interpreter.execute(&program("#test @npc.hp = 5")),
+        Err(ExecutionError::TokenNotFound("@npc".to_string()))
+    );
+}
+
+#[test]
+fn it_executes_conditionals() {
+    let mut interpreter = interpreter();
+    let output = interpreter.execute(&program("#test !r 1d1+9 >> $1 >= 10 ? !say 'Hit' : !say 'Miss' $1 > 10 ? !say 'Crit' : |")).unwrap();
+    assert_eq!(output.messages.len(), 1);
+    assert_eq!(output.messages[0].message, "Hit".to_string());
+}
+
+#[test]
+fn it_stops_on_exit() {
+    let mut interpreter = interpreter();
+    let output = interpreter.execute(&program("#test !say 'one' !exit !say 'two'")).unwrap();
+    assert_eq!(output.messages.len(), 1);
+}
+
+#[test]
+fn it_executes_test_mode() {
+    let mut interpreter = interpreter();
+    let output = interpreter.execute(&program("#test !test true !say 'one' !r 1d20")).unwrap();
+    assert!(output.messages[0].is_test);
+    assert!(output.rolls[0].is_test);
+}
+
+#[test]
+fn it_executes_templates() {
+    let mut interpreter = interpreter();
+    let output = interpreter.execute(&program("#test !template attack { damage: @me.dexterity }")).unwrap();
+
+    let mut attributes = HashMap::new();
+    attributes.insert("damage".to_string(), ArgValue::Number(3));
+    assert_eq!(output.templates, vec![Template {
+        name: "attack".to_string(),
+        attributes,
+    }]);
+}
+
+struct TestHost {
+    cancel: bool,
+}
+
+impl Host for TestHost {
+    fn input(&mut self, message: &str) -> Result<ArgValue, HostError> {
+        Ok(ArgValue::Text(format!("answer to {}", message)))
+    }
+
+    fn prompt(&mut self, _message: &str, options: &[PromptOption]) -> Result<usize, HostError> {
+        if self.cancel {
+            return Err(HostError::Cancelled);
+        }
+        assert_eq!(options[1], PromptOption { key: "b".to_string(), value: "Second".to_string() });
+        Ok(1)
+    }
+
+    fn target(&mut self, _message: &str) -> Result<String, HostError> {
+        Ok("me".to_string())
+    }
+}
+
+#[test]
+fn it_executes_interactive_commands() {
+    let mut interpreter = interpreter();
+    interpreter.host = Box::new(TestHost { cancel: false });
+
+    let output = interpreter.execute(&program("#test !input 'Name?' !prompt 'Pick' [a:First, b:Second] !target 'Who?' !say \"$1,$2,@target.hp\"")).unwrap();
+    assert_eq!(output.messages[0].message, "answer to Name?,b,20".to_string());
+
+    // !case picks the matching option without prompting
+    interpreter.host = Box::new(TestHost { cancel: true });
+    let output = interpreter.execute(&program("#test !case 'b' [a:1, b:2] !say \"$1\"")).unwrap();
+    assert_eq!(output.messages[0].message, "2".to_string());
+    assert_eq!(
+        interpreter.execute(&program("#test !case 'c' [a:1, b:2]")),
+        Err(ExecutionError::Callback("Cancelled by the user".to_string()))
+    );
+}
+
+#[test]
+fn it_suspends_and_resumes_interactive_commands() {
+    let mut interpreter = interpreter();
+    interpreter.host = Box::new(DeferredHost);
+
+    let execution = interpreter.start(&program("#test !test true !r 1d1+4 >> !prompt 'Pick' [a:First, b:Second] !say \"$1,$2\" !target 'Who?' !say \"@target.hp\"")).unwrap();
+    let suspended = match execution {
+        Execution::Suspended(suspended) => suspended,
+        execution => panic!("Expected to suspend, got {:?}", execution),
+    };
+    assert_eq!(suspended.output.rolls.len(), 1);
+    assert_eq!(suspended.request, Request::Prompt {
+        message: "Pick".to_string(),
+        options: vec![
+            PromptOption { key: "a".to_string(), value: "First".to_string() },
+            PromptOption { key: "b".to_string(), value: "Second".to_string() },
+        ],
+    });
+
+    // the continuation can be stored while waiting on the user
+    let json = serde_json::to_string(&suspended.continuation).unwrap();
+    let continuation: Continuation = serde_json::from_str(&json).unwrap();
+    assert_eq!(continuation.results, vec![ArgValue::Number(5)]);
+    assert!(continuation.test);
+
+    let suspended = match interpreter.resume(continuation, Answer::Option(1)).unwrap() {
+        Execution::Suspended(suspended) => suspended,
+        execution => panic!("Expected to suspend, got {:?}", execution),
+    };
+    assert_eq!(suspended.output.messages[0].message, "5,b".to_string());
+    assert!(suspended.output.messages[0].is_test);
+    assert_eq!(suspended.request, Request::Target { message: "Who?".to_string() });
+
+    // answering with the wrong kind of answer is an error
+    assert!(interpreter.resume(suspended.continuation.clone(), Answer::Option(0)).is_err());
+
+    let output = match interpreter.resume(suspended.continuation, Answer::Target("me".to_string())).unwrap() {
+        Execution::Complete(output) => output,
+        execution => panic!("Expected to complete, got {:?}", execution),
+    };
+    assert_eq!(output.messages[0].message, "20".to_string());
+}
+
+/// Answer every request in turn until the program completes, collecting each message
+fn answer_all<F>(interpreter: &mut Interpreter, mut execution: Execution, answer: F) -> (Vec<String>, usize)
+    where F: Fn(usize) -> Answer
+{
+    let mut messages = vec![];
+    let mut rolls = 0;
+    for answered in 0..10 {
+        let output = match execution {
+            Execution::Suspended(suspended) => {
+                let output = suspended.output.clone();
+                execution = interpreter.resume(suspended.continuation, answer(answered)).unwrap();
+                output
+            },
+            Execution::Complete(output) => {
+                messages.extend(output.messages.iter().map(|message| message.message.clone()));
+                return (messages, rolls + output.rolls.len());
+            },
+        };
+        messages.extend(output.messages.iter().map(|message| message.message.clone()));
+        rolls += output.rolls.len();
+    }
+    panic!("Program did not complete, got {:?}", messages);
+}
+
+#[test]
+fn it_resumes_inside_loops_blocks_and_macros() {
+    let mut interpreter = interpreter();
+    interpreter.host = Box::new(DeferredHost);
+    interpreter.macros.register("npc", "ask", program("#ask !say 'Asking' !input 'Name?' >> !say \"Called $0\""));
+
+    let execution = interpreter.start(&program(
+        "#test !for $i in 1..3 { !say \"Start $i\" !input 'n?' >> !say \"Got $i: $0\" } !say 'Done'"
+    )).unwrap();
+    let (messages, _) = answer_all(&mut interpreter, execution, |answered| Answer::Input(ArgValue::Number(answered as i32 * 10)));
+    assert_eq!(messages, vec![
+        "Start 1", "Got 1: 0", "Start 2", "Got 2: 10", "Start 3", "Got 3: 20", "Done",
+    ]);
+
+    let execution = interpreter.start(&program(
+        "#test !r 1d1 >> $1 == 1 ? { !say 'Rolled' !for @who in [@npc] { @who->ask } !say \"After $0\" } : !say 'Never'"
+    )).unwrap();
+    let (messages, rolls) = answer_all(&mut interpreter, execution, |_| Answer::Input(ArgValue::Text("Bob".to_string())));
+    assert_eq!(messages, vec!["Rolled", "Asking", "Called Bob", "After Bob"]);
+    assert_eq!(rolls, 1);
+}
+
+#[test]
+fn it_resumes_without_running_earlier_arguments_again() {
+    let mut interpreter = interpreter();
+    interpreter.host = Box::new(DeferredHost);
+    interpreter.macros.register("npc", "ask", program("#ask !input 'Name?'"));
+
+    let execution = interpreter.start(&program(
+        "#test @me.hp > 0 ? @me.hp -= 1 : | $name = !input 'Name?' | !say \"$name\""
+    )).unwrap();
+    let (messages, _) = answer_all(&mut interpreter, execution, |_| Answer::Input(ArgValue::Text("Bob".to_string())));
+    assert_eq!(messages, vec!["Bob"]);
+    assert_eq!(interpreter.tokens.get("me", "hp"), Ok(ArgValue::Number(19)));
+
+    // the macros running and the steps run so far are kept while waiting on the user
+    let execution = interpreter.start(&program("#test !say 'Hi' $a = 1 | @npc->ask")).unwrap();
+    let continuation = match execution {
+        Execution::Suspended(suspended) => suspended.continuation,
+        execution => panic!("Expected to suspend, got {:?}", execution),
+    };
+    assert_eq!(continuation.calls, vec!["@npc->ask".to_string()]);
+    assert_eq!(continuation.steps_run, 3);
+
+    interpreter.limits.max_steps = 3;
+    assert_eq!(
+        interpreter.resume(continuation.clone(), Answer::Input(ArgValue::Number(1))),
+        Err(ExecutionError::LimitExceeded(Limit::Steps, 3))
+    );
+    interpreter.limits.max_steps = 4;
+    assert!(interpreter.resume(continuation, Answer::Input(ArgValue::Number(1))).is_ok());
+}
+
+#[test]
+fn it_runs_token_macros() {
+    let mut interpreter = interpreter();
+    interpreter.macros.register("npc", "attack", program("#attack !say 'Swing' !roll 1d1+2 >> !exit !say 'Never'"));
+    interpreter.macros.register("npc", "loop", program("#loop @npc->again"));
+    interpreter.macros.register("npc", "again", program("#again @npc->loop"));
+    interpreter.macros.register("npc", "deep", program("#deep @me->deep"));
+    interpreter.macros.register("me", "deep", program("#deep @npc->attack"));
+
+    // macros run as their token and share saved results
+    let output = interpreter.execute(&program("#test @npc->attack | !say \"Hit for $1\"")).unwrap();
+    assert_eq!(output.messages.len(), 2);
+    assert_eq!(output.messages[0].from, Some("npc".to_string()));
+    assert_eq!(output.rolls[0].token, Some("npc".to_string()));
+    assert_eq!(output.messages[1].from, None);
+    assert_eq!(output.messages[1].message, "Hit for 3".to_string());
+
+    assert_eq!(
+        interpreter.execute(&program("#test @npc->loop")),
+        Err(ExecutionError::MacroCycle("@npc->loop".to_string()))
+    );
+    assert_eq!(
+        interpreter.execute(&program("#test @npc->missing")),
+        Err(ExecutionError::MacroNotFound("@npc->missing".to_string()))
+    );
+
+    interpreter.limits.max_call_depth = 2;
+    assert_eq!(
+        interpreter.execute(&program("#test @npc->deep")),
+        Err(ExecutionError::LimitExceeded(Limit::Calls, 2))
+    );
+}
+
+#[test]
+fn it_executes_functions() {
+    let mut interpreter = interpreter();
+    let double: Function = Box::new(|args| match args[0] {
+        ArgValue::Number(n) => Ok(ArgValue::Number(n * 2)),
+        _ => Err("Expected a whole number".to_string()),
+    });
+    interpreter.functions.register("double", Signature::new(vec![ArgType::Number]), double);
+    let sum: Function = Box::new(|args| {
+        Ok(ArgValue::Number(args.iter().fold(0, |total, arg| match *arg {
+            ArgValue::Number(n) => total + n,
+            _ => total,
+        })))
+    });
+    interpreter.functions.register("sum", Signature::variadic(vec![], ArgType::Number), sum);
+
+    let output = interpreter.execute(&program("#test $foo = double{@me.dexterity} | $bar = sum{1|2|@me.dexterity} | !say \"$foo,$bar\"")).unwrap();
+    assert_eq!(output.messages[0].message, "6,6".to_string());
+
+    assert_eq!(
+        interpreter.execute(&program("#test $foo = double{1|2}")),
+        Err(ExecutionError::ArgumentCount("double".to_string(), 1, 2))
+    );
+    assert_eq!(
+        interpreter.execute(&program("#test $foo = double{@me.name}")),
+        Err(ExecutionError::ArgumentType("double".to_string(), 1, ArgType::Number))
+    );
+    assert_eq!(
+        ExecutionError::ArgumentCount("double".to_string(), 1, 2).to_string(),
+        "Function \"double\" expects 1 arguments but was given 2".to_string()
+    );
+}
+
+#[test]
+fn it_reports_errors() {
+    let mut interpreter = interpreter();
+    assert_eq!(
+        interpreter.execute(&program("#test !say \"$missing\"")),
+        Err(ExecutionError::VariableNotSet("missing".to_string()))
+    );
+    assert_eq!(
+        interpreter.execute(&program("#test !say \"@me.missing\"")),
+        Err(ExecutionError::AttributeNotFound("@me.missing".to_string()))
+    );
+    assert_eq!(
+        interpreter.execute(&program("#test !say \"@target.hp\"")),
+        Err(ExecutionError::NoTarget)
+    );
+    assert_eq!(
+        interpreter.execute(&program("#test !input 'Name?'")),
+        Err(ExecutionError::MissingCallback("input".to_string()))
+    );
+    assert_eq!(
+        interpreter.execute(&program("#test $foo = nope{1}")),
+        Err(ExecutionError::FunctionNotFound("nope".to_string()))
+    );
+}
+
+#[test]
+fn it_reports_where_errors_happen() {
+    let mut interpreter = interpreter();
+    let input = "#test !say 'Attack!' !r 1d20 + @me.missing";
+    assert!(interpreter.execute(&program(input)).is_err());
+    assert_eq!(interpreter.error_span().unwrap().text(input), "@me.missing");
+
+    let input = "#test $foo = 1 | $foo > 0 ? !say \"$missing\" : !exit";
+    assert!(interpreter.execute(&program(input)).is_err());
+    assert_eq!(interpreter.error_span().unwrap().text(input), "!say \"$missing\"");
+
+    // errors inside token macros point at the call
+    interpreter.macros.register("npc", "attack", program("#attack !r 1d20 + @npc.missing"));
+    let input = "#test !say 'Go' | @npc->attack";
+    assert!(interpreter.execute(&program(input)).is_err());
+    assert_eq!(interpreter.error_span().unwrap().text(input), "@npc->attack");
+
+    interpreter.execute(&program("#test !r 1d20")).unwrap();
+    assert_eq!(interpreter.error_span(), None);
+}
+
+#[test]
+fn it_runs_macros_from_a_library() {
+    let mut interpreter = interpreter();
+    let library = parse_library(b"#attack !r 1d1+2\n#taunt !say 'Come here!'").unwrap();
+    interpreter.macros.register_library("npc", library);
+
+    let output = interpreter.execute(&program("#test @npc->taunt | @npc->attack")).unwrap();
+    assert_eq!(output.messages[0].message, "Come here!".to_string());
+    assert_eq!(output.rolls[0].result.value, 3);
+}
+
+#[test]
+fn it_calculates_expressions() {
+    let mut interpreter = interpreter();
+    let output = interpreter.execute(&program("#test $a = 2 + 3 * 4 | $b = (2 + 3) * 4 | $c = -$a % 5 | $d = (@me.dexterity - 10) \\ 2 | !say \"$a,$b,$c,$d\"")).unwrap();
+    assert_eq!(output.messages[0].message, "14,20,1,-4".to_string());
+
+    // expressions in conditionals and roll modifiers
+    let output = interpreter.execute(&program("#test @me.dexterity * 5 >= 15 ? !say 'Quick' : !say 'Slow'")).unwrap();
+    assert_eq!(output.messages[0].message, "Quick".to_string());
+    let output = interpreter.execute(&program("#test !r 1d1 + @me.dexterity * 2 - 1")).unwrap();
+    assert_eq!(output.rolls[0].result.value, 6);
+    let output = interpreter.execute(&program("#test !r 1d1 + 2d1 + @me.dexterity - 1")).unwrap();
+    assert_eq!(output.rolls[0].result.value, 5);
+
+    assert_eq!(
+        interpreter.execute(&program("#test $a = 1 % 0")),
+        Err(ExecutionError::InvalidValue("Cannot divide by zero".to_string()))
+    );
+    assert_eq!(
+        interpreter.execute(&program("#test $a = 1.5 / 0")),
+        Err(ExecutionError::InvalidValue("Cannot divide by zero".to_string()))
+    );
+
+    // += overflows the same way as +
+    assert_eq!(
+        interpreter.execute(&program("#test $a = 2147483647 + 1")),
+        Err(ExecutionError::InvalidValue("Number is too large".to_string()))
+    );
+    assert_eq!(
+        interpreter.execute(&program("#test $a = 2147483647 | $a += 1")),
+        Err(ExecutionError::InvalidValue("Number is too large".to_string()))
+    );
+}
+
+#[test]
+fn it_checks_conditions() {
+    let mut interpreter = interpreter();
+    let output = interpreter.execute(&program("#test !r 1d1 >> $1 == 1 && @me.dexterity > 2 ? !say 'yes' : !say 'no'")).unwrap();
+    assert_eq!(output.messages[0].message, "yes".to_string());
+    let output = interpreter.execute(&program("#test !r 1d1 >> !($1 == 1 || @me.dexterity > 2) ? !say 'yes' : !say 'no'")).unwrap();
+    assert_eq!(output.messages[0].message, "no".to_string());
+
+    // the right side is never looked at once the left side decides
+    let output = interpreter.execute(&program("#test !r 1d1 >> $1 > 1 && @nobody.hp > 0 ? !say 'yes' : !say 'no'")).unwrap();
+    assert_eq!(output.messages[0].message, "no".to_string());
+    let output = interpreter.execute(&program("#test !r 1d1 >> $1 == 1 || @nobody.hp > 0 ? !say 'yes' : !say 'no'")).unwrap();
+    assert_eq!(output.messages[0].message, "yes".to_string());
+    assert!(interpreter.execute(&program("#test !r 1d1 >> $1 == 1 && @nobody.hp > 0 ? !say 'yes' : !say 'no'")).is_err());
+}
+
+#[test]
+fn it_indexes_arrays_and_objects() {
+    let mut interpreter = interpreter();
+    let output = interpreter.execute(&program(
+        "#test $list = [4, 5, 6] | $stats = {str: 10, dex: 3} | $n = len($list) + len($stats) | !say \"$list[0],$stats.str,$n,$list[len($list) - 1]\""
+    )).unwrap();
+    assert_eq!(output.messages[0].message, "4,10,5,6".to_string());
+
+    let output = interpreter.execute(&program("#test $stats = {str: 10} | $stats.str >= 10 && len('abc') == 3 ? !say 'strong' : !say 'weak'")).unwrap();
+    assert_eq!(output.messages[0].message, "strong".to_string());
+
+    let output = interpreter.execute(&program("#test $dice = [1, 3] | !r $dice[1]d1 + $dice[0]")).unwrap();
+    assert_eq!(output.rolls[0].result.value, 4);
+
+    assert_eq!(
+        interpreter.execute(&program("#test $list = [1] | $a = $list[3]")),
+        Err(ExecutionError::InvalidValue("Cannot find 3 in 1".to_string()))
+    );
+    assert_eq!(
+        interpreter.execute(&program("#test $a = len(5)")),
+        Err(ExecutionError::InvalidValue("Cannot find the length of 5".to_string()))
+    );
+}
+
+#[test]
+fn it_says_escaped_text() {
+    let mut interpreter = interpreter();
+    let output = interpreter.execute(&program(r#"#test !say "@me.name says \"pay \$5 to \@gm\"""#)).unwrap();
+    assert_eq!(output.messages[0].message, "Elf says \"pay $5 to @gm\"".to_string());
+}
+
+#[test]
+fn it_runs_loops() {
+    let mut interpreter = interpreter();
+    let output = interpreter.execute(&program("#test $total = 0 | !for $i in 1..4 $total = $total + $i | !say \"$total\"")).unwrap();
+    assert_eq!(output.messages[0].message, "10".to_string());
+
+    let output = interpreter.execute(&program("#test !each $name in ['a', 'b', @me.name] { !say \"$name\" }")).unwrap();
+    let messages: Vec<String> = output.messages.iter().map(|message| message.message.clone()).collect();
+    assert_eq!(messages, vec!["a".to_string(), "b".to_string(), "Elf".to_string()]);
+
+    // a range that ends before it starts never runs
+    let output = interpreter.execute(&program("#test !for $i in 3..1 !say 'never'")).unwrap();
+    assert_eq!(output.messages, vec![]);
+
+    // tokens stand in for each token in turn
+    let mut attributes = HashMap::new();
+    attributes.insert("dexterity".to_string(), ArgValue::Number(1));
+    let mut tokens = MemoryTokenStore::new();
+    tokens.insert("me", Token { attributes: attributes.clone() });
+    attributes.insert("dexterity".to_string(), ArgValue::Number(5));
+    tokens.insert("npc", Token { attributes });
+    interpreter.tokens = Box::new(tokens);
+    let output = interpreter.execute(&program("#test !for @player in [@me, @npc] { !r 1d1 + @player.dexterity >> @player.initiative = $0 } !say \"@me.initiative and @npc.initiative\"")).unwrap();
+    assert_eq!(output.messages[0].message, "2 and 6".to_string());
+
+    assert_eq!(
+        interpreter.execute(&program("#test $a = 'a' | !for $i in 1..$a !say 'never'")),
+        Err(ExecutionError::InvalidValue("Cannot loop from 1 to a".to_string()))
+    );
+}
+
+#[test]
+fn it_runs_blocks_and_else_if() {
+    let mut interpreter = interpreter();
+    let output = interpreter.execute(&program("#test @me.hp > 10 ? { @me.hp -= 5 | !say 'Ouch' !say \"@me.hp left\" } : !say 'Down'")).unwrap();
+    let messages: Vec<String> = output.messages.iter().map(|message| message.message.clone()).collect();
+    assert_eq!(messages, vec!["Ouch".to_string(), "15 left".to_string()]);
+
+    let grade = "#test $1 == 20 ? !say 'Crit' : $1 >= 15 ? !say 'Hit' : $1 == 1 ? !say 'Fumble' : !say 'Miss'";
+    for (roll, message) in [(20, "Crit"), (16, "Hit"), (1, "Fumble"), (7, "Miss")].iter() {
+        let output = interpreter.execute(&program(&grade.replace("#test", &format!("#test !r 1d1+{} >>", roll - 1)))).unwrap();
+        assert_eq!(output.messages[0].message, message.to_string());
+    }
+}
+
+#[test]
+fn it_compares_values() {
+    let mut interpreter = interpreter();
+    let mut check = |input: &str| {
+        let output = interpreter.execute(&program(&format!("#test {} ? !say 'yes' : !say 'no'", input))).unwrap();
+        output.messages[0].message == "yes"
+    };
+
+    assert!(check("@me.name == \"Elf\""));
+    assert!(!check("@me.name != 'Elf'"));
+    assert!(check("@me.name > 'Dwarf'"));
+    assert!(check("@me.hp != 15"));
+    assert!(check("'15' == 15"));
+    assert!(check("'10' > '9'"));
+    assert!(check("true == true"));
+    assert!(check("true != false"));
+    assert!(!check("true > false"));
+
+    // different types are never equal
+    assert!(!check("true == 1"));
+    assert!(check("@me.name != 0"));
+    assert!(!check("@me.name < 0"));
+}
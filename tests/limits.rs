@@ -0,0 +1,111 @@
+extern crate ttml;
+extern crate nom;
+
+use nom::types::CompleteByteSlice;
+use ttml::error::ExecutionError;
+use ttml::interpreter::*;
+use ttml::limits::*;
+use ttml::parser::*;
+
+fn program(input: &str) -> Program {
+    let (_, program) = parse_p(CompleteByteSlice(input.as_bytes())).unwrap();
+    program
+}
+
+fn limits() -> ExecutionLimits {
+    ExecutionLimits {
+        max_dice: 10,
+        max_rerolls: 5,
+        max_steps: 6,
+        max_depth: 2,
+        max_iterations: 4,
+        max_messages: 3,
+        max_outcomes: 50,
+        max_string_length: 20,
+        max_call_depth: 2,
+    }
+}
+
+#[test]
+fn it_checks_programs_before_running() {
+    let limits = limits();
+    assert!(limits.check_program(&program("#test !roll 10d6 !say 'Hello'")).is_ok());
+    assert_eq!(
+        limits.check_program(&program("#test !roll 99999999d99999999")),
+        Err(ExecutionError::LimitExceeded(Limit::Dice, 10))
+    );
+    assert_eq!(
+        limits.check_program(&program("#test !roll 6d6 + 5d4")),
+        Err(ExecutionError::LimitExceeded(Limit::Dice, 10))
+    );
+    assert_eq!(
+        limits.check_program(&program("#test !roll 1d6rr<=6")),
+        Err(ExecutionError::LimitExceeded(Limit::Rerolls, 5))
+    );
+    assert_eq!(
+        limits.check_program(&program("#test !roll 1d20 + 1d6rr<=6")),
+        Err(ExecutionError::LimitExceeded(Limit::Rerolls, 5))
+    );
+    assert_eq!(
+        limits.check_program(&program("#test !roll 1d6rr!=7")),
+        Err(ExecutionError::LimitExceeded(Limit::Rerolls, 5))
+    );
+    assert!(limits.check_program(&program("#test !roll 1d6rr!=3")).is_ok());
+    assert!(limits.check_program(&program("#test !roll 1d6rr>1")).is_ok());
+    assert_eq!(
+        limits.check_program(&program("#test !roll 1d6e1")),
+        Err(ExecutionError::LimitExceeded(Limit::Rerolls, 5))
+    );
+    assert_eq!(
+        limits.check_program(&program("#test !say 'This message is much too long'")),
+        Err(ExecutionError::LimitExceeded(Limit::StringLength, 20))
+    );
+    assert_eq!(
+        limits.check_program(&program("#test !say 'a' !say 'b' !say 'c' !say 'd' !say 'e' !say 'f' !say 'g'")),
+        Err(ExecutionError::LimitExceeded(Limit::Steps, 6))
+    );
+    assert_eq!(
+        limits.check_program(&program("#test $1 > 1 ? $2 > 2 ? !say 'deep' : | : |")),
+        Err(ExecutionError::LimitExceeded(Limit::Depth, 2))
+    );
+
+    assert_eq!(
+        limits.check_program(&program("#test !for $i in 1..5 !say '$i'")),
+        Err(ExecutionError::LimitExceeded(Limit::Iterations, 4))
+    );
+
+    // the parser can reject programs over the limits
+    assert!(parse_program_limited(b"#test !roll 10d6", &limits).is_ok());
+    let error = parse_program_limited(b"#test !say 'Hi'\n!roll 11d6", &limits).unwrap_err();
+    assert_eq!(error.limit, Some((Limit::Dice, 10)));
+    assert_eq!((error.line, error.column), (2, 1));
+    assert_eq!(error.to_string(), "line 2, col 1: Too many dice in a roll (the limit is 10)".to_string());
+}
+
+#[test]
+fn it_enforces_limits_while_executing() {
+    let mut interpreter = Interpreter::new();
+    interpreter.limits = limits();
+
+    assert_eq!(
+        interpreter.execute(&program("#test $dice = 11 | !roll $dice d6")),
+        Err(ExecutionError::LimitExceeded(Limit::Dice, 10))
+    );
+    assert_eq!(
+        interpreter.execute(&program("#test !say 'a' !say 'b' !say 'c' !say 'd'")),
+        Err(ExecutionError::LimitExceeded(Limit::Messages, 3))
+    );
+    assert_eq!(
+        interpreter.execute(&program("#test $a = 'aaaaaaaaaa' | $a += 'aaaaaaaaaa' | $a += 'a'")),
+        Err(ExecutionError::LimitExceeded(Limit::StringLength, 20))
+    );
+
+    assert_eq!(
+        interpreter.execute(&program("#test $n = 5 | !for $i in 1..$n $a = $i")),
+        Err(ExecutionError::LimitExceeded(Limit::Iterations, 4))
+    );
+
+    // rerolls stop at the limit
+    let output = interpreter.execute(&program("#test !roll 1d[1,2]rr<2")).unwrap();
+    assert!(output.rolls[0].result.dice[0].rerolls.len() <= 5);
+}
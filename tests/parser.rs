@@ -10,6 +10,7 @@ use nom::{IResult, ErrorKind};
 use nom::types::CompleteByteSlice;
 use std::collections::HashMap;
 use ttml::arg::*;
+use ttml::error::Construct;
 use ttml::parser::*;
 use ttml::step::*;
 
@@ -24,7 +25,10 @@ fn test_simple_parser() {
             ],
             op: MacroOp::Roll,
             result: StepResult::Ignore,
+            span: None,
+            arg_spans: vec![],
         }],
+        docs: vec![],
     };
     let (_, result) = parse_p(CompleteByteSlice(b"#simple-macro-name !roll 1d20")).unwrap();
     assert_eq!(result, program);
@@ -36,6 +40,8 @@ fn test_simple_parser() {
                 args: vec![],
                 op: MacroOp::Exit,
                 result: StepResult::Ignore,
+                span: None,
+                arg_spans: vec![],
             },
             Step {
                 args: vec![
@@ -43,12 +49,16 @@ fn test_simple_parser() {
                         parts: vec! [
                             ArgValue::Text("Hello, world!".to_string()),
                         ],
+                        span: None,
                     })),
                 ],
                 op: MacroOp::Say,
                 result: StepResult::Ignore,
+                span: None,
+                arg_spans: vec![],
             }
         ],
+        docs: vec![],
     };
     let (_, result) = parse_p(CompleteByteSlice(b"#simple-macro-name-2 !exit !say \"Hello, world!\"")).unwrap();
     assert_eq!(result, program);
@@ -83,6 +93,8 @@ fn test_complex_parser() {
                 ],
                 op: MacroOp::Lambda,
                 result: StepResult::Save,
+                span: None,
+                arg_spans: vec![],
             },
             Step {
                 args: vec![
@@ -91,6 +103,8 @@ fn test_complex_parser() {
                 ],
                 op: MacroOp::Roll,
                 result: StepResult::Save,
+                span: None,
+                arg_spans: vec![],
             },
             Step {
                 args: vec![
@@ -98,10 +112,13 @@ fn test_complex_parser() {
                     Arg::Roll(RollArg::D(ArgValue::VariableReserved(1))),
                     Arg::Roll(RollArg::Comment(ArgValue::TextInterpolated(TextInterpolated {
                         parts: vec![ ArgValue::Text("A cool roll comment".to_string()) ],
+                        span: None,
                     }))),
                 ],
                 op: MacroOp::Roll,
                 result: StepResult::Ignore,
+                span: None,
+                arg_spans: vec![],
             },
             Step {
                 args: vec![
@@ -109,12 +126,16 @@ fn test_complex_parser() {
                         parts: vec! [
                             ArgValue::Text("Smite!".to_string()),
                         ],
+                        span: None,
                     })),
                 ],
                 op: MacroOp::Say,
                 result: StepResult::Ignore,
+                span: None,
+                arg_spans: vec![],
             },
         ],
+        docs: vec![],
     };
     let (_, result) = parse_p(CompleteByteSlice(b"#complex-macro-name $foo = 1 >> !r 1d20 >> !roll ${foo}d$1 \"A cool roll comment\" !say \"Smite!\"")).unwrap();
     assert_eq!(result, program);
@@ -132,6 +153,8 @@ fn test_complex_parser() {
                 ],
                 op: MacroOp::Roll,
                 result: StepResult::Ignore,
+                span: None,
+                arg_spans: vec![],
             },
             Step {
                 args: vec![
@@ -139,10 +162,13 @@ fn test_complex_parser() {
                         parts: vec! [
                             ArgValue::Text("Smite!".to_string()),
                         ],
+                        span: None,
                     })),
                 ],
                 op: MacroOp::Say,
                 result: StepResult::Ignore,
+                span: None,
+                arg_spans: vec![],
             },
             Step {
                 args: vec![
@@ -153,6 +179,8 @@ fn test_complex_parser() {
                 ],
                 op: MacroOp::Roll,
                 result: StepResult::Save,
+                span: None,
+                arg_spans: vec![],
             },
             Step {
                 args: vec![
@@ -161,12 +189,16 @@ fn test_complex_parser() {
                             ArgValue::Text("I rolled a ".to_string()),
                             ArgValue::VariableReserved(1),
                         ],
+                        span: None,
                     })),
                 ],
                 op: MacroOp::Say,
                 result: StepResult::Ignore,
+                span: None,
+                arg_spans: vec![],
             },
         ],
+        docs: vec![],
     };
     let (_, result) = parse_p(CompleteByteSlice(b"#complex-macro-name-2 !roll 3d8min8max16+3 !say \"Smite!\" !roll 2d20-5kh1 >> !say \"I rolled a $1\"")).unwrap();
     assert_eq!(result, program);
@@ -183,6 +215,8 @@ fn test_complex_parser() {
                 ],
                 op: MacroOp::Lambda,
                 result: StepResult::Ignore,
+                span: None,
+                arg_spans: vec![],
             },
             Step {
                 args: vec![
@@ -191,8 +225,11 @@ fn test_complex_parser() {
                 ],
                 op: MacroOp::Roll,
                 result: StepResult::Ignore,
+                span: None,
+                arg_spans: vec![],
             },
         ],
+        docs: vec![],
     };
     let (_, result) = parse_p(CompleteByteSlice(b"#test-assignment $foo = 'bar' !r 1d20")).unwrap();
     assert_eq!(result, program);
@@ -207,31 +244,38 @@ fn test_complex_parser() {
                 ],
                 op: MacroOp::Roll,
                 result: StepResult::Save,
+                span: None,
+                arg_spans: vec![],
             },
             Step {
                 args: vec![
                     Arg::Conditional(Conditional {
-                        left: ArgValue::VariableReserved(1),
-                        comparison: ComparisonArg::GreaterThan,
-                        right: ArgValue::Number(10),
-                        success: Some(Step {
+                        condition: Condition::Compare(Box::new(Comparison { left: ArgValue::VariableReserved(1), comparison: ComparisonArg::GreaterThan, right: ArgValue::Number(10) })),
+                        success: vec![Step {
                             args: vec![
                                 Arg::Say(SayArg::Message(TextInterpolated {
                                     parts: vec![
                                         ArgValue::Text("Success".to_string()),
                                     ],
+                                    span: None,
                                 })),
                             ],
                             op: MacroOp::Say,
                             result: StepResult::Ignore,
-                        }),
-                        failure: None,
+                            span: None,
+                            arg_spans: vec![],
+                        }],
+                        else_if: vec![],
+                        failure: vec![],
                     }),
                 ],
                 op: MacroOp::Lambda,
                 result: StepResult::Ignore,
+                span: None,
+                arg_spans: vec![],
             },
         ],
+        docs: vec![],
     };
     let (_, result) = parse_p(CompleteByteSlice(b"#test !r 1d20 >> $1 > 10 ? !say \"Success\" :|")).unwrap();
     assert_eq!(result, program);
@@ -243,10 +287,8 @@ fn test_complex_parser() {
             Step {
                 args: vec![
                     Arg::Conditional(Conditional {
-                        left: ArgValue::Number(5),
-                        comparison: ComparisonArg::LessThan,
-                        right: ArgValue::Number(10),
-                        success: Some(Step {
+                        condition: Condition::Compare(Box::new(Comparison { left: ArgValue::Number(5), comparison: ComparisonArg::LessThan, right: ArgValue::Number(10) })),
+                        success: vec![Step {
                             args: vec![
                                 Arg::Assign(Assign {
                                     left: ArgValue::Variable("mod".to_string()),
@@ -255,8 +297,11 @@ fn test_complex_parser() {
                             ],
                             op: MacroOp::Lambda,
                             result: StepResult::Ignore,
-                        }),
-                        failure: Some(Step {
+                            span: None,
+                            arg_spans: vec![],
+                        }],
+                        else_if: vec![],
+                        failure: vec![Step {
                             args: vec![
                                 Arg::Assign(Assign {
                                     left: ArgValue::Variable("mod".to_string()),
@@ -265,11 +310,15 @@ fn test_complex_parser() {
                             ],
                             op: MacroOp::Lambda,
                             result: StepResult::Ignore,
-                        }),
+                            span: None,
+                            arg_spans: vec![],
+                        }],
                     }),
                 ],
                 op: MacroOp::Lambda,
                 result: StepResult::Ignore,
+                span: None,
+                arg_spans: vec![],
             },
             Step {
                 args: vec![
@@ -279,12 +328,16 @@ fn test_complex_parser() {
                             ArgValue::Variable("mod".to_string()),
                             ArgValue::Text(" trailing space test  ".to_string()),
                         ],
+                        span: None,
                     })),
                 ],
                 op: MacroOp::Say,
                 result: StepResult::Ignore,
+                span: None,
+                arg_spans: vec![],
             },
         ],
+        docs: vec![],
     };
     let (_, result) = parse_p(CompleteByteSlice(b"#test 5 < 10 ? $mod = 1 : $mod = 2 !say \"Mod is $mod trailing space test  \"")).unwrap();
     assert_eq!(result, program);
@@ -396,9 +449,11 @@ fn test_quoted_interpolated_parser() {
                 name: "token".to_string(),
                 attribute: Some("attribute".to_string()),
                 macro_name: None,
+                span: None,
             }),
             ArgValue::Text(", it's good to see you".to_string())
         ],
+        span: None,
     });
 
     let (_, result) = quoted_interpolated_p(CompleteByteSlice(b"\"There is activity at $place bar\"")).unwrap();
@@ -408,6 +463,7 @@ fn test_quoted_interpolated_parser() {
             ArgValue::Variable("place".to_string()),
             ArgValue::Text(" bar".to_string())
         ],
+        span: None,
     });
 
     let (_, result) = quoted_interpolated_p(CompleteByteSlice(b"\"Hey bartender, @{bartender}.name! Get me an ale of ${beer}!\"")).unwrap();
@@ -418,11 +474,13 @@ fn test_quoted_interpolated_parser() {
                 name: "bartender".to_string(),
                 attribute: Some("name".to_string()),
                 macro_name: None,
+                span: None,
             }),
             ArgValue::Text("! Get me an ale of ".to_string()),
             ArgValue::Variable("beer".to_string()),
             ArgValue::Text("!".to_string()),
         ],
+        span: None,
     });
 }
 
@@ -462,6 +520,8 @@ fn test_roll_parse_step_p () {
         ],
         op: MacroOp::Roll,
         result: StepResult::Ignore,
+        span: None,
+        arg_spans: vec![],
     });
 }
 
@@ -498,6 +558,7 @@ fn test_arguments_roll_parser() {
     let (_, result) = arguments_roll_p(CompleteByteSlice(b"\"I am a comment\"")).unwrap();
     assert_eq!(result, Arg::Roll(RollArg::Comment(ArgValue::TextInterpolated(TextInterpolated {
         parts: vec![ ArgValue::Text("I am a comment".to_string()) ],
+        span: None,
     }))));
 
     let (_, result) = arguments_roll_p(CompleteByteSlice(b"[I am also a comment]")).unwrap();
@@ -517,8 +578,10 @@ fn test_arguments_roll_parser() {
                 name: "me".to_string(),
                 attribute: Some("attribute".to_string()),
                 macro_name: None,
+                span: None,
             }),
         ],
+        span: None,
     }))));
 
     // Modifier
@@ -547,6 +610,7 @@ fn test_arguments_roll_parser() {
         name: "me".to_string(),
         attribute: Some("dexterity".to_string()),
         macro_name: None,
+        span: None,
     }))));
 
     // Token argument
@@ -555,6 +619,7 @@ fn test_arguments_roll_parser() {
         name: "me".to_string(),
         attribute: None,
         macro_name: None,
+        span: None,
     }));
 
     // Variables
@@ -635,6 +700,7 @@ fn test_arguments_roll_parses_token_attributes() {
             name: "me".to_string(),
             attribute: Some("dexterity".to_string()),
             macro_name: None,
+            span: None,
         })))
     );
 }
@@ -661,9 +727,11 @@ fn it_parses_a_complete_say_command() {
                 name: "target".to_string(),
                 attribute: Some("name".to_string()),
                 macro_name: None,
+                span: None,
             }),
             ArgValue::Text("'s AC".to_string()),
         ],
+        span: None,
     })));
 }
 
@@ -681,6 +749,60 @@ fn it_parses_a_complete_roll_command() {
     ]);
 }
 
+#[test]
+fn it_parses_compound_rolls() {
+    let (_, result) = parse_roll_p(CompleteByteSlice(b"1d20 + 1d4 + @me.str_mod - 2")).unwrap();
+    assert_eq!(result[..3].to_vec(), vec![
+        Arg::Roll(RollArg::N(ArgValue::Number(1))),
+        Arg::Roll(RollArg::D(ArgValue::Number(20))),
+        Arg::Roll(RollArg::GroupPos(vec![
+            RollArg::N(ArgValue::Number(1)),
+            RollArg::D(ArgValue::Number(4)),
+        ])),
+    ]);
+    match result[3] {
+        Arg::Roll(RollArg::ModifierPos(ArgValue::Token(ref token))) => assert_eq!(token.name, "me".to_string()),
+        ref arg => panic!("unexpected {:?}", arg),
+    }
+    assert_eq!(result[4], Arg::Roll(RollArg::ModifierNeg(ArgValue::Number(2))));
+
+    // each group keeps its own flags, the comment belongs to the whole roll
+    let (_, result) = parse_roll_p(CompleteByteSlice(b"2d20kh1 - d6e6 ro<2 + 2d[0,1] 'fire'")).unwrap();
+    assert_eq!(result, vec![
+        Arg::Roll(RollArg::N(ArgValue::Number(2))),
+        Arg::Roll(RollArg::D(ArgValue::Number(20))),
+        Arg::Roll(RollArg::H(ArgValue::Number(1))),
+        Arg::Roll(RollArg::GroupNeg(vec![
+            RollArg::D(ArgValue::Number(6)),
+            RollArg::E(ArgValue::Number(6)),
+            RollArg::RO(Comparitive { op: ComparisonArg::LessThan, value: ArgValue::Number(2) }),
+        ])),
+        Arg::Roll(RollArg::GroupPos(vec![
+            RollArg::N(ArgValue::Number(2)),
+            RollArg::Sides(vec![ArgValue::Number(0), ArgValue::Number(1)]),
+        ])),
+        Arg::Roll(RollArg::Comment(ArgValue::Text("fire".to_string()))),
+    ]);
+}
+
+#[test]
+fn it_parses_success_counting_flags() {
+    let (_, result) = parse_roll_p(CompleteByteSlice(b"10d10cs>=8cf1gl<2")).unwrap();
+    assert_eq!(result[2..].to_vec(), vec![
+        Arg::Roll(RollArg::CS(Comparitive { op: ComparisonArg::GreaterThanOrEqual, value: ArgValue::Number(8) })),
+        Arg::Roll(RollArg::CF(Comparitive { op: ComparisonArg::LessThanOrEqual, value: ArgValue::Number(1) })),
+        Arg::Roll(RollArg::GL(Comparitive { op: ComparisonArg::LessThan, value: ArgValue::Number(2) })),
+    ]);
+
+    let (_, result) = parse_roll_p(CompleteByteSlice(b"5d6cs5 + $edge d6cs==6")).unwrap();
+    assert_eq!(result[2], Arg::Roll(RollArg::CS(Comparitive { op: ComparisonArg::GreaterThanOrEqual, value: ArgValue::Number(5) })));
+    assert_eq!(result[3], Arg::Roll(RollArg::GroupPos(vec![
+        RollArg::N(ArgValue::Variable("edge".to_string())),
+        RollArg::D(ArgValue::Number(6)),
+        RollArg::CS(Comparitive { op: ComparisonArg::EqualTo, value: ArgValue::Number(6) }),
+    ])));
+}
+
 #[test]
 fn it_parses_a_complete_hidden_roll_command() {
     // we should be able to combine strings
@@ -705,6 +827,7 @@ fn it_parses_a_complete_hidden_roll_command() {
             name: "me".to_string(),
             attribute: Some("dexterity".to_string()),
             macro_name: None,
+            span: None,
         }))),
         Arg::Roll(RollArg::Comment(ArgValue::Text("Comment with spaces".to_string())))
     ]);
@@ -722,6 +845,7 @@ fn it_parses_a_complete_whisper_roll_command() {
             name: "gm".to_string(),
             attribute: None,
             macro_name: None,
+            span: None,
         }),
         Arg::Roll(RollArg::N(ArgValue::Number(4))),
         Arg::Roll(RollArg::D(ArgValue::Number(20))),
@@ -736,6 +860,7 @@ fn test_arguments_whisper_parser() {
         parts: vec![
             ArgValue::Text("I am a message".to_string()),
         ],
+        span: None,
     })));
 
     let (_, result) = arguments_whisper_p(CompleteByteSlice(b"@me")).unwrap();
@@ -743,6 +868,7 @@ fn test_arguments_whisper_parser() {
         name: "me".to_string(),
         attribute: None,
         macro_name: None,
+        span: None,
     })));
 
     // we should be able to combine strings
@@ -752,40 +878,43 @@ fn test_arguments_whisper_parser() {
         name: "npc1".to_string(),
         attribute: None,
         macro_name: None,
+        span: None,
     })));
     assert_eq!(steps[0].args[1], Arg::Say(SayArg::Message(TextInterpolated {
         parts: vec! [
             ArgValue::Text("Rolled a ".to_string()),
             ArgValue::Variable("foo".to_string()),
         ],
+        span: None,
     })));
 }
 
 #[test]
 fn test_token_parser() {
     let (_, result) = token_p(CompleteByteSlice(b"@foo")).unwrap();
-    assert_eq!(result, TokenArg { name: "foo".to_string(), attribute: None, macro_name: None });
+    assert_eq!(result, TokenArg { name: "foo".to_string(), attribute: None, macro_name: None, span: None });
 
     let (_, result) = token_p(CompleteByteSlice(b"@{faz}")).unwrap();
-    assert_eq!(result, TokenArg { name: "faz".to_string(), attribute: None, macro_name: None });
+    assert_eq!(result, TokenArg { name: "faz".to_string(), attribute: None, macro_name: None, span: None });
 
     let (_, result) = token_p(CompleteByteSlice(b"@foo123bar.baz")).unwrap();
-    assert_eq!(result, TokenArg { name: "foo123bar".to_string(), attribute: Some("baz".to_string()), macro_name: None });
+    assert_eq!(result, TokenArg { name: "foo123bar".to_string(), attribute: Some("baz".to_string()), macro_name: None, span: None });
 
     let (_, result) = token_p(CompleteByteSlice(b"@foo_53_test")).unwrap();
-    assert_eq!(result, TokenArg { name: "foo_53_test".to_string(), attribute: None, macro_name: None });
+    assert_eq!(result, TokenArg { name: "foo_53_test".to_string(), attribute: None, macro_name: None, span: None });
 
     let (_, result) = token_p(CompleteByteSlice(b"@foo_bar.baz_bo")).unwrap();
-    assert_eq!(result, TokenArg { name: "foo_bar".to_string(), attribute: Some("baz_bo".to_string()), macro_name: None });
+    assert_eq!(result, TokenArg { name: "foo_bar".to_string(), attribute: Some("baz_bo".to_string()), macro_name: None, span: None });
 
     let (_, result) = token_p(CompleteByteSlice(b"@fooZ->my_test_func")).unwrap();
-    assert_eq!(result, TokenArg { name: "fooZ".to_string(), attribute: None, macro_name: Some("my_test_func".to_string()) });
+    assert_eq!(result, TokenArg { name: "fooZ".to_string(), attribute: None, macro_name: Some("my_test_func".to_string()), span: None });
 
     let (_, result) = token_p(CompleteByteSlice(b"@foo.{attacks.0.bar}")).unwrap();
     assert_eq!(result, TokenArg {
         name: "foo".to_string(), 
         attribute: Some("attacks.0.bar".to_string()),
         macro_name: None,
+        span: None,
     });
 
     let (_, result) = token_p(CompleteByteSlice(b"@{foo}.{0.1}")).unwrap();
@@ -793,7 +922,17 @@ fn test_token_parser() {
         name: "foo".to_string(), 
         attribute: Some("0.1".to_string()),
         macro_name: None,
+        span: None,
+    });
+
+    let (rest, result) = token_p(CompleteByteSlice(b"@me.spells.0.level. ")).unwrap();
+    assert_eq!(result, TokenArg {
+        name: "me".to_string(),
+        attribute: Some("spells.0.level".to_string()),
+        macro_name: None,
+        span: None,
     });
+    assert_eq!(rest, CompleteByteSlice(b". "));
 }
 
 #[test]
@@ -832,6 +971,7 @@ fn test_assign_token_parser() {
             name: "me".to_string(),
             attribute: Some("test".to_string()),
             macro_name: None,
+            span: None,
         }),
         right: vec![ ArgValue::Text("foo".to_string()) ],
     });
@@ -844,9 +984,11 @@ fn test_assign_token_parser() {
             name: "me".to_string(),
             attribute: Some("test".to_string()),
             macro_name: None,
+            span: None,
         }),
         right: vec![ ArgValue::TextInterpolated(TextInterpolated {
             parts: vec! [ ArgValue::Text("foo".to_string()) ],
+            span: None,
         }) ],
     });
 
@@ -859,6 +1001,7 @@ fn test_assign_token_parser() {
             name: "me".to_string(),
             attribute: Some("test".to_string()),
             macro_name: None,
+            span: None,
         }),
         right: vec![ ArgValue::Number(42) ],
     });
@@ -872,6 +1015,7 @@ fn test_assign_token_parser() {
             name: "me".to_string(),
             attribute: Some("test".to_string()),
             macro_name: None,
+            span: None,
         }),
         right: vec![ ArgValue::Float(-124.222) ],
     });
@@ -885,11 +1029,14 @@ fn test_assign_token_parser() {
             name: "me".to_string(),
             attribute: Some("bar".to_string()),
             macro_name: None,
+            span: None,
         }),
         right: vec![
-            ArgValue::Number(1),
-            ArgValue::Primitive(Primitive::Divide),
-            ArgValue::Number(3),
+            ArgValue::Expr(Box::new(Expr::Binary(
+                Box::new(Expr::Value(ArgValue::Number(1))),
+                Primitive::Divide,
+                Box::new(Expr::Value(ArgValue::Number(3))),
+            ))),
         ],
     });
 
@@ -906,6 +1053,7 @@ fn test_assign_token_parser() {
             name: "me".to_string(),
             attribute: Some("attacks".to_string()),
             macro_name: None,
+            span: None,
         }),
         right: vec![
             ArgValue::Array(vec![
@@ -936,6 +1084,7 @@ fn test_assign_variable_parser() {
                 parts: vec![
                     ArgValue::Text("foo".to_string()),
                 ],
+                span: None,
             }),
         ],
     });
@@ -956,9 +1105,11 @@ fn test_assign_variable_parser() {
     let assign = Arg::Assign(Assign {
         left: ArgValue::Variable("foo".to_string()),
         right: vec![
-            ArgValue::Number(1),
-            ArgValue::Primitive(Primitive::Add),
-            ArgValue::Number(2),
+            ArgValue::Expr(Box::new(Expr::Binary(
+                Box::new(Expr::Value(ArgValue::Number(1))),
+                Primitive::Add,
+                Box::new(Expr::Value(ArgValue::Number(2))),
+            ))),
         ],
     });
 
@@ -996,6 +1147,7 @@ fn test_arguments_prompt_parser() {
             parts: vec![
                 ArgValue::Text("Choose your style".to_string()),
             ],
+            span: None,
         },
         options,
     });
@@ -1011,12 +1163,14 @@ fn test_arguments_prompt_parser() {
                 name: "me".to_string(),
                 attribute: Some("attribute".to_string()),
                 macro_name: None,
+                span: None,
             })
         },
         SwitchOption {
             key: Some("baz".to_string()),
             value: ArgValue::TextInterpolated(TextInterpolated {
                 parts: vec![ ArgValue::Text("boo".to_string()) ],
+                span: None,
             }),
         },
     ];
@@ -1026,6 +1180,7 @@ fn test_arguments_prompt_parser() {
             parts: vec![
                 ArgValue::Text("Choose your thing".to_string()),
             ],
+            span: None,
         },
         options,
     });
@@ -1041,6 +1196,7 @@ fn test_parse_options() {
             key: Some("2".to_string()),
             value: ArgValue::TextInterpolated(TextInterpolated {
                 parts: vec![ ArgValue::Text("30 ft. Cone".to_string()) ],
+                span: None,
             }),
         },
     ];
@@ -1050,6 +1206,7 @@ fn test_parse_options() {
             parts: vec![
                 ArgValue::Text("Choose a type".to_string()),
             ],
+            span: None,
         },
         options,
     });
@@ -1083,12 +1240,14 @@ fn test_arguments_case_parser() {
                 name: "me".to_string(),
                 attribute: Some("attribute".to_string()),
                 macro_name: None,
+                span: None,
             })
         },
         SwitchOption {
             key: Some("baz".to_string()),
             value: ArgValue::TextInterpolated(TextInterpolated {
                 parts: vec![ ArgValue::Text("boo".to_string()) ],
+                span: None,
             }),
         },
     ];
@@ -1106,25 +1265,28 @@ fn test_conditional_parser() {
     // compare greater than
     let (_, result) = arguments_p(CompleteByteSlice(b"$foo > 1 ? !r 1d20 : !r 1d8")).unwrap();
     let compare = Arg::Conditional(Conditional {
-        left: ArgValue::Variable("foo".to_string()),
-        comparison: ComparisonArg::GreaterThan,
-        right: ArgValue::Number(1),
-        success: Some(Step {
+        condition: Condition::Compare(Box::new(Comparison { left: ArgValue::Variable("foo".to_string()), comparison: ComparisonArg::GreaterThan, right: ArgValue::Number(1) })),
+        success: vec![Step {
             args: vec![
                 Arg::Roll(RollArg::N(ArgValue::Number(1))),
                 Arg::Roll(RollArg::D(ArgValue::Number(20)))
             ],
             op: MacroOp::Roll,
             result: StepResult::Ignore,
-        }),
-        failure: Some(Step {
+            span: None,
+            arg_spans: vec![],
+        }],
+        else_if: vec![],
+        failure: vec![Step {
             args: vec![
                 Arg::Roll(RollArg::N(ArgValue::Number(1))),
                 Arg::Roll(RollArg::D(ArgValue::Number(8)))
             ],
             op: MacroOp::Roll,
             result: StepResult::Ignore,
-        }),
+            span: None,
+            arg_spans: vec![],
+        }],
     });
 
     assert_eq!(result, compare);
@@ -1132,36 +1294,38 @@ fn test_conditional_parser() {
     // ignoring results
     let (_, result) = arguments_p(CompleteByteSlice(b"$foo <= 5 ? !r 1d20 : |")).unwrap();
     let compare = Arg::Conditional(Conditional {
-        left: ArgValue::Variable("foo".to_string()),
-        comparison: ComparisonArg::LessThanOrEqual,
-        right: ArgValue::Number(5),
-        success: Some(Step {
+        condition: Condition::Compare(Box::new(Comparison { left: ArgValue::Variable("foo".to_string()), comparison: ComparisonArg::LessThanOrEqual, right: ArgValue::Number(5) })),
+        success: vec![Step {
             args: vec![
                 Arg::Roll(RollArg::N(ArgValue::Number(1))),
                 Arg::Roll(RollArg::D(ArgValue::Number(20)))
             ],
             op: MacroOp::Roll,
             result: StepResult::Ignore,
-        }),
-        failure: None,
+            span: None,
+            arg_spans: vec![],
+        }],
+        else_if: vec![],
+        failure: vec![],
     });
 
     assert_eq!(result, compare);
 
     let (_, result) = arguments_p(CompleteByteSlice(b"$foo >= -5 ? | : !r 1d20")).unwrap();
     let compare = Arg::Conditional(Conditional {
-        left: ArgValue::Variable("foo".to_string()),
-        comparison: ComparisonArg::GreaterThanOrEqual,
-        right: ArgValue::Number(-5),
-        success: None,
-        failure: Some(Step {
+        condition: Condition::Compare(Box::new(Comparison { left: ArgValue::Variable("foo".to_string()), comparison: ComparisonArg::GreaterThanOrEqual, right: ArgValue::Number(-5) })),
+        success: vec![],
+        else_if: vec![],
+        failure: vec![Step {
             args: vec![
                 Arg::Roll(RollArg::N(ArgValue::Number(1))),
                 Arg::Roll(RollArg::D(ArgValue::Number(20)))
             ],
             op: MacroOp::Roll,
             result: StepResult::Ignore,
-        }),
+            span: None,
+            arg_spans: vec![],
+        }],
     });
 
     assert_eq!(result, compare);
@@ -1169,10 +1333,8 @@ fn test_conditional_parser() {
     // equal to
     let (_, result) = arguments_p(CompleteByteSlice(b"$foo == 10 ? !r 1d20+5 : !r 1d20")).unwrap();
     let compare = Arg::Conditional(Conditional {
-        left: ArgValue::Variable("foo".to_string()),
-        comparison: ComparisonArg::EqualTo,
-        right: ArgValue::Number(10),
-        success: Some(Step {
+        condition: Condition::Compare(Box::new(Comparison { left: ArgValue::Variable("foo".to_string()), comparison: ComparisonArg::EqualTo, right: ArgValue::Number(10) })),
+        success: vec![Step {
             args: vec![
                 Arg::Roll(RollArg::N(ArgValue::Number(1))),
                 Arg::Roll(RollArg::D(ArgValue::Number(20))),
@@ -1180,15 +1342,20 @@ fn test_conditional_parser() {
             ],
             op: MacroOp::Roll,
             result: StepResult::Ignore,
-        }),
-        failure: Some(Step {
+            span: None,
+            arg_spans: vec![],
+        }],
+        else_if: vec![],
+        failure: vec![Step {
             args: vec![
                 Arg::Roll(RollArg::N(ArgValue::Number(1))),
                 Arg::Roll(RollArg::D(ArgValue::Number(20))),
             ],
             op: MacroOp::Roll,
             result: StepResult::Ignore,
-        }),
+            span: None,
+            arg_spans: vec![],
+        }],
     });
 
     assert_eq!(result, compare);
@@ -1198,10 +1365,8 @@ fn test_conditional_parser() {
 fn test_conditional_parser_does_assignments() {
     let (_, result) = arguments_p(CompleteByteSlice(b"10 == 10 ? $foo = 1 : $foo = 2")).unwrap();
     let compare = Arg::Conditional(Conditional {
-        left: ArgValue::Number(10),
-        comparison: ComparisonArg::EqualTo,
-        right: ArgValue::Number(10),
-        success: Some(Step {
+        condition: Condition::Compare(Box::new(Comparison { left: ArgValue::Number(10), comparison: ComparisonArg::EqualTo, right: ArgValue::Number(10) })),
+        success: vec![Step {
             args: vec![
                 Arg::Assign(Assign {
                     left: ArgValue::Variable("foo".to_string()),
@@ -1210,8 +1375,11 @@ fn test_conditional_parser_does_assignments() {
             ],
             op: MacroOp::Lambda,
             result: StepResult::Ignore,
-        }),
-        failure: Some(Step {
+            span: None,
+            arg_spans: vec![],
+        }],
+        else_if: vec![],
+        failure: vec![Step {
             args: vec![
                 Arg::Assign(Assign {
                     left: ArgValue::Variable("foo".to_string()),
@@ -1220,25 +1388,31 @@ fn test_conditional_parser_does_assignments() {
             ],
             op: MacroOp::Lambda,
             result: StepResult::Ignore,
-        }),
+            span: None,
+            arg_spans: vec![],
+        }],
     });
 
     assert_eq!(result, compare);
 
     let (_, result) = arguments_p(CompleteByteSlice(b"@me.bar >= @me.foo ? $foo = 1 : $foo = 2")).unwrap();
     let compare = Arg::Conditional(Conditional {
-        left: ArgValue::Token(TokenArg {
-            name: "me".to_string(),
-            attribute: Some("bar".to_string()),
-            macro_name: None,
-        }),
-        comparison: ComparisonArg::GreaterThanOrEqual,
-        right: ArgValue::Token(TokenArg {
-            name: "me".to_string(),
-            attribute: Some("foo".to_string()),
-            macro_name: None,
-        }),
-        success: Some(Step {
+        condition: Condition::Compare(Box::new(Comparison {
+            left: ArgValue::Token(TokenArg {
+                name: "me".to_string(),
+                attribute: Some("bar".to_string()),
+                macro_name: None,
+                span: None,
+            }),
+            comparison: ComparisonArg::GreaterThanOrEqual,
+            right: ArgValue::Token(TokenArg {
+                name: "me".to_string(),
+                attribute: Some("foo".to_string()),
+                macro_name: None,
+                span: None,
+            }),
+        })),
+        success: vec![Step {
             args: vec![
                 Arg::Assign(Assign {
                     left: ArgValue::Variable("foo".to_string()),
@@ -1247,8 +1421,11 @@ fn test_conditional_parser_does_assignments() {
             ],
             op: MacroOp::Lambda,
             result: StepResult::Ignore,
-        }),
-        failure: Some(Step {
+            span: None,
+            arg_spans: vec![],
+        }],
+        else_if: vec![],
+        failure: vec![Step {
             args: vec![
                 Arg::Assign(Assign {
                     left: ArgValue::Variable("foo".to_string()),
@@ -1257,7 +1434,9 @@ fn test_conditional_parser_does_assignments() {
             ],
             op: MacroOp::Lambda,
             result: StepResult::Ignore,
-        }),
+            span: None,
+            arg_spans: vec![],
+        }],
     });
 
     assert_eq!(result, compare);
@@ -1287,10 +1466,12 @@ fn test_json_parser() {
         name: "me".to_string(),
         attribute: Some("attribute".to_string()),
         macro_name: None,
+        span: None,
     }));
     object.insert("bar".to_string(), ArgValue::Text("Single quoted string".to_string()));
     object.insert("baz".to_string(), ArgValue::TextInterpolated(TextInterpolated {
         parts: vec![ ArgValue::Text("String interpolated".to_string()) ],
+        span: None,
     }));
     object.insert("boo".to_string(), ArgValue::Float(-45.2));
     object.insert("far".to_string(), ArgValue::Object(nested_object));
@@ -1315,6 +1496,7 @@ fn test_template_parser() {
         parts: vec![
             ArgValue::Text("bar".to_string()),
         ],
+        span: None,
     }));
     let (_, result) = arguments_template_p(CompleteByteSlice(r#" {
         foo: "bar"
@@ -1385,6 +1567,7 @@ fn test_concat_parser() {
                 name: "token".to_string(),
                 attribute: Some("hp".to_string()),
                 macro_name: None,
+                span: None,
             }),
             right: vec![
                 ArgValue::Number(5)
@@ -1421,6 +1604,7 @@ fn test_deduct_parser() {
                 name: "token".to_string(),
                 attribute: Some("hp".to_string()),
                 macro_name: None,
+                span: None,
             }),
             right: vec![
                 ArgValue::Number(15)
@@ -1445,13 +1629,18 @@ fn test_assign_command() {
                             ],
                             op: MacroOp::Roll,
                             result: StepResult::Ignore,
+                            span: None,
+                            arg_spans: vec![],
                         })
                     ]
                 })
             ],
             op: MacroOp::Lambda,
             result: StepResult::Ignore,
+            span: None,
+            arg_spans: vec![],
         }],
+        docs: vec![],
     };
     let (_, result) = parse_p(CompleteByteSlice(b"#assign-command $foo = !roll 1d20")).unwrap();
     assert_eq!(result, program);
@@ -1473,19 +1662,20 @@ fn test_assign_in_comparison_with_command() {
                         parts: vec![
                             ArgValue::Text("Test this function".to_string()),
                         ],
+                        span: None,
                     },
                     options,
                 })
             ],
             op: MacroOp::Prompt,
             result: StepResult::Save,
+            span: None,
+            arg_spans: vec![],
         }, Step {
             args: vec![
                 Arg::Conditional(Conditional {
-                    left: ArgValue::VariableReserved(0),
-                    comparison: ComparisonArg::EqualTo,
-                    right: ArgValue::Number(0),
-                    success: Some(Step {
+                    condition: Condition::Compare(Box::new(Comparison { left: ArgValue::VariableReserved(0), comparison: ComparisonArg::EqualTo, right: ArgValue::Number(0) })),
+                    success: vec![Step {
                         args: vec![
                             Arg::Assign(Assign {
                                 left: ArgValue::Variable("foo".to_string()),
@@ -1497,14 +1687,19 @@ fn test_assign_in_comparison_with_command() {
                                         ],
                                         op: MacroOp::Roll,
                                         result: StepResult::Ignore,
+                                        span: None,
+                                        arg_spans: vec![],
                                     })
                                 ]
                             })
                         ],
                         op: MacroOp::Lambda,
                         result: StepResult::Ignore,
-                    }),
-                    failure: Some(Step {
+                        span: None,
+                        arg_spans: vec![],
+                    }],
+                    else_if: vec![],
+                    failure: vec![Step {
                         args: vec![
                             Arg::Assign(Assign {
                                 left: ArgValue::Variable("foo".to_string()),
@@ -1515,11 +1710,15 @@ fn test_assign_in_comparison_with_command() {
                         ],
                         op: MacroOp::Lambda,
                         result: StepResult::Ignore,
-                    }),
+                        span: None,
+                        arg_spans: vec![],
+                    }],
                 }),
             ],
             op: MacroOp::Lambda,
             result: StepResult::Ignore,
+            span: None,
+            arg_spans: vec![],
         }, Step {
             args: vec![
                 Arg::Roll(RollArg::N(ArgValue::Number(1))),
@@ -1527,7 +1726,10 @@ fn test_assign_in_comparison_with_command() {
             ],
             op: MacroOp::Roll,
             result: StepResult::Ignore,
+            span: None,
+            arg_spans: vec![],
         }],
+        docs: vec![],
     };
     let (_, result) = parse_p(CompleteByteSlice(
         b"#complex-assign-command !prompt 'Test this function' [0:'Ok', 1:'No'] >> ${0} == 0 ? $foo = !roll 1d20 : $foo = ${0} | !roll 1d8"
@@ -1545,6 +1747,7 @@ fn test_function_parser() {
                 name: "me".to_string(),
                 attribute: Some("items".to_string()),
                 macro_name: None,
+                span: None,
             }),
             right: vec![
                 ArgValue::Step(Step {
@@ -1554,6 +1757,8 @@ fn test_function_parser() {
                     ],
                     op: MacroOp::Function("get".to_string()),
                     result: StepResult::Ignore,
+                    span: None,
+                    arg_spans: vec![],
                 })
             ]
         }
@@ -1567,6 +1772,7 @@ fn test_function_parser() {
                 name: "me".to_string(),
                 attribute: Some("attacks".to_string()),
                 macro_name: None,
+                span: None,
             }),
             right: vec![
                 ArgValue::Step(Step {
@@ -1577,13 +1783,525 @@ fn test_function_parser() {
                         Arg::Function(ArgValue::TextInterpolated(TextInterpolated {
                             parts: vec![
                                 ArgValue::Text("boo".to_string()),
-                            ]
+                            ],
+                            span: None,
                         }))
                     ],
                     op: MacroOp::Function("custom_function".to_string()),
                     result: StepResult::Ignore,
+                    span: None,
+                    arg_spans: vec![],
                 })
             ]
         }
     ));
 }
+
+#[test]
+fn it_describes_parse_errors() {
+    let error = parse_program(b"#test\n!say 'ok'\n!say \"hi").unwrap_err();
+    assert_eq!((error.line, error.column, error.offset), (3, 6, 21));
+    assert_eq!(error.to_string(), "line 3, col 6: unterminated double-quoted string".to_string());
+
+    let error = parse_program(b"test !say 'hi'").unwrap_err();
+    assert_eq!(error.construct, Some(Construct::Name));
+
+    let error = parse_program(b"#test !bogus 1").unwrap_err();
+    assert_eq!(error.construct, Some(Construct::Command));
+    assert_eq!(error.message, "unrecognized command !bogus".to_string());
+    assert!(error.expected.contains(&"!roll".to_string()));
+
+    let error = parse_program(b"#test $1 > ? !say 'x'").unwrap_err();
+    assert_eq!(error.construct, Some(Construct::Conditional));
+
+    let error = parse_program(b"#test !prompt 'Pick' [a, b").unwrap_err();
+    assert_eq!(error.construct, Some(Construct::PromptOptions));
+    assert_eq!(error.expected, vec!["]".to_string()]);
+
+    let error = parse_program(b"#test !input hello").unwrap_err();
+    assert_eq!(error.construct, Some(Construct::InputMessage));
+    assert_eq!(error.column, 14);
+
+    assert!(parse_program(b"#test !say 'hi' !roll 1d20 \n").is_ok());
+}
+
+#[test]
+fn it_rejects_numbers_too_large() {
+    assert!(num_p(CompleteByteSlice(b"99999999999")).is_err());
+    assert!(roll_digit_p(CompleteByteSlice(b"99999999999")).is_err());
+    assert!(variable_reserved_p(CompleteByteSlice(b"$99999")).is_err());
+    assert_eq!(num_p(CompleteByteSlice(b"-2147483647")).unwrap().1, -2147483647);
+
+    let error = parse_program(b"#t !r 99999999999d6").unwrap_err();
+    assert_eq!(error.to_string(), "line 1, col 7: number is too large (the largest is 2147483647)".to_string());
+    let error = parse_program(b"#t !r 1d99999999999").unwrap_err();
+    assert_eq!(error.to_string(), "line 1, col 9: number is too large (the largest is 2147483647)".to_string());
+}
+
+#[test]
+fn it_recovers_from_parse_errors() {
+    let partial = parse_recovering(b"#test !say 'one' !bogus 1 !say 'two' | !input hello !say \"three");
+    let messages: Vec<String> = partial.errors.iter().map(|error| error.to_string()).collect();
+    assert_eq!(messages, vec![
+        "line 1, col 18: unrecognized command !bogus".to_string(),
+        "line 1, col 47: expected a quoted message".to_string(),
+        "line 1, col 58: unterminated double-quoted string".to_string(),
+    ]);
+
+    let ops: Vec<MacroOp> = partial.program.steps.iter().map(|step| step.op.clone()).collect();
+    assert_eq!(ops, vec![MacroOp::Say, MacroOp::Say]);
+
+    // a step that does not parse is left out, only its error is kept
+    let partial = parse_recovering(b"#test !say \"hello");
+    assert_eq!(partial.errors.len(), 1);
+    assert_eq!(partial.errors[0].message, "unterminated double-quoted string".to_string());
+    assert!(partial.program.steps.is_empty());
+
+    let partial = parse_recovering(b"#test !say 'one' | hello !say 'two'");
+    assert_eq!(partial.errors[0].to_string(), "line 1, col 20: unexpected \"hello\"".to_string());
+    let ops: Vec<MacroOp> = partial.program.steps.iter().map(|step| step.op.clone()).collect();
+    assert_eq!(ops, vec![MacroOp::Say, MacroOp::Say]);
+
+    // a missing name is reported and the steps are still parsed
+    let partial = parse_recovering(b"test !say 'one'");
+    assert_eq!(partial.errors[0].construct, Some(Construct::Name));
+    assert_eq!(partial.program.name, MacroOp::Name(String::new()));
+    assert_eq!(partial.program.steps.len(), 1);
+
+    let partial = parse_recovering(b"#test !say 'one' >> !roll 1d20");
+    assert!(partial.errors.is_empty());
+    assert_eq!(partial.program.steps.len(), 2);
+}
+
+#[test]
+fn it_records_spans() {
+    let input = "#test !r 1d20 + @me.dexterity >> !say \"Hit @target.name\" | $foo = 2";
+    let program = parse_program(input.as_bytes()).unwrap();
+
+    let roll = &program.steps[0];
+    assert_eq!(roll.span.unwrap().text(input), "!r 1d20 + @me.dexterity");
+    let args: Vec<&str> = roll.arg_spans.iter().map(|span| span.text(input)).collect();
+    assert_eq!(args, vec!["1", "d20", "+ @me.dexterity"]);
+    match roll.args[2] {
+        Arg::Roll(RollArg::ModifierPos(ArgValue::Token(ref token))) => {
+            assert_eq!(token.span.unwrap().text(input), "@me.dexterity");
+        },
+        ref arg => panic!("unexpected {:?}", arg),
+    }
+
+    match program.steps[1].args[0] {
+        Arg::Say(SayArg::Message(ref text)) => {
+            assert_eq!(text.span.unwrap().text(input), "\"Hit @target.name\"");
+            match text.parts[1] {
+                ArgValue::Token(ref token) => assert_eq!(token.span, Some(Span { start: 43, end: 55 })),
+                ref part => panic!("unexpected {:?}", part),
+            }
+        },
+        ref arg => panic!("unexpected {:?}", arg),
+    }
+    assert_eq!(program.steps[2].span.unwrap().text(input), "$foo = 2");
+
+    // parsers for parts of a macro return offsets in their own input
+    let (_, token) = token_p(CompleteByteSlice(b" @me.dexterity + 1")).unwrap();
+    assert_eq!(token.span, Some(Span { start: 1, end: 14 }));
+    let (_, step) = parse_step_p(CompleteByteSlice(b"!say \"Hi @me\" >> !r 1d20")).unwrap();
+    assert_eq!(step.span, Some(Span { start: 0, end: 13 }));
+    assert_eq!(step.arg_spans, vec![Span { start: 5, end: 13 }]);
+    match step.args[0] {
+        Arg::Say(SayArg::Message(ref text)) => match text.parts[1] {
+            ArgValue::Token(ref token) => assert_eq!(token.span, Some(Span { start: 9, end: 12 })),
+            ref part => panic!("unexpected {:?}", part),
+        },
+        ref arg => panic!("unexpected {:?}", arg),
+    }
+
+    // spans are not compared, so layout does not matter
+    assert_eq!(parse_program(b"#test   !r   1d20").unwrap(), parse_program(b"#test !r 1d20").unwrap());
+}
+
+#[test]
+fn it_parses_the_documented_expressions_and_conditionals() {
+    let api = include_str!("../API.md");
+    let start = api.find("\n# Expressions\n").unwrap();
+    let end = api.find("\n# Tokens\n").unwrap();
+
+    let mut examples = 0;
+    for block in api[start..end].split("```bash\n").skip(1) {
+        let block = &block[..block.find("```").unwrap()];
+        for line in block.lines().filter(|line| !line.trim().is_empty()) {
+            let input = format!("#test {}", line);
+            assert!(parse_program(input.as_bytes()).is_ok(), "documented example does not parse: {}", line);
+            examples += 1;
+        }
+    }
+    assert!(examples > 10);
+}
+
+#[test]
+fn it_parses_libraries() {
+    let input = b"#attack
+!r 1d20+5 >> !say \"Hit $1\"
+
+#heal $hp = 3
+!say 'Healed'
+#check !r 1d20 >> $1 > 10 ? !say 'Pass' : !say 'Fail'
+";
+    let library = parse_library(input).unwrap();
+    assert_eq!(library.names(), vec!["attack", "heal", "check"]);
+    assert_eq!(library.get("attack").unwrap().steps.len(), 2);
+    assert_eq!(library.get("heal").unwrap().steps[0].op, MacroOp::Lambda);
+    assert_eq!(library.get("check").unwrap().steps.len(), 2);
+    assert_eq!(library.get("missing"), None);
+
+    let error = parse_library(b"#attack !r 1d20\n#heal !r 2d4\n#attack !r 1d4").unwrap_err();
+    assert_eq!(error.to_string(), "line 3, col 1: duplicate macro name #attack, first defined on line 1".to_string());
+    assert_eq!(error.construct, Some(Construct::Name));
+
+    let error = parse_library(b"#attack !r 1d20\n#heal !bogus").unwrap_err();
+    assert_eq!((error.line, error.column), (2, 7));
+
+    assert_eq!(parse_library(b" \n ").unwrap(), Library::default());
+}
+
+#[test]
+fn it_parses_indexes_and_lengths() {
+    let index = |value: ArgValue, key: ArgValue| ArgValue::Index(Box::new(value), Box::new(key));
+    let list = ArgValue::Variable("list".to_string());
+
+    let (_, result) = indexed_p(CompleteByteSlice(b"$list[0]")).unwrap();
+    assert_eq!(result, index(list.clone(), ArgValue::Number(0)));
+    let (_, result) = indexed_p(CompleteByteSlice(b"$stats.str")).unwrap();
+    assert_eq!(result, index(ArgValue::Variable("stats".to_string()), ArgValue::Text("str".to_string())));
+    let (_, result) = indexed_p(CompleteByteSlice(b"$party[$i + 1]['name']")).unwrap();
+    match result {
+        ArgValue::Index(ref value, ref key) => {
+            assert_eq!(**key, ArgValue::Text("name".to_string()));
+            match **value {
+                ArgValue::Index(_, ref key) => match **key {
+                    ArgValue::Expr(_) => {},
+                    ref key => panic!("unexpected {:?}", key),
+                },
+                ref value => panic!("unexpected {:?}", value),
+            }
+        },
+        ref value => panic!("unexpected {:?}", value),
+    }
+    let (_, result) = indexed_p(CompleteByteSlice(b"@me.inventory[1]")).unwrap();
+    match result {
+        ArgValue::Index(ref value, _) => match **value {
+            ArgValue::Token(ref token) => assert_eq!(token.attribute, Some("inventory".to_string())),
+            ref value => panic!("unexpected {:?}", value),
+        },
+        ref value => panic!("unexpected {:?}", value),
+    }
+
+    let (_, result) = length_p(CompleteByteSlice(b"len($list)")).unwrap();
+    assert_eq!(result, ArgValue::Length(Box::new(list.clone())));
+    let (_, result) = length_p(CompleteByteSlice(b"len([1, 2])")).unwrap();
+    assert_eq!(result, ArgValue::Length(Box::new(ArgValue::Array(vec![ArgValue::Number(1), ArgValue::Number(2)]))));
+
+    // in assignments, conditionals, interpolation and rolls
+    let (_, result) = assignment_p(CompleteByteSlice(b"$last = $list[len($list) - 1]")).unwrap();
+    match result.right[0] {
+        ArgValue::Index(_, ref key) => match **key {
+            ArgValue::Expr(_) => {},
+            ref key => panic!("unexpected {:?}", key),
+        },
+        ref value => panic!("unexpected {:?}", value),
+    }
+    let (_, result) = conditional_p(CompleteByteSlice(b"$list == [1, 2] && $stats.str > 10 ? !say 'yes' : |")).unwrap();
+    match result.condition {
+        Condition::And(ref left, _) => match **left {
+            Condition::Compare(ref compare) => assert_eq!(compare.right, ArgValue::Array(vec![ArgValue::Number(1), ArgValue::Number(2)])),
+            ref condition => panic!("unexpected {:?}", condition),
+        },
+        ref condition => panic!("unexpected {:?}", condition),
+    }
+    let (_, result) = quoted_interpolated_p(CompleteByteSlice(b"\"First is $list[0], str is $stats.str.\"")).unwrap();
+    assert_eq!(result.parts[1], index(list.clone(), ArgValue::Number(0)));
+    assert_eq!(result.parts[3], index(ArgValue::Variable("stats".to_string()), ArgValue::Text("str".to_string())));
+    assert_eq!(result.parts[4], ArgValue::Text(".".to_string()));
+    let (_, result) = parse_roll_p(CompleteByteSlice(b"len($list)d$dice[0]")).unwrap();
+    assert_eq!(result, vec![
+        Arg::Roll(RollArg::N(ArgValue::Length(Box::new(list.clone())))),
+        Arg::Roll(RollArg::D(index(ArgValue::Variable("dice".to_string()), ArgValue::Number(0)))),
+    ]);
+}
+
+#[test]
+fn it_decodes_escapes() {
+    let (_, result) = quoted_interpolated_p(CompleteByteSlice(br#""Say \"hi\" to \@gm, it costs \$5\n\u{2694} \\ $1""#)).unwrap();
+    assert_eq!(result.parts, vec![
+        ArgValue::Text("Say \"hi\" to @gm, it costs $5\n\u{2694} \\ ".to_string()),
+        ArgValue::VariableReserved(1),
+    ]);
+
+    let (_, result) = single_quoted_p(CompleteByteSlice(br#"'don\'t'"#)).unwrap();
+    assert_eq!(result, "don't".to_string());
+    let (_, result) = double_quoted_p(CompleteByteSlice(br#""a \"b\"""#)).unwrap();
+    assert_eq!(result, "a \"b\"".to_string());
+    let (_, result) = single_quoted_p(CompleteByteSlice(b"''")).unwrap();
+    assert_eq!(result, "".to_string());
+
+    // backslashes that do not start an escape are kept
+    let (_, result) = single_quoted_p(CompleteByteSlice(br#"'C:\dice \u{zz}'"#)).unwrap();
+    assert_eq!(result, r#"C:\dice \u{zz}"#.to_string());
+
+    // escaped quotes do not end strings when skipping comments or recovering
+    let program = parse_program(br#"#test !say "a \" // b" // c"#).unwrap();
+    assert_eq!(program.steps[0].args[0], Arg::Say(SayArg::Message(TextInterpolated {
+        parts: vec![ ArgValue::Text("a \" // b".to_string()) ],
+        span: None,
+    })));
+    let error = parse_program(br#"#test !say "a \" b"#).unwrap_err();
+    assert_eq!(error.message, "unterminated double-quoted string".to_string());
+    assert_eq!(error.offset, 11);
+}
+
+#[test]
+fn it_skips_comments() {
+    let input = "/// Roll to hit
+/// and say the result
+#attack // the name
+!r 1d20 /* to hit */ + 5 >> // saved as $1
+/* a block
+   over two lines */
+!say \"Hit for $1, // not a comment\"";
+    let program = parse_program(input.as_bytes()).unwrap();
+    assert_eq!(program.docs, vec!["Roll to hit".to_string(), "and say the result".to_string()]);
+    assert_eq!(program.steps.len(), 2);
+    assert_eq!(program.steps[0].args.len(), 3);
+    assert_eq!(program.steps[0].span.unwrap().text(input), "!r 1d20 /* to hit */ + 5");
+    assert_eq!(program.steps[1].args[0], Arg::Say(SayArg::Message(TextInterpolated {
+        parts: vec![
+            ArgValue::Text("Hit for ".to_string()),
+            ArgValue::VariableReserved(1),
+            ArgValue::Text(", // not a comment".to_string()),
+        ],
+        span: None,
+    })));
+
+    let error = parse_program(b"#test !say 'hi' /* never closed").unwrap_err();
+    assert_eq!(error.to_string(), "line 1, col 17: unterminated block comment".to_string());
+
+    // errors after a comment are still placed by character
+    let error = parse_program("#test /* é */ !bogus".as_bytes()).unwrap_err();
+    assert_eq!(error.column, 15);
+
+    let library = parse_library(b"/** Swing\n * twice */\n#attack !r 2d6 // damage\n/// Heal up\n#heal !r 1d4").unwrap();
+    assert_eq!(library.get("attack").unwrap().docs, vec!["Swing\ntwice".to_string()]);
+    assert_eq!(library.get("heal").unwrap().docs, vec!["Heal up".to_string()]);
+}
+
+#[test]
+fn it_parses_expressions() {
+    let value = |n| Box::new(Expr::Value(ArgValue::Number(n)));
+
+    // * binds tighter than +
+    let (_, result) = expression_p(CompleteByteSlice(b"2 + 3 * 4")).unwrap();
+    assert_eq!(result, ArgValue::Expr(Box::new(Expr::Binary(
+        value(2),
+        Primitive::Add,
+        Box::new(Expr::Binary(value(3), Primitive::Multiply, value(4))),
+    ))));
+
+    // parentheses and unary minus
+    let (_, result) = expression_p(CompleteByteSlice(b"-(2 + 3) % 4")).unwrap();
+    assert_eq!(result, ArgValue::Expr(Box::new(Expr::Binary(
+        Box::new(Expr::Negate(Box::new(Expr::Binary(value(2), Primitive::Add, value(3))))),
+        Primitive::Modulo,
+        value(4),
+    ))));
+
+    let (_, result) = expression_p(CompleteByteSlice(b"(@me.strength - 10) \\ 2")).unwrap();
+    match result {
+        ArgValue::Expr(ref expr) => match **expr {
+            Expr::Binary(_, ref op, _) => assert_eq!(*op, Primitive::IntegerDivide),
+            ref expr => panic!("unexpected {:?}", expr),
+        },
+        ref value => panic!("unexpected {:?}", value),
+    }
+
+    // a single value is not wrapped
+    let (_, result) = expression_p(CompleteByteSlice(b"$foo")).unwrap();
+    assert_eq!(result, ArgValue::Variable("foo".to_string()));
+
+    let (_, result) = arguments_roll_p(CompleteByteSlice(b"+ @me.level * 2")).unwrap();
+    match result {
+        Arg::Roll(RollArg::ModifierPos(ArgValue::Expr(_))) => {},
+        ref arg => panic!("unexpected {:?}", arg),
+    }
+
+    let (_, result) = conditional_p(CompleteByteSlice(b"$1 + 2 >= 15 ? !say 'hit' : |")).unwrap();
+    match result.condition {
+        Condition::Compare(ref compare) => {
+            assert_eq!(compare.right, ArgValue::Number(15));
+            match compare.left {
+                ArgValue::Expr(_) => {},
+                ref value => panic!("unexpected {:?}", value),
+            }
+        },
+        ref condition => panic!("unexpected {:?}", condition),
+    }
+}
+
+#[test]
+fn it_parses_loops() {
+    let (_, result) = parse_p(CompleteByteSlice(b"#test !for $i in 1..$count { !r 1d20 >> !say \"$i\" } !say 'done'")).unwrap();
+    assert_eq!(result.steps.len(), 2);
+    assert_eq!(result.steps[0].op, MacroOp::Loop);
+    match result.steps[0].args[0] {
+        Arg::Loop(ref each) => {
+            assert_eq!(each.variable, ArgValue::Variable("i".to_string()));
+            assert_eq!(each.over, LoopOver::Range(ArgValue::Number(1), ArgValue::Variable("count".to_string())));
+            assert_eq!(each.steps.len(), 2);
+        },
+        ref arg => panic!("unexpected {:?}", arg),
+    }
+
+    let (_, result) = arguments_loop_p(CompleteByteSlice(b"@player in [@alice, @bob] !r 1d20 + @player.dexterity")).unwrap();
+    match result {
+        Arg::Loop(ref each) => {
+            match each.variable {
+                ArgValue::Token(ref token) => assert_eq!(token.name, "player".to_string()),
+                ref value => panic!("unexpected {:?}", value),
+            }
+            match each.over {
+                LoopOver::Items(ArgValue::Array(ref items)) => assert_eq!(items.len(), 2),
+                ref over => panic!("unexpected {:?}", over),
+            }
+            assert_eq!(each.steps.len(), 1);
+        },
+        ref arg => panic!("unexpected {:?}", arg),
+    }
+
+    let (_, result) = parse_p(CompleteByteSlice(b"#test !each $item in $list !say '$item'")).unwrap();
+    match result.steps[0].args[0] {
+        Arg::Loop(ref each) => assert_eq!(each.over, LoopOver::Items(ArgValue::Variable("list".to_string()))),
+        ref arg => panic!("unexpected {:?}", arg),
+    }
+
+    let error = parse_program(b"#test !for $i in 1..3 { !say 'a'").unwrap_err();
+    assert_eq!(error.message, "unterminated block".to_string());
+}
+
+#[test]
+fn it_parses_blocks_and_else_if() {
+    let (_, result) = conditional_p(CompleteByteSlice(b"$1 >= 15 ? { !r 1d8 >> !say 'Hit' } : !say 'Miss'")).unwrap();
+    assert_eq!(result.success.iter().map(|step| step.op.clone()).collect::<Vec<MacroOp>>(), vec![MacroOp::Roll, MacroOp::Say]);
+    assert_eq!(result.success[0].result, StepResult::Save);
+    assert_eq!(result.else_if, vec![]);
+    assert_eq!(result.failure.len(), 1);
+
+    let (_, result) = conditional_p(CompleteByteSlice(b"$1 == 20 ? !say 'Crit' : $1 >= 15 ? { $a = 1 | $b = 2 } : $1 == 1 ? | : !say 'Miss'")).unwrap();
+    assert_eq!(result.success.len(), 1);
+    assert_eq!(result.else_if.len(), 2);
+    assert_eq!(result.else_if[0].condition, Condition::Compare(Box::new(Comparison {
+        left: ArgValue::VariableReserved(1),
+        comparison: ComparisonArg::GreaterThanOrEqual,
+        right: ArgValue::Number(15),
+    })));
+    assert_eq!(result.else_if[0].success.len(), 2);
+    assert_eq!(result.else_if[1].success, vec![]);
+    assert_eq!(result.failure[0].op, MacroOp::Say);
+
+    // steps after a block belong to the program again
+    let (_, result) = parse_p(CompleteByteSlice(b"#test !r 1d20 >> $1 > 10 ? { !say 'a' !say 'b' } : {} !say 'after'")).unwrap();
+    assert_eq!(result.steps.len(), 3);
+    match result.steps[1].args[0] {
+        Arg::Conditional(ref conditional) => {
+            assert_eq!(conditional.success.len(), 2);
+            assert_eq!(conditional.failure, vec![]);
+        },
+        ref arg => panic!("unexpected {:?}", arg),
+    }
+
+    let error = parse_program(b"#test !r 1d20 >> $1 > 2 ? { !say 'a' : !say 'b'").unwrap_err();
+    assert_eq!(error.message, "unterminated block".to_string());
+    assert_eq!(error.expected, vec!["}".to_string()]);
+    assert_eq!(error.offset, 26);
+}
+
+#[test]
+fn it_parses_conditions() {
+    let compare = |left: ArgValue, comparison: ComparisonArg, right: i32| {
+        Box::new(Condition::Compare(Box::new(Comparison { left, comparison, right: ArgValue::Number(right) })))
+    };
+    let hp = ArgValue::Token(TokenArg {
+        name: "me".to_string(),
+        attribute: Some("hp".to_string()),
+        macro_name: None,
+        span: None,
+    });
+
+    let (_, result) = conditional_p(CompleteByteSlice(b"$1 >= 15 && @me.hp > 0 ? !say 'hit' : |")).unwrap();
+    assert_eq!(result.condition, Condition::And(
+        compare(ArgValue::VariableReserved(1), ComparisonArg::GreaterThanOrEqual, 15),
+        compare(hp.clone(), ComparisonArg::GreaterThan, 0),
+    ));
+
+    // && binds tighter than ||
+    let (_, result) = conditional_p(CompleteByteSlice(b"$1 == 20 || $1 >= 15 && @me.hp > 0 ? !say 'hit' : |")).unwrap();
+    assert_eq!(result.condition, Condition::Or(
+        compare(ArgValue::VariableReserved(1), ComparisonArg::EqualTo, 20),
+        Box::new(Condition::And(
+            compare(ArgValue::VariableReserved(1), ComparisonArg::GreaterThanOrEqual, 15),
+            compare(hp.clone(), ComparisonArg::GreaterThan, 0),
+        )),
+    ));
+
+    // grouping and negation
+    let (_, result) = conditional_p(CompleteByteSlice(b"!($1 == 20 || $1 == 1) && @me.hp > 0 ? !say 'hit' : |")).unwrap();
+    assert_eq!(result.condition, Condition::And(
+        Box::new(Condition::Not(Box::new(Condition::Or(
+            compare(ArgValue::VariableReserved(1), ComparisonArg::EqualTo, 20),
+            compare(ArgValue::VariableReserved(1), ComparisonArg::EqualTo, 1),
+        )))),
+        compare(hp.clone(), ComparisonArg::GreaterThan, 0),
+    ));
+
+    // parentheses around arithmetic still belong to the comparison
+    let (_, result) = conditional_p(CompleteByteSlice(b"($1 + 2) * 3 > 10 ? !say 'hit' : |")).unwrap();
+    match result.condition {
+        Condition::Compare(ref compare) => match compare.left {
+            ArgValue::Expr(_) => assert_eq!(compare.right, ArgValue::Number(10)),
+            ref value => panic!("unexpected {:?}", value),
+        },
+        ref condition => panic!("unexpected {:?}", condition),
+    }
+
+    // text, booleans and not equal
+    let (_, result) = conditional_p(CompleteByteSlice(b"@me.class == \"wizard\" && $flag != true ? !say 'hit' : |")).unwrap();
+    assert_eq!(result.condition, Condition::And(
+        Box::new(Condition::Compare(Box::new(Comparison {
+            left: ArgValue::Token(TokenArg {
+                name: "me".to_string(),
+                attribute: Some("class".to_string()),
+                macro_name: None,
+                span: None,
+            }),
+            comparison: ComparisonArg::EqualTo,
+            right: ArgValue::TextInterpolated(TextInterpolated {
+                parts: vec![ ArgValue::Text("wizard".to_string()) ],
+                span: None,
+            }),
+        }))),
+        Box::new(Condition::Compare(Box::new(Comparison {
+            left: ArgValue::Variable("flag".to_string()),
+            comparison: ComparisonArg::NotEqualTo,
+            right: ArgValue::Boolean(true),
+        }))),
+    ));
+    let (_, result) = conditional_p(CompleteByteSlice(b"'elf' != @me.race ? !say 'hit' : |")).unwrap();
+    match result.condition {
+        Condition::Compare(ref compare) => assert_eq!(compare.left, ArgValue::Text("elf".to_string())),
+        ref condition => panic!("unexpected {:?}", condition),
+    }
+
+    // as a whole step
+    let (_, result) = parse_p(CompleteByteSlice(b"#test !r 1d20 >> !($1 < 10) ? !say 'hit' : |")).unwrap();
+    match result.steps[1].args[0] {
+        Arg::Conditional(Conditional { condition: Condition::Not(_), .. }) => {},
+        ref arg => panic!("unexpected {:?}", arg),
+    }
+}
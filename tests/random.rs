@@ -0,0 +1,29 @@
+extern crate ttml;
+
+use ttml::random::*;
+
+#[test]
+fn it_repeats_numbers_for_the_same_seed() {
+    let mut first = SeededRandom::new(1234);
+    let mut second = SeededRandom::new(1234);
+    for _ in 0..100 {
+        assert_eq!(first.next_u64(), second.next_u64());
+    }
+    assert_eq!(first.seed(), 1234);
+
+    let mut other = SeededRandom::new(4321);
+    let mut first = SeededRandom::new(1234);
+    assert_ne!(first.next_u64(), other.next_u64());
+}
+
+#[test]
+fn it_picks_numbers_below_a_maximum() {
+    let mut random = OsRandom::new();
+    let mut seen = [false; 6];
+    for _ in 0..1000 {
+        let n = random.below(6);
+        assert!(n < 6);
+        seen[n as usize] = true;
+    }
+    assert!(seen.iter().all(|seen| *seen));
+}